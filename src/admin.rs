@@ -0,0 +1,469 @@
+//! Authenticated API for operating the faucet while it's running, served
+//! under `/admin`.
+//!
+//! Requests need an `Authorization: Bearer <token>` header with the token set
+//! in the `[admin]` config. Client certificates (mTLS) aren't checked by the
+//! faucet itself, that's left to a reverse proxy in front of it.
+//!
+//! Requests are handled one at a time, and every one of them, authorized or
+//! not, is appended to the audit log once it's been handled. Authorized
+//! requests are refused if the audit log can't be opened, and get a `500`
+//! response if their entry can't be written.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post},
+    Json, Router,
+};
+use axum_client_ip::ClientIp;
+use bdk_wallet::bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shrex::Hex;
+use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::{error, info};
+
+use crate::{
+    bans::{self, Ban},
+    error::ApiError,
+    ip_bucket::IpBucket,
+    layer::LayerState,
+    pow::{Challenge, ChallengeMode, DifficultyCurve},
+    settings::{LayerConfig, MAX_SATS_PER_CLAIM, SETTINGS},
+    AppState, Chain,
+};
+
+/// Largest request body the admin API accepts
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Held while an admin request is handled and audited, so that audit log
+/// entries are written in the order the requests took effect.
+static AUDITING: Mutex<()> = Mutex::const_new(());
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Bearer token required to use the admin API. The admin API is disabled
+    /// when this isn't set.
+    ///
+    /// Defaults to `None`.
+    pub token: Option<String>,
+
+    /// File every admin request is appended to, one JSON object per line.
+    ///
+    /// Defaults to `faucet-audit.log`.
+    pub audit_log: PathBuf,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            audit_log: PathBuf::from("faucet-audit.log"),
+        }
+    }
+}
+
+impl fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("audit_log", &self.audit_log)
+            .finish()
+    }
+}
+
+/// SHA256 of the admin token. Tokens are compared by their hashes so the
+/// comparison doesn't leak how much of the token a request got right.
+#[derive(Debug, Clone, Copy)]
+struct TokenHash([u8; 32]);
+
+impl TokenHash {
+    fn new(token: &str) -> Self {
+        Self(Sha256::digest(token.as_bytes()).into())
+    }
+}
+
+/// Routes of the admin API, requiring `token`.
+pub fn router(token: &str) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/{chain}/pause", post(pause))
+        .route("/{chain}/resume", post(resume))
+        .route("/{chain}/config", patch(update_config))
+        .route("/queue", get(get_queue).delete(flush_queue))
+        .route("/batch", post(force_batch))
+        .route("/bans", get(get_bans).post(add_ban))
+        .route("/bans/{ip}", delete(remove_ban))
+        .route("/challenges", get(get_challenges))
+        .layer(middleware::from_fn_with_state(
+            TokenHash::new(token),
+            authorize,
+        ))
+}
+
+/// One line of the audit log.
+#[derive(Debug, Serialize)]
+struct AuditEntry {
+    /// Unix time the request was made at
+    time: u64,
+    ip: IpAddr,
+    method: String,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+    status: u16,
+}
+
+/// Checks the request's bearer token and records the request in the audit
+/// log.
+async fn authorize(
+    State(token): State<TokenHash>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = req.into_parts();
+    let mut entry = AuditEntry {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_secs(),
+        ip,
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_owned(),
+        body: None,
+        status: 0,
+    };
+
+    let authorized = parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| TokenHash::new(given).0 == token.0);
+
+    let _auditing = AUDITING.lock().await;
+    let log = match blocking(open_audit_log).await {
+        Ok(log) => Some(log),
+        Err(e) => {
+            error!("failed opening audit log: {e}");
+            None
+        }
+    };
+    let response = if !authorized {
+        (StatusCode::UNAUTHORIZED, "invalid admin token").into_response()
+    } else if log.is_none() {
+        // nothing is done that can't be audited
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed opening audit log",
+        )
+        .into_response()
+    } else {
+        match to_bytes(body, MAX_BODY_LEN).await {
+            Ok(body) => {
+                entry.body = serde_json::from_slice(&body).ok();
+                next.run(Request::from_parts(parts, Body::from(body))).await
+            }
+            Err(_) => (StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response(),
+        }
+    };
+
+    entry.status = response.status().as_u16();
+    info!(
+        "admin request {} {} from {}: {}",
+        entry.method, entry.path, entry.ip, entry.status
+    );
+    let Some(mut log) = log else {
+        return response;
+    };
+    match blocking(move || append_audit_entry(&mut log, &entry)).await {
+        Ok(()) => response,
+        Err(e) => {
+            error!("failed writing to audit log: {e}");
+            if !authorized {
+                return response;
+            }
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "request was handled, but writing the audit log failed",
+            )
+            .into_response()
+        }
+    }
+}
+
+/// Runs blocking file IO on the blocking thread pool.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+fn open_audit_log() -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&SETTINGS.admin.audit_log)
+}
+
+fn append_audit_entry(log: &mut File, entry: &AuditEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    log.write_all(&line)?;
+    log.sync_data()
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayerStatus {
+    paused: bool,
+    #[serde(flatten)]
+    config: LayerConfig,
+}
+
+impl LayerStatus {
+    fn new(layer: &LayerState) -> Self {
+        Self {
+            paused: layer.is_paused(),
+            config: layer.config(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminStatus {
+    l1: LayerStatus,
+    l2: LayerStatus,
+    queue_depth: usize,
+    queue_capacity: usize,
+}
+
+async fn get_status(State(state): State<Arc<AppState>>) -> Json<AdminStatus> {
+    Json(AdminStatus {
+        l1: LayerStatus::new(&state.l1),
+        l2: LayerStatus::new(&state.l2),
+        queue_depth: state.batcher.queue_depth(),
+        queue_capacity: state.batcher.queue_capacity(),
+    })
+}
+
+/// Stops handing out challenges and accepting claims on the chain.
+async fn pause(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<String>,
+) -> Result<Json<LayerStatus>, ApiError> {
+    let layer = state.layer(Chain::try_from(chain.as_str())?);
+    layer.set_paused(true);
+    Ok(Json(LayerStatus::new(layer)))
+}
+
+async fn resume(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<String>,
+) -> Result<Json<LayerStatus>, ApiError> {
+    let layer = state.layer(Chain::try_from(chain.as_str())?);
+    layer.set_paused(false);
+    Ok(Json(LayerStatus::new(layer)))
+}
+
+/// Changes to a chain's claim settings. Fields that aren't set are left as
+/// they are.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerUpdate {
+    amount_per_claim: Option<Amount>,
+    min_difficulty: Option<u8>,
+    max_difficulty: Option<u8>,
+    min_balance: Option<Amount>,
+    difficulty_increase_coeff: Option<f32>,
+    difficulty_curve: Option<DifficultyCurve>,
+}
+
+async fn update_config(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<String>,
+    Json(update): Json<LayerUpdate>,
+) -> Result<Json<LayerStatus>, ApiError> {
+    let layer = state.layer(Chain::try_from(chain.as_str())?);
+    if update
+        .amount_per_claim
+        .is_some_and(|amount| amount > MAX_SATS_PER_CLAIM)
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("amount_per_claim is too high, max is {MAX_SATS_PER_CLAIM}"),
+        ));
+    }
+    layer
+        .update(|config| {
+            if let Some(amount) = update.amount_per_claim {
+                config.amount_per_claim = amount;
+            }
+            if let Some(difficulty) = update.min_difficulty {
+                config.min_difficulty = difficulty;
+            }
+            if let Some(difficulty) = update.max_difficulty {
+                config.max_difficulty = difficulty;
+            }
+            if let Some(balance) = update.min_balance {
+                config.min_balance = balance;
+            }
+            if let Some(coeff) = update.difficulty_increase_coeff {
+                config.difficulty_increase_coeff = coeff;
+            }
+            if let Some(curve) = update.difficulty_curve {
+                config.difficulty_curve = curve;
+            }
+        })
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Json(LayerStatus::new(layer)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueuedPayoutStatus {
+    id: String,
+    address: String,
+    /// In sats
+    amount: u64,
+    /// Times paying it out has failed so far
+    attempts: u32,
+}
+
+async fn get_queue(State(state): State<Arc<AppState>>) -> Json<Vec<QueuedPayoutStatus>> {
+    let queue = state
+        .batcher
+        .queued_payouts()
+        .into_iter()
+        .map(|(req, attempts)| QueuedPayoutStatus {
+            id: Hex(req.id).to_string(),
+            address: req.address.to_string(),
+            amount: req.amount.to_sat(),
+            attempts,
+        })
+        .collect();
+    Json(queue)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Flushed {
+    flushed: usize,
+}
+
+/// Drops every queued L1 payout, failing their claims.
+async fn flush_queue(State(state): State<Arc<AppState>>) -> Result<Json<Flushed>, ApiError> {
+    let flushed = state
+        .batcher
+        .flush_queue("payout was cancelled by the faucet operator")
+        .map_err(|e| {
+            error!("failed flushing payout queue: {e:?}");
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "failed flushing queue")
+        })?;
+    Ok(Json(Flushed { flushed }))
+}
+
+async fn force_batch(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.batcher.force_batch();
+    StatusCode::ACCEPTED
+}
+
+async fn get_bans() -> Result<Json<Vec<Ban>>, ApiError> {
+    bans::list().map(Json).map_err(|e| {
+        error!("failed listing bans: {e:?}");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "failed listing bans")
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BanRequest {
+    /// Banned along with the rest of its [`IpBucket`]
+    ip: IpAddr,
+    /// How long the ban lasts in seconds, forever if not set
+    duration: Option<u64>,
+    reason: Option<String>,
+}
+
+async fn add_ban(Json(req): Json<BanRequest>) -> Result<StatusCode, ApiError> {
+    let ip = IpBucket::new(req.ip, SETTINGS.ipv6_prefix_len);
+    bans::ban(
+        ip,
+        req.duration.map(Duration::from_secs),
+        req.reason.as_deref(),
+    )
+    .map_err(|e| {
+        error!("failed banning {ip}: {e:?}");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "failed banning ip")
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_ban(Path(ip): Path<IpAddr>) -> Result<StatusCode, ApiError> {
+    let ip = IpBucket::new(ip, SETTINGS.ipv6_prefix_len);
+    match bans::unban(ip) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(ApiError::new(StatusCode::NOT_FOUND, "ip isn't banned")),
+        Err(e) => {
+            error!("failed unbanning {ip}: {e:?}");
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed unbanning ip",
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChallengeStatus {
+    ip: String,
+    chain: Chain,
+    difficulty: u8,
+    shares: u8,
+    claimed: bool,
+    expires_in_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutstandingChallenges {
+    /// Challenges are only tracked in `stateful` mode, so the list is always
+    /// empty in `stateless` mode
+    mode: ChallengeMode,
+    challenges: Vec<ChallengeStatus>,
+}
+
+async fn get_challenges() -> Json<OutstandingChallenges> {
+    let challenges = Challenge::outstanding()
+        .into_iter()
+        .map(|(ip, chain, challenge)| ChallengeStatus {
+            ip: ip.to_string(),
+            chain,
+            difficulty: challenge.difficulty(),
+            shares: challenge.shares(),
+            claimed: challenge.is_claimed(),
+            expires_in_secs: challenge.expires_in().as_secs(),
+            address: challenge.address().map(str::to_owned),
+        })
+        .collect();
+    Json(OutstandingChallenges {
+        mode: SETTINGS.challenge_mode,
+        challenges,
+    })
+}
@@ -0,0 +1,145 @@
+//! IP addresses banned from the faucet by an operator.
+//!
+//! Bans are stored in the wallet's sqlite database so that they survive
+//! restarts of the faucet.
+
+use std::time::Duration;
+
+use bdk_wallet::rusqlite::{self, params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::{
+    db::{self, now_secs},
+    ip_bucket::IpBucket,
+};
+
+/// A banned [`IpBucket`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ban {
+    pub ip: String,
+    /// Unix time the ban ends at, `None` if it's permanent
+    pub expires_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+/// Creates the ban table if it doesn't exist yet and clears out expired bans.
+pub fn init() -> rusqlite::Result<()> {
+    db::with(|db| init_in(db))
+}
+
+fn init_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_bans (
+            ip TEXT PRIMARY KEY NOT NULL,
+            expires_at INTEGER,
+            reason TEXT
+        ) WITHOUT ROWID;",
+    )?;
+    db.execute(
+        "DELETE FROM faucet_bans WHERE expires_at <= ?1",
+        [now_secs()],
+    )?;
+    Ok(())
+}
+
+/// Bans `ip` for `duration`, or forever if it's `None`. Replaces any existing
+/// ban of `ip`.
+pub fn ban(ip: IpBucket, duration: Option<Duration>, reason: Option<&str>) -> rusqlite::Result<()> {
+    db::with(|db| ban_in(db, ip, duration, reason))
+}
+
+fn ban_in(
+    db: &Connection,
+    ip: IpBucket,
+    duration: Option<Duration>,
+    reason: Option<&str>,
+) -> rusqlite::Result<()> {
+    let expires_at = duration.map(|d| now_secs().saturating_add(d.as_secs()));
+    db.execute(
+        "INSERT INTO faucet_bans (ip, expires_at, reason) VALUES (?1, ?2, ?3)
+        ON CONFLICT (ip) DO UPDATE SET expires_at = excluded.expires_at, reason = excluded.reason",
+        params![ip.to_string(), expires_at, reason],
+    )?;
+    Ok(())
+}
+
+/// Lifts the ban of `ip`, returning whether it was banned.
+pub fn unban(ip: IpBucket) -> rusqlite::Result<bool> {
+    db::with(|db| unban_in(db, ip))
+}
+
+fn unban_in(db: &Connection, ip: IpBucket) -> rusqlite::Result<bool> {
+    let removed = db.execute("DELETE FROM faucet_bans WHERE ip = ?1", [ip.to_string()])?;
+    Ok(removed > 0)
+}
+
+pub fn is_banned(ip: &IpBucket) -> rusqlite::Result<bool> {
+    db::with(|db| is_banned_in(db, ip))
+}
+
+fn is_banned_in(db: &Connection, ip: &IpBucket) -> rusqlite::Result<bool> {
+    let mut stmt = db.prepare_cached(
+        "SELECT 1 FROM faucet_bans WHERE ip = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+    )?;
+    let banned = stmt
+        .query_row(params![ip.to_string(), now_secs()], |_| Ok(()))
+        .optional()?;
+    Ok(banned.is_some())
+}
+
+/// All bans that haven't expired.
+pub fn list() -> rusqlite::Result<Vec<Ban>> {
+    db::with(|db| list_in(db))
+}
+
+fn list_in(db: &Connection) -> rusqlite::Result<Vec<Ban>> {
+    let mut stmt = db.prepare(
+        "SELECT ip, expires_at, reason FROM faucet_bans
+        WHERE expires_at IS NULL OR expires_at > ?1 ORDER BY ip",
+    )?;
+    let bans = stmt.query_map([now_secs()], |row| {
+        Ok(Ban {
+            ip: row.get(0)?,
+            expires_at: row.get(1)?,
+            reason: row.get(2)?,
+        })
+    })?;
+    bans.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn ipv4(a: u8, b: u8, c: u8, d: u8) -> IpBucket {
+        IpBucket::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), 56)
+    }
+
+    #[test]
+    fn test_bans() {
+        let db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+        let ip = ipv4(1, 2, 3, 4);
+        let other_ip = ipv4(5, 6, 7, 8);
+
+        ban_in(&db, ip, None, Some("spam")).unwrap();
+        ban_in(&db, other_ip, Some(Duration::ZERO), None).unwrap();
+        assert!(is_banned_in(&db, &ip).unwrap());
+        // zero length bans are already over
+        assert!(!is_banned_in(&db, &other_ip).unwrap());
+        assert_eq!(
+            list_in(&db).unwrap(),
+            vec![Ban {
+                ip: "1.2.3.4".to_owned(),
+                expires_at: None,
+                reason: Some("spam".to_owned()),
+            }]
+        );
+
+        assert!(unban_in(&db, ip).unwrap());
+        assert!(!unban_in(&db, ip).unwrap());
+        assert!(!is_banned_in(&db, &ip).unwrap());
+    }
+}
@@ -1,38 +1,152 @@
 use std::{
-    collections::VecDeque,
-    sync::Arc,
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 
-use bdk_wallet::bitcoin::{self, Amount};
+use alloy::primitives::Address as L2Address;
+use bdk_wallet::{
+    bitcoin::{self, Amount, OutPoint, Transaction},
+    rusqlite,
+};
 use kanal::{unbounded_async, AsyncSender, SendError};
-use parking_lot::{RwLock, RwLockWriteGuard};
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
 use terrors::OneOf;
 use tokio::{
     select, spawn,
+    sync::{watch, Notify},
     task::{spawn_blocking, JoinHandle},
-    time::interval,
+    time::{interval, interval_at, sleep, Instant},
 };
-use tracing::{error, info, info_span, Instrument};
+use tracing::{error, info, info_span, warn, Instrument, Span};
 
-use crate::l1::{fee_rate, L1Wallet, Persister, ESPLORA_CLIENT};
+use crate::{
+    chain_source::CHAIN_SOURCE,
+    claims::{ClaimId, ClaimState, ClaimTracker},
+    fee_bump::{bump_stuck_batches, SentBatch, SentBatches},
+    health::{Heartbeat, TaskState},
+    l1::{batch_fee_rate, L1Wallet, Persister},
+    l2::L2Wallet,
+    l2_payout_store,
+    l2_payouts::{self, L2PayoutRequest},
+    metrics, payout_store, Chain,
+};
 
 pub enum PayoutRequest {
-    L1(L1PayoutRequest),
+    /// An L1 payout, along with the place reserved for it in the queue.
+    L1(QueueSlot, L1PayoutRequest),
+    L2(L2PayoutRequest),
 }
 
+#[derive(Debug, Clone)]
 pub struct L1PayoutRequest {
+    pub id: ClaimId,
     pub address: bitcoin::Address,
     pub amount: Amount,
 }
 
+/// An [`L1PayoutRequest`] waiting in the batcher's queue.
+struct QueuedPayout {
+    req: L1PayoutRequest,
+    /// How many times paying this out has failed so far
+    attempts: u32,
+}
+
+type PayoutQueue = Arc<Mutex<VecDeque<QueuedPayout>>>;
+
+/// How often unconfirmed batches are checked for whether they need a fee
+/// bump.
+const FEE_BUMP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a batch is broadcast again while it can't be told whether it
+/// went out.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// State shared by the batcher task and the tasks broadcasting its batches.
+#[derive(Clone)]
+struct BatchContext {
+    l1_wallet: Arc<RwLock<L1Wallet>>,
+    queue: PayoutQueue,
+    /// See [`Batcher::in_flight`]
+    in_flight: Arc<AtomicUsize>,
+    claims: Arc<ClaimTracker>,
+    sent_batches: Arc<SentBatches>,
+    /// Outputs spent by batches that haven't been applied to the wallet yet,
+    /// which new batches mustn't spend
+    reserved: Arc<Mutex<HashSet<OutPoint>>>,
+    /// Number of batches being broadcast that haven't been applied to the
+    /// wallet yet
+    broadcasting: Arc<AtomicUsize>,
+}
+
+/// Counts a batch as being broadcast until dropped.
+struct BroadcastGuard(Arc<AtomicUsize>);
+
+impl BroadcastGuard {
+    fn new(broadcasting: &Arc<AtomicUsize>) -> Self {
+        broadcasting.fetch_add(1, Ordering::AcqRel);
+        Self(broadcasting.clone())
+    }
+}
+
+impl Drop for BroadcastGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 pub struct Batcher {
     task: Option<JoinHandle<()>>,
+    l2_task: Option<JoinHandle<()>>,
+    /// Beats every time the batcher task goes around its loop
+    heartbeat: Arc<Heartbeat>,
     payout_sender: Option<AsyncSender<PayoutRequest>>,
-    cfg: BatcherConfig,
+    l1_payout_queue: PayoutQueue,
+    /// Number of L1 payouts that have reserved a place in the queue and
+    /// haven't been paid out or given up on yet.
+    in_flight: Arc<AtomicUsize>,
+    claims: Arc<ClaimTracker>,
+    sent_batches: Arc<SentBatches>,
+    /// Wakes the batcher task up to send a batch straight away
+    force_batch: Arc<Notify>,
+    /// Current config, which the batcher and L2 payout tasks pick up changes
+    /// to
+    cfg: watch::Sender<BatcherConfig>,
+}
+
+/// A reserved place in the L1 payout queue.
+///
+/// The place is given back when this is dropped, unless it's used up by
+/// [`Batcher::queue_payout_request`].
+#[derive(Debug)]
+pub struct QueueSlot {
+    in_flight: Arc<AtomicUsize>,
+    used: bool,
+}
+
+impl QueueSlot {
+    /// Marks the place as taken by a payout that has been queued.
+    fn consume(mut self) {
+        self.used = true;
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        if !self.used {
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
 }
 
+/// The L1 payout queue has no room for another payout.
+#[derive(Debug)]
+pub struct QueueFull;
+
 #[derive(Debug)]
 pub struct BatcherNotStarted;
 
@@ -40,7 +154,13 @@ pub struct BatcherNotStarted;
 #[allow(dead_code)]
 pub struct BatcherNotAvailable(SendError);
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// The payout couldn't be written to the payout store.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct PayoutNotPersisted(rusqlite::Error);
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct BatcherConfig {
     /// How long the period for transaction batching is.
     ///
@@ -56,6 +176,56 @@ pub struct BatcherConfig {
     ///
     /// Defaults to `2_500`.
     pub max_in_flight: usize,
+
+    /// How many times to try paying out a request before marking it as
+    /// failed.
+    ///
+    /// Defaults to `3`.
+    pub max_attempts: u32,
+
+    /// How long an L2 payout transaction can go without being mined before
+    /// it's re-sent with higher fees.
+    ///
+    /// Defaults to `60` seconds.
+    pub l2_resend_after: Duration,
+
+    /// By how many percent fees are raised when re-sending a stuck L2 payout
+    /// transaction. Values below `10` are treated as `10`, the minimum nodes
+    /// accept for replacements.
+    ///
+    /// Defaults to `20`.
+    pub l2_fee_bump_percent: u64,
+
+    /// Whether to batch L2 payouts through a disperse contract, using the
+    /// same `period` and `max_per_tx` as L1 payouts.
+    ///
+    /// Defaults to `false`.
+    pub l2_batching: bool,
+
+    /// Disperse contract to batch L2 payouts through. If not set, the faucet
+    /// deploys its own.
+    ///
+    /// Defaults to `None`.
+    pub l2_disperse_contract: Option<L2Address>,
+
+    /// How long an L1 batch can go unconfirmed before it's replaced with one
+    /// paying a higher fee.
+    ///
+    /// Defaults to `3_600` seconds.
+    pub rbf_after: Duration,
+
+    /// How many percent above the fee rate an unconfirmed L1 batch pays the
+    /// current fee rate estimate has to be before the batch is replaced.
+    /// Replacements raise the fee rate by at least this much.
+    ///
+    /// Defaults to `50`.
+    pub rbf_fee_rate_gap_percent: u64,
+
+    /// Maximum extra fee to spend on replacing an L1 batch, on top of the fee
+    /// it originally paid.
+    ///
+    /// Defaults to `100_000` sats.
+    pub rbf_max_fee: Amount,
 }
 
 impl Default for BatcherConfig {
@@ -64,6 +234,14 @@ impl Default for BatcherConfig {
             period: Duration::from_secs(180),
             max_per_tx: 250,
             max_in_flight: 2500,
+            max_attempts: 3,
+            l2_resend_after: Duration::from_secs(60),
+            l2_fee_bump_percent: 20,
+            l2_batching: false,
+            l2_disperse_contract: None,
+            rbf_after: Duration::from_secs(60 * 60),
+            rbf_fee_rate_gap_percent: 50,
+            rbf_max_fee: Amount::from_sat(100_000),
         }
     }
 }
@@ -75,47 +253,84 @@ impl Batcher {
     pub fn new(cfg: BatcherConfig) -> Self {
         Self {
             task: None,
+            l2_task: None,
+            heartbeat: Default::default(),
             payout_sender: None,
-            cfg,
+            l1_payout_queue: Default::default(),
+            in_flight: Default::default(),
+            claims: Default::default(),
+            sent_batches: Default::default(),
+            force_batch: Default::default(),
+            cfg: watch::Sender::new(cfg),
         }
     }
 
-    pub fn start(&mut self, l1_wallet: Arc<RwLock<L1Wallet>>) {
+    pub fn start(&mut self, l1_wallet: Arc<RwLock<L1Wallet>>, l2_wallet: Arc<L2Wallet>) {
         let (tx, rx) = unbounded_async();
+        let (l2_tx, l2_rx) = unbounded_async();
+
+        self.l2_task = Some(spawn(
+            l2_payouts::run(l2_wallet, self.claims.clone(), l2_rx, self.cfg.subscribe())
+                .instrument(info_span!("l2 payouts")),
+        ));
 
-        let cfg = self.cfg.clone();
+        let mut cfg_rx = self.cfg.subscribe();
+        let mut cfg = cfg_rx.borrow_and_update().clone();
+        let ctx = BatchContext {
+            l1_wallet,
+            queue: self.l1_payout_queue.clone(),
+            in_flight: self.in_flight.clone(),
+            claims: self.claims.clone(),
+            sent_batches: self.sent_batches.clone(),
+            reserved: Default::default(),
+            broadcasting: Default::default(),
+        };
+        let heartbeat = self.heartbeat.clone();
+        let force_batch = self.force_batch.clone();
 
         let span = info_span!("batcher");
         let batcher_task = spawn(async move {
+            restore_sent_batches(&ctx);
+            restore_payouts(&ctx, cfg.max_attempts);
+
             let mut batch_interval = interval(cfg.period);
-            let mut l1_payout_queue: VecDeque<L1PayoutRequest> = VecDeque::new();
+            let mut fee_bump_interval = interval(FEE_BUMP_INTERVAL);
 
             loop {
+                heartbeat.beat();
                 select! {
                     // biased to ensure that even if we have incoming requests, they don't block
                     // each batch from being built when it's scheduled
                     biased;
                     instant = batch_interval.tick() => {
-                        if l1_payout_queue.is_empty() {
+                        if ctx.queue.lock().is_empty() {
                             continue
                         }
                         let span = info_span!("batch processing", batch = ?instant);
                         let _guard = span.enter();
 
-                        let mut l1w = l1_wallet.write();
+                        let mut l1w = ctx.l1_wallet.write();
 
                         let mut psbt = l1w.build_tx();
-                        psbt.fee_rate(fee_rate());
-                        let num_to_deque = cfg.max_per_tx.min(l1_payout_queue.len());
-                        let mut total_sent = Amount::ZERO;
-                        for req in l1_payout_queue.drain(..num_to_deque) {
+                        // outputs spent by batches that might still go out
+                        psbt.unspendable(ctx.reserved.lock().iter().copied().collect());
+                        let batch = {
+                            let mut queue = ctx.queue.lock();
+                            psbt.fee_rate(batch_fee_rate(is_urgent(queue.len(), cfg.max_per_tx)));
+                            let num_to_deque = cfg.max_per_tx.min(queue.len());
+                            queue.drain(..num_to_deque).collect::<Vec<_>>()
+                        };
+                        let claim_ids = batch.iter().map(|p| p.req.id).collect::<Vec<_>>();
+                        ctx.claims.set_state_many(&claim_ids, ClaimState::InBatch);
+
+                        for QueuedPayout { req, .. } in &batch {
                             psbt.add_recipient(req.address.script_pubkey(), req.amount);
-                            total_sent += req.amount;
                         }
                         let mut psbt = match psbt.finish() {
                             Ok(psbt) => psbt,
                             Err(e) => {
                                 error!("failed finalizing tx: {e:?}");
+                                requeue_failed(&ctx, batch, cfg.max_attempts, "failed to build payout transaction");
                                 continue;
                             }
                         };
@@ -125,29 +340,46 @@ impl Batcher {
                         l1w.sign(&mut psbt, Default::default())
                             .expect("signing should not fail");
                         let tx = psbt.extract_tx().expect("fully signed psbt");
+                        let txid = tx.compute_txid();
+                        drop(l1w);
+
+                        if let Err(e) = payout_store::mark_broadcasting(&claim_ids, &tx) {
+                            error!("failed recording batch {txid}: {e:?}");
+                            requeue_failed(&ctx, batch, cfg.max_attempts, "failed to store payout transaction");
+                            continue;
+                        }
 
-                        let l1_wallet = l1_wallet.clone();
                         let span = info_span!("broadcast l1 tx", batch = ?instant);
-                        spawn(async move {
-                            if let Err(e) = ESPLORA_CLIENT.broadcast(&tx).await {
-                                error!("error broadcasting tx: {e:?}");
-                            }
-                            info!("sent {total_sent} to {num_to_deque} requestors");
-                            // triple nested spawn!
-                            spawn_blocking(move || {
-                                let mut l1w = l1_wallet.write();
-                                l1w.apply_unconfirmed_txs([(tx, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64)]);
-                                l1w.persist(&mut Persister).expect("persist should work");
-                            })
-                            .await
-                            .expect("successful blocking update");
-                        }.instrument(span));
+                        spawn_settle_batch(&ctx, tx, batch, cfg.max_attempts, span);
+                    }
+                    _ = force_batch.notified() => batch_interval.reset_immediately(),
+                    Ok(()) = cfg_rx.changed() => {
+                        let new_cfg = cfg_rx.borrow_and_update().clone();
+                        if new_cfg.period != cfg.period {
+                            batch_interval = interval_at(Instant::now() + new_cfg.period, new_cfg.period);
+                        }
+                        cfg = new_cfg;
+                    }
+                    _ = fee_bump_interval.tick() => {
+                        // replacing a batch while another one that may spend
+                        // its change is being broadcast would invalidate the
+                        // other one
+                        if ctx.broadcasting.load(Ordering::Acquire) == 0 {
+                            bump_stuck_batches(&ctx.l1_wallet, &ctx.claims, &ctx.sent_batches, &cfg)
+                                .instrument(info_span!("fee bumping"))
+                                .await;
+                        }
                     }
                     req = rx.recv() => match req {
                         Ok(req) => match req {
-                            PayoutRequest::L1(req) => if l1_payout_queue.len() < cfg.max_in_flight {
-                                l1_payout_queue.push_back(req)
+                            // requests hold a reserved QueueSlot, so there's always room
+                            PayoutRequest::L1(slot, req) => {
+                                ctx.queue.lock().push_back(QueuedPayout { req, attempts: 0 });
+                                slot.consume();
                             }
+                            PayoutRequest::L2(req) => if let Err(e) = l2_tx.send(req).await {
+                                error!("error forwarding L2 payout request: {e:?}");
+                            },
                         },
                         Err(e) => error!("error receiving PayoutRequest: {e:?}")
                     }
@@ -159,20 +391,476 @@ impl Batcher {
         self.payout_sender = Some(tx);
     }
 
+    /// Returns the 0-indexed position of the L1 claim in the payout queue, if
+    /// it's still queued.
+    pub fn queue_position(&self, id: &ClaimId) -> Option<usize> {
+        self.l1_payout_queue
+            .lock()
+            .iter()
+            .position(|p| &p.req.id == id)
+    }
+
+    /// The L1 payouts waiting in the queue, along with how many times paying
+    /// each of them out has failed so far.
+    pub fn queued_payouts(&self) -> Vec<(L1PayoutRequest, u32)> {
+        self.l1_payout_queue
+            .lock()
+            .iter()
+            .map(|p| (p.req.clone(), p.attempts))
+            .collect()
+    }
+
+    /// Drops all the L1 payouts waiting in the queue, marking their claims as
+    /// failed. Returns how many were dropped.
+    pub fn flush_queue(&self, reason: &str) -> rusqlite::Result<usize> {
+        let mut queue = self.l1_payout_queue.lock();
+        let ids = queue.iter().map(|p| p.req.id).collect::<Vec<_>>();
+        payout_store::remove(&ids)?;
+        queue.clear();
+        drop(queue);
+        self.in_flight.fetch_sub(ids.len(), Ordering::AcqRel);
+        self.claims.set_state_many(
+            &ids,
+            ClaimState::Failed {
+                reason: reason.to_owned(),
+            },
+        );
+        Ok(ids.len())
+    }
+
+    /// Sends a batch with whatever is in the L1 queue straight away, instead
+    /// of waiting for the end of the period.
+    pub fn force_batch(&self) {
+        self.force_batch.notify_one();
+    }
+
+    pub fn claims(&self) -> &ClaimTracker {
+        &self.claims
+    }
+
+    /// L1 batches that went out and haven't confirmed yet.
+    pub fn sent_batches(&self) -> &SentBatches {
+        &self.sent_batches
+    }
+
+    /// Number of L1 payouts that are queued or being paid out, including
+    /// reserved places.
+    pub fn queue_depth(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Whether the next batch is urgent, in which case it's built with the
+    /// regular fee rate even in economy mode.
+    pub fn is_urgent(&self) -> bool {
+        let queued = self.l1_payout_queue.lock().len();
+        is_urgent(queued, self.cfg.borrow().max_per_tx)
+    }
+
+    /// Maximum number of L1 payouts that can be queued or being paid out at
+    /// once.
+    pub fn queue_capacity(&self) -> usize {
+        self.cfg.borrow().max_in_flight
+    }
+
+    /// How often batches are sent out.
+    pub fn period(&self) -> Duration {
+        self.cfg.borrow().period
+    }
+
+    /// Changes the config of the running batcher. A new `period` starts
+    /// counting from now.
+    pub fn set_config(&self, cfg: BatcherConfig) {
+        self.cfg.send_replace(cfg);
+    }
+
+    pub fn task_state(&self) -> TaskState {
+        TaskState::of(self.task.as_ref())
+    }
+
+    pub fn l2_task_state(&self) -> TaskState {
+        TaskState::of(self.l2_task.as_ref())
+    }
+
+    /// Time since the batcher task last went around its loop.
+    pub fn heartbeat_age(&self) -> Option<Duration> {
+        self.heartbeat.age()
+    }
+
+    /// How long the batcher task can go without going around its loop before
+    /// it's considered stuck. It wakes up at least every
+    /// [`FEE_BUMP_INTERVAL`], but bumping fees can take a while.
+    pub fn max_heartbeat_age(&self) -> Duration {
+        self.cfg.borrow().period.min(FEE_BUMP_INTERVAL) * 3
+    }
+
+    /// Reserves a place in the L1 payout queue, failing if it's full. This
+    /// should be done before accepting a claim so that accepted claims are
+    /// never dropped.
+    pub fn reserve_slot(&self) -> Result<QueueSlot, QueueFull> {
+        let max_in_flight = self.cfg.borrow().max_in_flight;
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max_in_flight).then_some(n + 1)
+            })
+            .map_err(|_| QueueFull)?;
+        Ok(QueueSlot {
+            in_flight: self.in_flight.clone(),
+            used: false,
+        })
+    }
+
+    /// Queues a payout. Payouts are written to the payout store of their
+    /// chain before this returns, so they survive restarts.
     pub async fn queue_payout_request(
         &self,
         req: PayoutRequest,
-    ) -> Result<(), OneOf<(BatcherNotStarted, BatcherNotAvailable)>> {
+    ) -> Result<(), OneOf<(BatcherNotStarted, BatcherNotAvailable, PayoutNotPersisted)>> {
         let tx = self
             .payout_sender
             .as_ref()
             .ok_or(OneOf::new(BatcherNotStarted))?
             .clone();
 
-        tx.send(req)
-            .await
-            .map_err(|e| OneOf::new(BatcherNotAvailable(e)))?;
+        let (chain, id) = match &req {
+            PayoutRequest::L1(_, req) => {
+                payout_store::insert(req).map_err(|e| OneOf::new(PayoutNotPersisted(e)))?;
+                (Chain::L1, req.id)
+            }
+            PayoutRequest::L2(req) => {
+                l2_payout_store::insert(req).map_err(|e| OneOf::new(PayoutNotPersisted(e)))?;
+                (Chain::L2, req.id)
+            }
+        };
+
+        if let Err(e) = tx.send(req).await {
+            // the claim is rejected, so it mustn't be paid out after a restart
+            let removed = match chain {
+                Chain::L1 => payout_store::remove(&[id]),
+                Chain::L2 => l2_payout_store::remove(&[id]),
+            };
+            if let Err(e) = removed {
+                error!("failed removing rejected payout from store: {e:?}");
+            }
+            return Err(OneOf::new(BatcherNotAvailable(e)));
+        }
 
         Ok(())
     }
 }
+
+/// Batches are urgent when more payouts are waiting in the queue than fit in
+/// one. Payouts that are already in a batch being broadcast don't count.
+fn is_urgent(queued: usize, max_per_tx: usize) -> bool {
+    queued > max_per_tx
+}
+
+/// What became of a transaction that was broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BroadcastStatus {
+    /// The node knows about the transaction.
+    Sent,
+    /// The transaction didn't go out. For L1 transactions, the chain source
+    /// doesn't know about it and none of its inputs are spent. For L2 ones,
+    /// the node doesn't know about it, so its nonce is free to be reused.
+    Dropped,
+    /// Whether the transaction went out can't be told yet.
+    Unknown,
+}
+
+/// Broadcasts the transaction. If broadcasting errors, double checks whether
+/// the transaction made it out anyway, since retrying a payout that actually
+/// went out would pay it out twice.
+pub(crate) async fn broadcast(tx: &Transaction) -> BroadcastStatus {
+    let Err(e) = CHAIN_SOURCE.broadcast(tx).await else {
+        return BroadcastStatus::Sent;
+    };
+    let txid = tx.compute_txid();
+    match CHAIN_SOURCE.get_tx(&txid).await {
+        Ok(Some(_)) => {
+            warn!("error broadcasting {txid}, but the chain source knows about it: {e}");
+            return BroadcastStatus::Sent;
+        }
+        Ok(None) => {}
+        Err(check_err) => {
+            warn!("error broadcasting {txid}: {e}, and failed looking it up: {check_err}");
+            return BroadcastStatus::Unknown;
+        }
+    }
+    for input in &tx.input {
+        match CHAIN_SOURCE.is_unspent(&input.previous_output).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("error broadcasting {txid}: {e}, but its inputs are spent");
+                return BroadcastStatus::Unknown;
+            }
+            Err(check_err) => {
+                warn!(
+                    "error broadcasting {txid}: {e}, and failed checking its inputs: {check_err}"
+                );
+                return BroadcastStatus::Unknown;
+            }
+        }
+    }
+    error!("error broadcasting {txid}: {e}");
+    BroadcastStatus::Dropped
+}
+
+pub(crate) fn apply_unconfirmed_tx(l1_wallet: &RwLock<L1Wallet>, tx: Transaction) {
+    let mut l1w = l1_wallet.write();
+    l1w.apply_unconfirmed_txs([(
+        tx,
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+    )]);
+    l1w.persist(&mut Persister).expect("persist should work");
+}
+
+/// Broadcasts a signed batch in the background, then records it as sent, or
+/// puts its payouts back in the queue if it didn't go out.
+///
+/// As long as it can't be told whether the batch went out, its transaction
+/// is broadcast again every [`RECHECK_INTERVAL`], since paying its payouts
+/// with a new transaction could pay them out twice. Meanwhile, its inputs
+/// stay reserved and fee bumping is held off.
+fn spawn_settle_batch(
+    ctx: &BatchContext,
+    tx: Transaction,
+    batch: Vec<QueuedPayout>,
+    max_attempts: u32,
+    span: Span,
+) {
+    let broadcasting = BroadcastGuard::new(&ctx.broadcasting);
+    let inputs = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect::<Vec<_>>();
+    ctx.reserved.lock().extend(&inputs);
+
+    let ctx = ctx.clone();
+    spawn(
+        async move {
+            let _broadcasting = broadcasting;
+            settle_batch(&ctx, tx, batch, max_attempts).await;
+            let mut reserved = ctx.reserved.lock();
+            for input in &inputs {
+                reserved.remove(input);
+            }
+        }
+        .instrument(span),
+    );
+}
+
+/// Broadcasts the batch until it's known whether it went out, and records
+/// the outcome.
+async fn settle_batch(
+    ctx: &BatchContext,
+    tx: Transaction,
+    batch: Vec<QueuedPayout>,
+    max_attempts: u32,
+) {
+    let txid = tx.compute_txid();
+    let mut status = broadcast(&tx).await;
+    while status == BroadcastStatus::Unknown {
+        // the wallet picks up batches that went out when it syncs, even if
+        // the chain source can't find them, e.g. bitcoind without txindex
+        if ctx.l1_wallet.read().get_tx(txid).is_some() {
+            status = BroadcastStatus::Sent;
+            break;
+        }
+        warn!(
+            "can't tell whether {txid} went out, broadcasting it again in {}s",
+            RECHECK_INTERVAL.as_secs()
+        );
+        sleep(RECHECK_INTERVAL).await;
+        status = broadcast(&tx).await;
+    }
+
+    if status == BroadcastStatus::Sent {
+        record_sent(ctx, tx, &batch).await;
+    } else {
+        requeue_failed(
+            ctx,
+            batch,
+            max_attempts,
+            "failed to broadcast payout transaction",
+        );
+    }
+}
+
+/// Marks the payouts of a batch that went out as broadcast and applies the
+/// batch to the wallet.
+async fn record_sent(ctx: &BatchContext, tx: Transaction, batch: &[QueuedPayout]) {
+    let txid = tx.compute_txid();
+    let claim_ids = batch.iter().map(|p| p.req.id).collect::<Vec<_>>();
+    let total_sent = batch.iter().map(|p| p.req.amount).sum::<Amount>();
+
+    // the batch's inputs are still the wallet's own until it's applied
+    let original_fee = ctx.l1_wallet.read().calculate_fee(&tx).unwrap_or_else(|e| {
+        warn!("failed calculating the fee of {txid}: {e:?}");
+        Amount::ZERO
+    });
+
+    ctx.in_flight.fetch_sub(batch.len(), Ordering::AcqRel);
+    ctx.claims
+        .set_state_many(&claim_ids, ClaimState::Broadcast { txid: txid.into() });
+    if let Err(e) = payout_store::mark_sent(&claim_ids, txid, original_fee) {
+        error!("failed recording sent batch {txid} in store: {e:?}");
+    }
+    info!("sent {total_sent} to {} requestors in {txid}", batch.len());
+    metrics::BATCH_SIZE.observe(batch.len() as f64);
+    metrics::SATS_DISPENSED
+        .with_label_values(&[Chain::L1.as_str()])
+        .inc_by(total_sent.to_sat());
+    ctx.sent_batches.insert(SentBatch {
+        claims: claim_ids,
+        original_fee,
+        txids: vec![txid],
+    });
+
+    let l1_wallet = ctx.l1_wallet.clone();
+    spawn_blocking(move || apply_unconfirmed_tx(&l1_wallet, tx))
+        .await
+        .expect("successful blocking update");
+}
+
+/// Puts payouts from a failed batch back at the front of the queue, in their
+/// original order, and marks the ones that ran out of attempts as failed.
+fn requeue_failed(ctx: &BatchContext, batch: Vec<QueuedPayout>, max_attempts: u32, reason: &str) {
+    let ids = batch.iter().map(|p| p.req.id).collect::<Vec<_>>();
+    if let Err(e) = payout_store::record_failed_attempt(&ids) {
+        error!("failed recording failed payout attempt: {e:?}");
+    }
+
+    let (retry, give_up): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|p| QueuedPayout {
+            attempts: p.attempts + 1,
+            ..p
+        })
+        .partition(|p| p.attempts < max_attempts);
+
+    let give_up = give_up.iter().map(|p| p.req.id).collect::<Vec<_>>();
+    if !give_up.is_empty() {
+        warn!(
+            "giving up on {} payouts after {max_attempts} attempts: {reason}",
+            give_up.len()
+        );
+        ctx.claims.set_state_many(
+            &give_up,
+            ClaimState::Failed {
+                reason: reason.to_owned(),
+            },
+        );
+        if let Err(e) = payout_store::remove(&give_up) {
+            error!("failed removing failed payouts from store: {e:?}");
+        }
+        ctx.in_flight.fetch_sub(give_up.len(), Ordering::AcqRel);
+    }
+
+    let retry_ids = retry.iter().map(|p| p.req.id).collect::<Vec<_>>();
+    ctx.claims.set_state_many(&retry_ids, ClaimState::Queued);
+    let mut queue = ctx.queue.lock();
+    for payout in retry.into_iter().rev() {
+        queue.push_front(payout);
+    }
+}
+
+/// Loads the batches that went out but hadn't confirmed when the faucet last
+/// stopped, so they keep being bumped and their claims settled.
+fn restore_sent_batches(ctx: &BatchContext) {
+    let batches = match payout_store::load_sent() {
+        Ok(batches) => batches,
+        Err(e) => {
+            error!("failed loading stored sent batches: {e:?}");
+            return;
+        }
+    };
+    if batches.is_empty() {
+        return;
+    }
+    info!("restoring {} unconfirmed batches", batches.len());
+
+    for batch in batches {
+        let txid = *batch.txids.last().expect("batches have a txid");
+        for id in &batch.claims {
+            ctx.claims
+                .restore(*id, Chain::L1, ClaimState::Broadcast { txid: txid.into() });
+        }
+        ctx.sent_batches.insert(batch);
+    }
+}
+
+/// Loads the payouts that were outstanding when the faucet last stopped back
+/// into the queue.
+///
+/// Batches that were about to be broadcast are settled in the background
+/// like freshly built ones, so their payouts are only queued again once it's
+/// known their transaction never made it out.
+fn restore_payouts(ctx: &BatchContext, max_attempts: u32) {
+    let payouts = match payout_store::load() {
+        Ok(payouts) => payouts,
+        Err(e) => {
+            error!("failed loading stored payouts: {e:?}");
+            return;
+        }
+    };
+    if payouts.is_empty() {
+        return;
+    }
+    info!("restoring {} stored payouts", payouts.len());
+
+    // restored payouts were accepted before the restart, so they're queued
+    // even if that goes over capacity
+    ctx.in_flight.fetch_add(payouts.len(), Ordering::AcqRel);
+
+    let mut batches = Vec::<(Transaction, Vec<QueuedPayout>)>::new();
+    let mut queue = ctx.queue.lock();
+    for payout in payouts {
+        let id = payout.request.id;
+        let queued = QueuedPayout {
+            req: payout.request,
+            attempts: payout.attempts,
+        };
+        let Some(tx) = payout.tx else {
+            ctx.claims.restore(id, Chain::L1, ClaimState::Queued);
+            queue.push_back(queued);
+            continue;
+        };
+        ctx.claims.restore(id, Chain::L1, ClaimState::InBatch);
+        match batches.iter_mut().find(|(batch_tx, _)| *batch_tx == tx) {
+            Some((_, batch)) => batch.push(queued),
+            None => batches.push((tx, vec![queued])),
+        }
+    }
+    drop(queue);
+
+    for (tx, batch) in batches {
+        let span = info_span!("restore l1 tx", txid = %tx.compute_txid());
+        spawn_settle_batch(ctx, tx, batch, max_attempts, span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_slots() {
+        let batcher = Batcher::new(BatcherConfig {
+            max_in_flight: 2,
+            ..Default::default()
+        });
+
+        let first = batcher.reserve_slot().unwrap();
+        let _second = batcher.reserve_slot().unwrap();
+        assert_eq!(batcher.queue_depth(), 2);
+        assert!(batcher.reserve_slot().is_err());
+
+        drop(first);
+        assert_eq!(batcher.queue_depth(), 1);
+        batcher.reserve_slot().unwrap();
+    }
+}
@@ -0,0 +1,507 @@
+//! Where the L1 wallet gets its view of the chain from.
+//!
+//! A [`ChainSource`] syncs the wallet, estimates fees and broadcasts
+//! transactions. It's either a list of esplora APIs to fail over between, or a
+//! bitcoind node's JSON-RPC interface, picked by `chain_source` in the config.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+};
+
+use bdk_bitcoind_rpc::{
+    bitcoincore_rpc::{self, jsonrpc, Auth, Client, RpcApi},
+    Emitter,
+};
+use bdk_esplora::{
+    esplora_client::{self, AsyncClient},
+    EsploraAsyncExt,
+};
+use bdk_wallet::bitcoin::{OutPoint, Transaction, Txid};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    endpoints::{EndpointError, EndpointStatus, Endpoints},
+    l1::{L1Wallet, Persister},
+    metrics,
+    settings::{ChainSourceConfig, SETTINGS},
+};
+
+/// Shared chain source for the L1 wallet
+pub static CHAIN_SOURCE: LazyLock<ChainSource> =
+    LazyLock::new(|| ChainSource::new(&SETTINGS.chain_source));
+
+/// Which kind of [`ChainSource`] to use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainSourceKind {
+    /// esplora APIs, configured with `esplora`.
+    #[default]
+    Esplora,
+    /// A bitcoind node's JSON-RPC interface, configured with `[bitcoind]`.
+    Bitcoind,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct BitcoindConfig {
+    /// URL of the node's JSON-RPC interface.
+    pub url: String,
+
+    /// Cookie file to authenticate with. Takes precedence over `user` and
+    /// `password`.
+    pub cookie_file: Option<PathBuf>,
+
+    /// RPC user to authenticate with.
+    pub user: Option<String>,
+
+    /// RPC password to authenticate with.
+    pub password: Option<String>,
+
+    /// Height to start scanning blocks from the first time the wallet syncs.
+    ///
+    /// Defaults to `0`.
+    #[serde(default)]
+    pub start_height: u32,
+}
+
+impl fmt::Debug for BitcoindConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BitcoindConfig")
+            .field("url", &self.url)
+            .field("cookie_file", &self.cookie_file)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("start_height", &self.start_height)
+            .finish()
+    }
+}
+
+impl BitcoindConfig {
+    fn auth(&self) -> Auth {
+        match (&self.cookie_file, &self.user, &self.password) {
+            (Some(cookie_file), _, _) => Auth::CookieFile(cookie_file.clone()),
+            (None, Some(user), Some(password)) => Auth::UserPass(user.clone(), password.clone()),
+            _ => Auth::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ChainSourceError {
+    Esplora(Box<esplora_client::Error>),
+    Bitcoind(bitcoincore_rpc::Error),
+    /// The wallet couldn't apply a block from bitcoind, because the chain
+    /// changed under it. The next sync starts over from the wallet's tip.
+    CannotConnect(String),
+}
+
+impl fmt::Display for ChainSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Esplora(e) => write!(f, "esplora error: {e}"),
+            Self::Bitcoind(e) => write!(f, "bitcoind error: {e}"),
+            Self::CannotConnect(e) => write!(f, "cannot connect block to wallet: {e}"),
+        }
+    }
+}
+
+impl From<esplora_client::Error> for ChainSourceError {
+    fn from(e: esplora_client::Error) -> Self {
+        Self::Esplora(Box::new(e))
+    }
+}
+
+impl From<Box<esplora_client::Error>> for ChainSourceError {
+    fn from(e: Box<esplora_client::Error>) -> Self {
+        Self::Esplora(e)
+    }
+}
+
+impl EndpointError for esplora_client::Error {
+    fn is_endpoint_failure(&self) -> bool {
+        match self {
+            // the request was rejected, e.g. broadcasting an invalid
+            // transaction
+            esplora_client::Error::HttpResponse { status, .. } => *status >= 500 || *status == 429,
+            esplora_client::Error::TransactionNotFound(_)
+            | esplora_client::Error::HeaderHeightNotFound(_)
+            | esplora_client::Error::HeaderHashNotFound(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for ChainSourceError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        Self::Bitcoind(e)
+    }
+}
+
+pub enum ChainSource {
+    Esplora(Arc<Endpoints<AsyncClient>>),
+    Bitcoind(Bitcoind),
+}
+
+impl ChainSource {
+    pub fn new(cfg: &ChainSourceConfig) -> Self {
+        match cfg {
+            ChainSourceConfig::Esplora(urls) => {
+                let clients = urls.iter().map(|url| {
+                    let client = esplora_client::Builder::new(url)
+                        .build_async()
+                        .expect("valid esplora config");
+                    (url.clone(), client)
+                });
+                Self::Esplora(Arc::new(Endpoints::new(
+                    "esplora",
+                    clients,
+                    SETTINGS.endpoints.clone(),
+                )))
+            }
+            ChainSourceConfig::Bitcoind(cfg) => {
+                Self::Bitcoind(Bitcoind::new(cfg).expect("valid bitcoind config"))
+            }
+        }
+    }
+
+    /// Spawns a tokio task that health checks the esplora endpoints.
+    pub fn spawn_health_checks(&self) {
+        if let Self::Esplora(endpoints) = self {
+            endpoints.spawn_health_checks(|client| async move {
+                client
+                    .get_height()
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            });
+        }
+    }
+
+    /// Name of the backend, used in metrics.
+    fn backend(&self) -> &'static str {
+        match self {
+            Self::Esplora(_) => "esplora",
+            Self::Bitcoind(_) => "bitcoind",
+        }
+    }
+
+    /// Health of the esplora endpoints. Empty when using bitcoind.
+    pub fn status(&self) -> Vec<EndpointStatus> {
+        match self {
+            Self::Esplora(endpoints) => endpoints.status(),
+            Self::Bitcoind(_) => Vec::new(),
+        }
+    }
+
+    /// Scans the chain for the wallet's transactions and persists them.
+    pub async fn sync(&self, l1_wallet: &Arc<RwLock<L1Wallet>>) -> Result<(), ChainSourceError> {
+        metrics::observe_rpc(self.backend(), "sync", async {
+            match self {
+                Self::Esplora(endpoints) => {
+                    let update = endpoints
+                        .call(|client| {
+                            let req = l1_wallet.read().start_sync_with_revealed_spks().build();
+                            // sync is ok because we only receive on our single
+                            // address logged on startup
+                            async move { client.sync(req, 10).await }
+                        })
+                        .await?;
+                    let mut l1w = l1_wallet.write();
+                    l1w.apply_update(update)
+                        .expect("should be able to connect to db");
+                    l1w.persist(&mut Persister).expect("persist should work");
+                    Ok(())
+                }
+                Self::Bitcoind(bitcoind) => {
+                    let emitter = bitcoind.emitter.clone();
+                    let client = bitcoind.client.clone();
+                    let start_height = bitcoind.start_height;
+                    let l1_wallet = l1_wallet.clone();
+                    spawn_blocking(move || {
+                        let mut emitter = emitter.lock();
+                        let res = sync_bitcoind(&mut emitter, client, start_height, &l1_wallet);
+                        if matches!(res, Err(ChainSourceError::CannotConnect(_))) {
+                            *emitter = None;
+                        }
+                        res
+                    })
+                    .await
+                    .expect("sync not to panic")
+                }
+            }
+        })
+        .await
+    }
+
+    /// Fee rate estimates in sat/vB, keyed by confirmation target in blocks.
+    ///
+    /// esplora gives estimates for a fixed set of targets, bitcoind is asked
+    /// for the given `targets`.
+    pub async fn fee_estimates(
+        &self,
+        targets: &[u16],
+    ) -> Result<HashMap<u16, f64>, ChainSourceError> {
+        metrics::observe_rpc(self.backend(), "fee_estimates", async {
+            match self {
+                Self::Esplora(endpoints) => Ok(endpoints
+                    .call(|client| async move { client.get_fee_estimates().await })
+                    .await?),
+                Self::Bitcoind(bitcoind) => {
+                    let targets = targets.to_vec();
+                    bitcoind
+                        .call(move |client| {
+                            let mut estimates = HashMap::new();
+                            for target in targets {
+                                // in BTC/kvB
+                                let estimate = client.estimate_smart_fee(target, None)?;
+                                if let Some(fee_rate) = estimate.fee_rate {
+                                    estimates.insert(target, fee_rate.to_sat() as f64 / 1000.0);
+                                }
+                            }
+                            Ok(estimates)
+                        })
+                        .await
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn broadcast(&self, tx: &Transaction) -> Result<(), ChainSourceError> {
+        metrics::observe_rpc(self.backend(), "broadcast", async {
+            match self {
+                Self::Esplora(endpoints) => Ok(endpoints
+                    .call(|client| async move { client.broadcast(tx).await })
+                    .await?),
+                Self::Bitcoind(bitcoind) => {
+                    let tx = tx.clone();
+                    bitcoind
+                        .call(move |client| client.send_raw_transaction(&tx).map(|_| ()))
+                        .await
+                }
+            }
+        })
+        .await
+    }
+
+    /// Looks up a transaction in the mempool or the chain. bitcoind can only
+    /// find confirmed transactions when it's running with `txindex=1`.
+    pub async fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, ChainSourceError> {
+        metrics::observe_rpc(self.backend(), "get_tx", async {
+            match self {
+                Self::Esplora(endpoints) => Ok(endpoints
+                    .call(|client| async move { client.get_tx(txid).await })
+                    .await?),
+                Self::Bitcoind(bitcoind) => {
+                    let txid = *txid;
+                    bitcoind
+                        .call(
+                            move |client| match client.get_raw_transaction(&txid, None) {
+                                Ok(tx) => Ok(Some(tx)),
+                                Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))
+                                    if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+                                {
+                                    Ok(None)
+                                }
+                                Err(e) => Err(e),
+                            },
+                        )
+                        .await
+                }
+            }
+        })
+        .await
+    }
+
+    /// Whether the output exists and isn't spent by a transaction in the
+    /// mempool or the chain.
+    pub async fn is_unspent(&self, outpoint: &OutPoint) -> Result<bool, ChainSourceError> {
+        metrics::observe_rpc(self.backend(), "is_unspent", async {
+            match self {
+                Self::Esplora(endpoints) => {
+                    let status = endpoints
+                        .call(|client| async move {
+                            client
+                                .get_output_status(&outpoint.txid, outpoint.vout.into())
+                                .await
+                        })
+                        .await?;
+                    Ok(status.is_some_and(|status| !status.spent))
+                }
+                Self::Bitcoind(bitcoind) => {
+                    let outpoint = *outpoint;
+                    bitcoind
+                        .call(move |client| {
+                            client
+                                .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))
+                                .map(|out| out.is_some())
+                        })
+                        .await
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// Error code bitcoind returns for unknown transactions
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+pub struct Bitcoind {
+    client: Arc<Client>,
+    start_height: u32,
+    /// Emits the blocks and mempool transactions the wallet hasn't seen yet.
+    /// Created on the first sync, so it starts from the wallet's tip.
+    emitter: Arc<Mutex<Option<Emitter<Arc<Client>>>>>,
+}
+
+impl Bitcoind {
+    pub fn new(cfg: &BitcoindConfig) -> Result<Self, bitcoincore_rpc::Error> {
+        Ok(Self {
+            client: Arc::new(Client::new(&cfg.url, cfg.auth())?),
+            start_height: cfg.start_height,
+            emitter: Default::default(),
+        })
+    }
+
+    /// Runs a blocking RPC call on a blocking thread.
+    async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Client) -> Result<T, bitcoincore_rpc::Error> + Send + 'static,
+    ) -> Result<T, ChainSourceError> {
+        let client = self.client.clone();
+        Ok(spawn_blocking(move || f(&client))
+            .await
+            .expect("rpc call not to panic")?)
+    }
+}
+
+fn sync_bitcoind(
+    emitter: &mut Option<Emitter<Arc<Client>>>,
+    client: Arc<Client>,
+    start_height: u32,
+    l1_wallet: &RwLock<L1Wallet>,
+) -> Result<(), ChainSourceError> {
+    let emitter = emitter.get_or_insert_with(|| {
+        let l1w = l1_wallet.read();
+        let unconfirmed = l1w
+            .transactions()
+            .filter(|tx| !tx.chain_position.is_confirmed())
+            .map(|tx| tx.tx_node.tx.clone())
+            .collect::<Vec<_>>();
+        Emitter::new(client, l1w.latest_checkpoint(), start_height, unconfirmed)
+    });
+
+    while let Some(event) = emitter.next_block()? {
+        let mut l1w = l1_wallet.write();
+        l1w.apply_block_connected_to(&event.block, event.block_height(), event.connected_to())
+            .map_err(|e| ChainSourceError::CannotConnect(e.to_string()))?;
+        // persist every so often so a long initial scan isn't lost on restart
+        if event.block_height() % 1000 == 0 {
+            l1w.persist(&mut Persister).expect("persist should work");
+        }
+    }
+
+    let mempool = emitter.mempool()?;
+    let mut l1w = l1_wallet.write();
+    l1w.apply_unconfirmed_txs(mempool.update);
+    l1w.apply_evicted_txs(mempool.evicted);
+    l1w.persist(&mut Persister).expect("persist should work");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        process::{Child, Command},
+        time::Duration,
+    };
+
+    use bdk_wallet::bitcoin::Amount;
+
+    use super::*;
+
+    /// A regtest bitcoind, killed when dropped.
+    struct Node(Child);
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+        }
+    }
+
+    /// Starts a regtest bitcoind from `$BITCOIND_EXE`, or `bitcoind` on the
+    /// path, in a fresh data directory.
+    fn spawn_bitcoind() -> (Node, BitcoindConfig) {
+        let exe = std::env::var("BITCOIND_EXE").unwrap_or_else(|_| "bitcoind".to_owned());
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let datadir = std::env::temp_dir().join(format!("faucet-bitcoind-{port}"));
+        std::fs::create_dir_all(&datadir).unwrap();
+        let node = Node(
+            Command::new(exe)
+                .arg("-regtest")
+                .arg("-txindex")
+                .arg("-listen=0")
+                .arg("-fallbackfee=0.0001")
+                .arg(format!("-rpcport={port}"))
+                .arg(format!("-datadir={}", datadir.display()))
+                .spawn()
+                .expect("bitcoind to start"),
+        );
+        let cfg = BitcoindConfig {
+            url: format!("http://127.0.0.1:{port}"),
+            cookie_file: Some(datadir.join("regtest").join(".cookie")),
+            user: None,
+            password: None,
+            start_height: 0,
+        };
+        (node, cfg)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs bitcoind to be installed"]
+    async fn test_bitcoind_chain_source() {
+        let (_node, cfg) = spawn_bitcoind();
+        // the cookie file only exists once bitcoind is up
+        let bitcoind = loop {
+            match Bitcoind::new(&cfg) {
+                Ok(bitcoind) if bitcoind.client.get_block_count().is_ok() => break bitcoind,
+                _ => tokio::time::sleep(Duration::from_millis(200)).await,
+            }
+        };
+
+        // have the node's own wallet fund and sign a transaction for us to
+        // broadcast
+        let tx = bitcoind
+            .call(|client| {
+                client.create_wallet("test", None, None, None, None)?;
+                let address = client.get_new_address(None, None)?.assume_checked();
+                client.generate_to_address(101, &address)?;
+                let outs = HashMap::from([(address.to_string(), Amount::from_sat(10_000))]);
+                let tx = client.create_raw_transaction(&[], &outs, None, Some(true))?;
+                let funded = client.fund_raw_transaction(&tx, None, None)?;
+                let signed = client.sign_raw_transaction_with_wallet(&funded.hex, None, None)?;
+                Ok(signed.transaction().unwrap())
+            })
+            .await
+            .unwrap();
+        let source = ChainSource::Bitcoind(bitcoind);
+
+        let txid = tx.compute_txid();
+        assert!(source.get_tx(&txid).await.unwrap().is_none());
+        source.broadcast(&tx).await.unwrap();
+        assert_eq!(source.get_tx(&txid).await.unwrap(), Some(tx));
+
+        // regtest has no fee estimates, but asking for them shouldn't fail
+        assert!(source.fee_estimates(&[1, 6]).await.is_ok());
+    }
+}
@@ -0,0 +1,499 @@
+//! Stateless proof-of-work challenges.
+//!
+//! Instead of storing outstanding challenges in the process-local
+//! [`ChallengeSet`](crate::pow::ChallengeSet), the server hands the client a
+//! token holding everything it needs to verify a solution later, signed with a
+//! key derived from the faucet's seed. This lets challenges survive restarts
+//! and be verified by any replica sharing the same seed.
+//!
+//! The only state kept is the set of spent tokens, which stops the same token
+//! from being used twice until it expires. It's kept in the sqlite database,
+//! so it survives restarts and is shared by all replicas using the same
+//! database file. Replicas with a database of their own each accept a token
+//! once.
+
+use std::{
+    sync::{LazyLock, OnceLock},
+    time::{Duration, Instant},
+};
+
+use bdk_wallet::rusqlite::{self, params, Connection};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use rand::{rng, Rng};
+use sha2::Sha256;
+use terrors::{OneOf, E7};
+use tracing::error;
+
+use crate::{
+    db::{self, now_secs as unix_now},
+    display_err, err,
+    ip_bucket::IpBucket,
+    pow::{
+        check_binding, check_shares, AddressMismatch, AlreadyClaimed, BadProofOfWork, Nonce,
+        NonceNotFound, Share, UnboundChallenge,
+    },
+    pow_algorithm::PowAlgorithm,
+    seed::Seed,
+    Chain,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Changes whenever the layout of tokens does, so that tokens issued by an
+/// older faucet are rejected instead of misread.
+const TOKEN_VERSION: u8 = 2;
+const MAC_LEN: usize = 32;
+/// version | nonce | chain | ip bucket | difficulty | expires at | algorithm |
+/// shares | address length
+const FIXED_PAYLOAD_LEN: usize = 1 + 16 + 1 + 16 + 1 + 8 + ALGORITHM_LEN + 1 + 1;
+/// kind | scrypt log n | scrypt r | scrypt p
+const ALGORITHM_LEN: usize = 1 + 1 + 4 + 4;
+
+/// How often expired tokens are pruned from the spent set.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+static TOKEN_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// When expired tokens were last pruned from the spent set.
+static LAST_PRUNE: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+/// The challenge token could not be decoded or wasn't signed by this faucet.
+#[derive(Debug)]
+pub struct InvalidChallengeToken;
+display_err!(
+    InvalidChallengeToken,
+    "The challenge token is invalid. Please request a new challenge."
+);
+
+/// Whether the token was spent before couldn't be checked.
+#[derive(Debug)]
+pub struct SpentTokensUnavailable;
+display_err!(
+    SpentTokensUnavailable,
+    "Failed checking the challenge token. Please try again."
+);
+
+/// Ways a client's proof of work solution for a token can be rejected.
+pub type CheckTokenError = OneOf<(
+    InvalidChallengeToken,
+    NonceNotFound,
+    BadProofOfWork,
+    AlreadyClaimed,
+    AddressMismatch,
+    UnboundChallenge,
+    SpentTokensUnavailable,
+)>;
+
+/// Short name of the reason a solution was rejected, used in metrics.
+pub fn rejection_label(e: &CheckTokenError) -> &'static str {
+    match e.as_enum() {
+        E7::A(InvalidChallengeToken) => "invalid_token",
+        E7::B(NonceNotFound) => "nonce_not_found",
+        E7::C(BadProofOfWork) => "bad_proof_of_work",
+        E7::D(AlreadyClaimed) => "already_claimed",
+        E7::E(AddressMismatch) => "address_mismatch",
+        E7::F(UnboundChallenge) => "unbound_challenge",
+        E7::G(SpentTokensUnavailable) => "spent_tokens_unavailable",
+    }
+}
+
+/// Creates the spent token table if it doesn't exist yet and clears out
+/// expired tokens.
+pub fn init() -> rusqlite::Result<()> {
+    db::with(|db| init_in(db))
+}
+
+fn init_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_spent_tokens (
+            nonce BLOB PRIMARY KEY NOT NULL,
+            expires_at INTEGER NOT NULL
+        ) WITHOUT ROWID;
+        CREATE INDEX IF NOT EXISTS faucet_spent_tokens_expires_at
+            ON faucet_spent_tokens (expires_at);",
+    )?;
+    prune_in(db, unix_now())
+}
+
+/// A proof-of-work challenge that is carried by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeToken {
+    nonce: Nonce,
+    chain: Chain,
+    ip: IpBucket,
+    /// Difficulty each share has to meet
+    difficulty: u8,
+    algorithm: PowAlgorithm,
+    /// Number of shares the solution has to consist of
+    shares: u8,
+    /// Unix timestamp in seconds
+    expires_at: u64,
+    /// Recipient address the challenge is bound to, if any
+    address: Option<String>,
+}
+
+/// Derives the key used to sign challenge tokens from the faucet's seed. Must
+/// be called once before any tokens are issued or checked.
+pub fn init_key(seed: &Seed) {
+    let mut mac = HmacSha256::new_from_slice(seed).expect("any key length is valid");
+    mac.update(b"alpen faucet challenge token key");
+    let key = mac.finalize().into_bytes().into();
+    TOKEN_KEY.set(key).expect("challenge token key set once");
+}
+
+fn key() -> &'static [u8; 32] {
+    TOKEN_KEY.get().expect("challenge token key initialised")
+}
+
+impl ChallengeToken {
+    /// Issues a new signed challenge for the given [`IpBucket`], optionally
+    /// bound to a recipient address.
+    ///
+    /// Addresses are at most 255 bytes long, which is checked by the caller.
+    pub fn issue(
+        chain: Chain,
+        ip: &IpBucket,
+        difficulty: u8,
+        algorithm: PowAlgorithm,
+        shares: u8,
+        challenge_duration: Duration,
+        address: Option<String>,
+    ) -> Self {
+        Self {
+            nonce: rng().random(),
+            chain,
+            ip: *ip,
+            difficulty,
+            algorithm,
+            shares,
+            expires_at: unix_now().saturating_add(challenge_duration.as_secs()),
+            address,
+        }
+    }
+
+    /// Serializes and signs the token.
+    pub fn encode(&self) -> Vec<u8> {
+        let address = self.address.as_deref().unwrap_or_default().as_bytes();
+        let mut bytes = Vec::with_capacity(FIXED_PAYLOAD_LEN + address.len() + MAC_LEN);
+        bytes.push(TOKEN_VERSION);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.push(match self.chain {
+            Chain::L1 => 1,
+            Chain::L2 => 2,
+        });
+        bytes.extend_from_slice(&self.ip.to_bits().to_be_bytes());
+        bytes.push(self.difficulty);
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes.extend_from_slice(&encode_algorithm(&self.algorithm));
+        bytes.push(self.shares);
+        bytes.push(u8::try_from(address.len()).expect("address at most 255 bytes"));
+        bytes.extend_from_slice(address);
+
+        let mut mac = HmacSha256::new_from_slice(key()).expect("any key length is valid");
+        mac.update(&bytes);
+        bytes.extend_from_slice(&mac.finalize().into_bytes());
+        bytes
+    }
+
+    /// Checks the token's signature and deserializes it.
+    pub fn decode(bytes: &[u8]) -> Result<Self, InvalidChallengeToken> {
+        if bytes.len() < FIXED_PAYLOAD_LEN + MAC_LEN || bytes[0] != TOKEN_VERSION {
+            return Err(InvalidChallengeToken);
+        }
+        let (payload, tag) = bytes.split_at(bytes.len() - MAC_LEN);
+        let mut mac = HmacSha256::new_from_slice(key()).expect("any key length is valid");
+        mac.update(payload);
+        mac.verify_slice(tag).map_err(|_| InvalidChallengeToken)?;
+
+        let chain = match payload[17] {
+            1 => Chain::L1,
+            2 => Chain::L2,
+            _ => return Err(InvalidChallengeToken),
+        };
+        let address = &payload[FIXED_PAYLOAD_LEN..];
+        if address.len() != usize::from(payload[FIXED_PAYLOAD_LEN - 1]) {
+            return Err(InvalidChallengeToken);
+        }
+        let address = match address {
+            [] => None,
+            address => {
+                Some(String::from_utf8(address.to_vec()).map_err(|_| InvalidChallengeToken)?)
+            }
+        };
+        Ok(Self {
+            nonce: payload[1..17].try_into().expect("correct length"),
+            chain,
+            ip: IpBucket::from_bits(u128::from_be_bytes(
+                payload[18..34].try_into().expect("correct length"),
+            )),
+            difficulty: payload[34],
+            algorithm: decode_algorithm(
+                payload[43..43 + ALGORITHM_LEN]
+                    .try_into()
+                    .expect("correct length"),
+            )?,
+            shares: payload[43 + ALGORITHM_LEN],
+            expires_at: u64::from_be_bytes(payload[35..43].try_into().expect("correct length")),
+            address,
+        })
+    }
+
+    /// Validates the proof of work solution by the client, who is claiming
+    /// funds to `address`, against the token they were issued.
+    ///
+    /// Unless `require_binding` is set, tokens that aren't bound to an
+    /// address are still accepted.
+    pub fn check_solution(
+        chain: Chain,
+        ip: &IpBucket,
+        address: &str,
+        token: &[u8],
+        solution: &[Share],
+        require_binding: bool,
+    ) -> Result<(), CheckTokenError> {
+        db::with(|db| {
+            Self::check_solution_in(db, chain, ip, address, token, solution, require_binding)
+        })
+    }
+
+    fn check_solution_in(
+        db: &Connection,
+        chain: Chain,
+        ip: &IpBucket,
+        address: &str,
+        token: &[u8],
+        solution: &[Share],
+        require_binding: bool,
+    ) -> Result<(), CheckTokenError> {
+        let token = Self::decode(token).map_err(OneOf::new)?;
+        if token.chain != chain || token.ip != *ip {
+            return err!(InvalidChallengeToken);
+        }
+        let now = unix_now();
+        if token.expires_at <= now {
+            return err!(NonceNotFound);
+        }
+        check_binding(token.address.as_deref(), address, require_binding)
+            .map_err(OneOf::broaden)?;
+
+        // like with stateful challenges, the token is spent whether or not
+        // the proof of work is valid to ratelimit attempts
+        match spend_in(db, &token.nonce, token.expires_at, now) {
+            Ok(true) => {}
+            Ok(false) => return err!(AlreadyClaimed),
+            Err(e) => {
+                error!("error spending challenge token: {e:?}");
+                return err!(SpentTokensUnavailable);
+            }
+        }
+
+        if check_shares(
+            &token.nonce,
+            token.difficulty,
+            &token.algorithm,
+            token.shares,
+            token.address.as_deref(),
+            solution,
+        ) {
+            Ok(())
+        } else {
+            err!(BadProofOfWork)
+        }
+    }
+
+    pub fn nonce(&self) -> Nonce {
+        self.nonce
+    }
+
+    pub fn difficulty(&self) -> u8 {
+        self.difficulty
+    }
+
+    pub fn algorithm(&self) -> PowAlgorithm {
+        self.algorithm
+    }
+
+    pub fn shares(&self) -> u8 {
+        self.shares
+    }
+
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_deref()
+    }
+}
+
+fn encode_algorithm(algorithm: &PowAlgorithm) -> [u8; ALGORITHM_LEN] {
+    let mut bytes = [0; ALGORITHM_LEN];
+    if let PowAlgorithm::Scrypt { log_n, r, p } = *algorithm {
+        bytes[0] = 1;
+        bytes[1] = log_n;
+        bytes[2..6].copy_from_slice(&r.to_be_bytes());
+        bytes[6..10].copy_from_slice(&p.to_be_bytes());
+    }
+    bytes
+}
+
+/// Decodes the algorithm of a token, which was validated when the token was
+/// issued.
+fn decode_algorithm(bytes: [u8; ALGORITHM_LEN]) -> Result<PowAlgorithm, InvalidChallengeToken> {
+    match bytes[0] {
+        0 => Ok(PowAlgorithm::Sha256),
+        1 => Ok(PowAlgorithm::Scrypt {
+            log_n: bytes[1],
+            r: u32::from_be_bytes(bytes[2..6].try_into().expect("correct length")),
+            p: u32::from_be_bytes(bytes[6..10].try_into().expect("correct length")),
+        }),
+        _ => Err(InvalidChallengeToken),
+    }
+}
+
+/// Marks the token with the given nonce as spent. Returns `false` if it was
+/// already spent.
+fn spend_in(db: &Connection, nonce: &Nonce, expires_at: u64, now: u64) -> rusqlite::Result<bool> {
+    {
+        let mut last_prune = LAST_PRUNE.lock();
+        if last_prune.elapsed() >= PRUNE_INTERVAL {
+            *last_prune = Instant::now();
+            drop(last_prune);
+            prune_in(db, now)?;
+        }
+    }
+    let inserted = db.execute(
+        "INSERT INTO faucet_spent_tokens (nonce, expires_at) VALUES (?1, ?2)
+        ON CONFLICT (nonce) DO NOTHING",
+        params![nonce.as_slice(), expires_at],
+    )?;
+    Ok(inserted > 0)
+}
+
+fn prune_in(db: &Connection, now: u64) -> rusqlite::Result<()> {
+    db.execute(
+        "DELETE FROM faucet_spent_tokens WHERE expires_at <= ?1",
+        [now],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    const ADDRESS: &str = "tb1qexample";
+
+    fn ip(last: u8) -> IpBucket {
+        IpBucket::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), 56)
+    }
+
+    fn db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+        db
+    }
+
+    fn token(address: Option<&str>) -> ChallengeToken {
+        let _ = TOKEN_KEY.set([7; 32]);
+        ChallengeToken::issue(
+            Chain::L1,
+            &ip(1),
+            0,
+            PowAlgorithm::Sha256,
+            1,
+            Duration::from_secs(120),
+            address.map(str::to_owned),
+        )
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let scrypt = ChallengeToken {
+            algorithm: PowAlgorithm::Scrypt {
+                log_n: 14,
+                r: 8,
+                p: 1,
+            },
+            shares: 16,
+            ..token(Some(ADDRESS))
+        };
+        for token in [token(None), token(Some(ADDRESS)), scrypt] {
+            let bytes = token.encode();
+            assert_eq!(ChallengeToken::decode(&bytes).unwrap(), token);
+        }
+    }
+
+    #[test]
+    fn test_tampered_token() {
+        let mut bytes = token(None).encode();
+        // bump the difficulty
+        bytes[34] = 1;
+        assert!(ChallengeToken::decode(&bytes).is_err());
+        assert!(ChallengeToken::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_token_bound_to_client_and_spent_once() {
+        let db = db();
+        let bytes = token(None).encode();
+        let solution = [[0; 8]];
+        let check = |chain, ip: &IpBucket| {
+            ChallengeToken::check_solution_in(&db, chain, ip, ADDRESS, &bytes, &solution, false)
+        };
+
+        let wrong_ip = check(Chain::L1, &ip(2));
+        assert!(wrong_ip
+            .unwrap_err()
+            .narrow::<InvalidChallengeToken, _>()
+            .is_ok());
+        let wrong_chain = check(Chain::L2, &ip(1));
+        assert!(wrong_chain
+            .unwrap_err()
+            .narrow::<InvalidChallengeToken, _>()
+            .is_ok());
+
+        // difficulty 0 so any solution is valid
+        check(Chain::L1, &ip(1)).unwrap();
+        let replay = check(Chain::L1, &ip(1));
+        assert!(replay.unwrap_err().narrow::<AlreadyClaimed, _>().is_ok());
+    }
+
+    #[test]
+    fn test_token_bound_to_address() {
+        let db = db();
+        let check = |address, token: &[u8]| {
+            ChallengeToken::check_solution_in(
+                &db,
+                Chain::L1,
+                &ip(1),
+                address,
+                token,
+                &[[0; 8]],
+                true,
+            )
+        };
+
+        let unbound = token(None).encode();
+        let res = check(ADDRESS, &unbound);
+        assert!(res.unwrap_err().narrow::<UnboundChallenge, _>().is_ok());
+
+        let bound = token(Some(ADDRESS)).encode();
+        let res = check("tb1qother", &bound);
+        assert!(res.unwrap_err().narrow::<AddressMismatch, _>().is_ok());
+        check(ADDRESS, &bound).unwrap();
+    }
+
+    #[test]
+    fn test_spent_tokens() {
+        let db = db();
+        assert!(spend_in(&db, &[1; 16], 100, 50).unwrap());
+        assert!(!spend_in(&db, &[1; 16], 100, 50).unwrap());
+        assert!(spend_in(&db, &[2; 16], 200, 50).unwrap());
+
+        // expired tokens are forgotten, they're rejected for being expired
+        // before they're checked against the spent set
+        prune_in(&db, 100).unwrap();
+        assert!(spend_in(&db, &[1; 16], 300, 150).unwrap());
+        assert!(!spend_in(&db, &[2; 16], 200, 150).unwrap());
+    }
+}
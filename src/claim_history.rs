@@ -0,0 +1,494 @@
+//! Per-client claim history, used to raise the difficulty for clients that
+//! keep coming back.
+//!
+//! Every claim adds one to the weight of the claiming IP bucket, and of the
+//! recipient address, which then halves every `reputation.half_life` of the
+//! chain. The history is kept in memory, bounded to `max_entries` clients,
+//! and can be written through to the wallet's sqlite database so that it
+//! survives restarts. Writes to the database happen in the background, in
+//! the order the history changed.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem,
+    time::Duration,
+};
+
+use bdk_wallet::rusqlite::{self, params, Connection};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::spawn_blocking,
+};
+use tracing::error;
+
+use crate::{
+    cooldown::CooldownKey,
+    db::{self, now_secs},
+    Chain,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClaimHistoryConfig {
+    /// Most clients to remember across both chains. Once full, the client
+    /// that claimed least recently is forgotten to make room.
+    ///
+    /// Defaults to `100000`.
+    pub max_entries: usize,
+
+    /// Whether to keep the history in the sqlite database, so it survives
+    /// restarts.
+    ///
+    /// Defaults to `false`.
+    pub persist: bool,
+}
+
+impl Default for ClaimHistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            persist: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReputationConfig {
+    /// Difficulty added for every recent claim of the client. Disabled when
+    /// `0`.
+    ///
+    /// Defaults to `0`.
+    pub bits_per_claim: u8,
+
+    /// How long it takes for a claim to count half as much.
+    ///
+    /// Defaults to `86400` seconds.
+    pub half_life: Duration,
+
+    /// Most a client's claims can add to the difficulty.
+    ///
+    /// Defaults to `8`.
+    pub max_extra_difficulty: u8,
+
+    /// Whether claims to the recipient address count as well, for challenges
+    /// bound to an address.
+    ///
+    /// Defaults to `false`.
+    pub by_address: bool,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            bits_per_claim: 0,
+            half_life: Duration::from_secs(60 * 60 * 24),
+            max_extra_difficulty: 8,
+            by_address: false,
+        }
+    }
+}
+
+impl ReputationConfig {
+    /// Whether the half life isn't zero.
+    pub fn is_valid(&self) -> bool {
+        !self.half_life.is_zero()
+    }
+
+    /// Difficulty to add for a client with `recent_claims`.
+    pub fn extra_difficulty(&self, recent_claims: f64) -> u8 {
+        (f64::from(self.bits_per_claim) * recent_claims)
+            .round()
+            .min(self.max_extra_difficulty.into()) as u8
+    }
+}
+
+/// Most changes written to the db in one transaction
+const MAX_WRITE_BATCH: usize = 1000;
+
+/// A client on a chain, identified by its [`CooldownKey`].
+type ClientId = (Chain, String);
+
+/// Claims of a single client on a single chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    /// Claims as of `updated_at`, each weighted by how long ago it was made
+    weight: f64,
+    /// Unix time of the last claim
+    updated_at: u64,
+}
+
+impl Entry {
+    fn weight_at(&self, now: u64, half_life: Duration) -> f64 {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.weight * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+    }
+}
+
+/// Entries of all clients, along with the order they were last updated in so
+/// the least recently updated one can be found without a scan.
+#[derive(Debug, Default)]
+struct Entries {
+    by_client: HashMap<ClientId, Entry>,
+    by_age: BTreeSet<(u64, ClientId)>,
+}
+
+impl Entries {
+    fn get(&self, id: &ClientId) -> Option<&Entry> {
+        self.by_client.get(id)
+    }
+
+    fn len(&self) -> usize {
+        self.by_client.len()
+    }
+
+    fn insert(&mut self, id: ClientId, entry: Entry) {
+        if let Some(old) = self.by_client.insert(id.clone(), entry) {
+            self.by_age.remove(&(old.updated_at, id.clone()));
+        }
+        self.by_age.insert((entry.updated_at, id));
+    }
+
+    /// Removes the least recently updated entry and returns its client.
+    fn pop_oldest(&mut self) -> Option<ClientId> {
+        let (_, id) = self.by_age.pop_first()?;
+        self.by_client.remove(&id);
+        Some(id)
+    }
+}
+
+/// A change to the history, to be written to the db.
+#[derive(Debug)]
+enum Write {
+    Save(ClientId, Entry),
+    Delete(ClientId),
+}
+
+/// The claim history of all clients, keyed by chain and the
+/// [`CooldownKey`] of the client.
+#[derive(Debug)]
+pub struct ClaimHistory {
+    config: ClaimHistoryConfig,
+    entries: Mutex<Entries>,
+    /// Sends changes to the task writing them to the db, if `persist` is set
+    writes: Option<UnboundedSender<Write>>,
+}
+
+impl ClaimHistory {
+    /// Creates an empty history, or loads the persisted one if `persist` is
+    /// set.
+    ///
+    /// With `persist` set, this must be called from within a tokio runtime,
+    /// which the history is written to the db on.
+    pub fn new(config: ClaimHistoryConfig) -> rusqlite::Result<Self> {
+        let mut entries = Entries::default();
+        let mut writes = None;
+        if config.persist {
+            let loaded = db::with(|db| {
+                init_in(db)?;
+                load_in(db, config.max_entries)
+            })?;
+            for (id, entry) in loaded {
+                entries.insert(id, entry);
+            }
+            writes = Some(spawn_writer());
+        }
+        Ok(Self {
+            config,
+            entries: Mutex::new(entries),
+            writes,
+        })
+    }
+
+    /// Recent claims of whichever of the `keys` has the most, with each claim
+    /// halving every `half_life`.
+    pub fn recent_claims(&self, chain: Chain, keys: &[CooldownKey], half_life: Duration) -> f64 {
+        let now = now_secs();
+        let entries = self.entries.lock();
+        keys.iter()
+            .filter_map(|key| entries.get(&(chain, key.to_string())))
+            .map(|entry| entry.weight_at(now, half_life))
+            .fold(0.0, f64::max)
+    }
+
+    /// Counts a claim made by all the `keys`.
+    pub fn record(&self, chain: Chain, keys: &[CooldownKey], half_life: Duration) {
+        let now = now_secs();
+        let mut entries = self.entries.lock();
+        for key in keys {
+            let id = (chain, key.to_string());
+            let weight = match entries.get(&id) {
+                Some(entry) => entry.weight_at(now, half_life),
+                None => {
+                    if entries.len() >= self.config.max_entries {
+                        if let Some(oldest) = entries.pop_oldest() {
+                            self.write(Write::Delete(oldest));
+                        }
+                    }
+                    0.0
+                }
+            };
+            let entry = Entry {
+                weight: weight + 1.0,
+                updated_at: now,
+            };
+            self.write(Write::Save(id.clone(), entry));
+            entries.insert(id, entry);
+        }
+    }
+
+    /// Queues a change to be written to the db, if persisting. Changes are
+    /// queued while holding the lock on the entries, so they're written in
+    /// the order they were made.
+    fn write(&self, write: Write) {
+        if let Some(writes) = &self.writes {
+            // only fails if the writer task is gone, which it logs
+            let _ = writes.send(write);
+        }
+    }
+}
+
+/// Spawns a tokio task writing changes to the history to the db, in the
+/// order they're received.
+fn spawn_writer() -> UnboundedSender<Write> {
+    let (tx, mut rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut writes = Vec::new();
+        while rx.recv_many(&mut writes, MAX_WRITE_BATCH).await > 0 {
+            let batch = mem::take(&mut writes);
+            match spawn_blocking(move || db::with(|db| write_in(db, &batch))).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("failed writing claim history: {e:?}"),
+                Err(e) => error!("claim history writer panicked: {e:?}"),
+            }
+        }
+        error!("claim history writer stopped");
+    });
+    tx
+}
+
+fn write_in(db: &mut Connection, writes: &[Write]) -> rusqlite::Result<()> {
+    let db_tx = db.transaction()?;
+    for write in writes {
+        match write {
+            Write::Save(id, entry) => save_in(&db_tx, id, entry)?,
+            Write::Delete(id) => delete_in(&db_tx, id)?,
+        }
+    }
+    db_tx.commit()
+}
+
+fn init_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_claim_history (
+            key TEXT NOT NULL,
+            chain TEXT NOT NULL,
+            weight REAL NOT NULL,
+            updated_at INTEGER NOT NULL,
+            PRIMARY KEY (key, chain)
+        ) WITHOUT ROWID;",
+    )
+}
+
+/// Loads the `max_entries` most recent entries and deletes the rest.
+fn load_in(db: &Connection, max_entries: usize) -> rusqlite::Result<HashMap<ClientId, Entry>> {
+    let mut stmt = db.prepare(
+        "SELECT key, chain, weight, updated_at FROM faucet_claim_history
+        ORDER BY updated_at DESC",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut entries = HashMap::new();
+    let mut stale = Vec::new();
+    while let Some(row) = rows.next()? {
+        let key: String = row.get(0)?;
+        let chain: String = row.get(1)?;
+        let Ok(chain) = Chain::try_from(chain.as_str()) else {
+            continue;
+        };
+        if entries.len() >= max_entries {
+            stale.push((chain, key));
+            continue;
+        }
+        let entry = Entry {
+            weight: row.get(2)?,
+            updated_at: row.get(3)?,
+        };
+        entries.insert((chain, key), entry);
+    }
+    for id in &stale {
+        delete_in(db, id)?;
+    }
+    Ok(entries)
+}
+
+fn save_in(db: &Connection, (chain, key): &ClientId, entry: &Entry) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO faucet_claim_history (key, chain, weight, updated_at) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (key, chain) DO UPDATE
+        SET weight = excluded.weight, updated_at = excluded.updated_at",
+        params![key, chain.as_str(), entry.weight, entry.updated_at],
+    )?;
+    Ok(())
+}
+
+fn delete_in(db: &Connection, (chain, key): &ClientId) -> rusqlite::Result<()> {
+    db.execute(
+        "DELETE FROM faucet_claim_history WHERE key = ?1 AND chain = ?2",
+        params![key, chain.as_str()],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::ip_bucket::IpBucket;
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    fn ip(d: u8) -> CooldownKey {
+        CooldownKey::Ip(IpBucket::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, d)), 56))
+    }
+
+    fn history(max_entries: usize) -> ClaimHistory {
+        ClaimHistory::new(ClaimHistoryConfig {
+            max_entries,
+            persist: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_recent_claims() {
+        let history = history(10);
+        let address = CooldownKey::Address("tb1qexample".to_owned());
+
+        history.record(Chain::L1, &[ip(1)], DAY);
+        history.record(Chain::L1, &[ip(1), address.clone()], DAY);
+        assert_relative_eq!(history.recent_claims(Chain::L1, &[ip(1)], DAY), 2.0);
+        // the client with the most claims counts
+        assert_relative_eq!(
+            history.recent_claims(Chain::L1, &[ip(2), address.clone()], DAY),
+            1.0
+        );
+        assert_relative_eq!(
+            history.recent_claims(Chain::L1, &[ip(1), address], DAY),
+            2.0
+        );
+        // per chain
+        assert_relative_eq!(history.recent_claims(Chain::L2, &[ip(1)], DAY), 0.0);
+    }
+
+    #[test]
+    fn test_decay() {
+        let entry = Entry {
+            weight: 4.0,
+            updated_at: 1000,
+        };
+        assert_relative_eq!(entry.weight_at(1000, DAY), 4.0);
+        assert_relative_eq!(entry.weight_at(1000 + DAY.as_secs(), DAY), 2.0);
+        assert_relative_eq!(entry.weight_at(1000 + 3 * DAY.as_secs(), DAY), 0.5);
+        // clocks going backwards don't add weight
+        assert_relative_eq!(entry.weight_at(0, DAY), 4.0);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let history = history(3);
+        for d in 1..=5 {
+            history.record(Chain::L2, &[ip(d)], DAY);
+        }
+        assert_eq!(history.entries.lock().len(), 3);
+    }
+
+    #[test]
+    fn test_eviction_order() {
+        let mut entries = Entries::default();
+        let id = |d| (Chain::L1, ip(d).to_string());
+        let entry = |updated_at| Entry {
+            weight: 1.0,
+            updated_at,
+        };
+        entries.insert(id(1), entry(10));
+        entries.insert(id(2), entry(20));
+        entries.insert(id(3), entry(30));
+        // updating an entry makes it the most recent
+        entries.insert(id(1), entry(40));
+
+        assert_eq!(entries.pop_oldest(), Some(id(2)));
+        assert_eq!(entries.pop_oldest(), Some(id(3)));
+        assert_eq!(entries.pop_oldest(), Some(id(1)));
+        assert_eq!(entries.pop_oldest(), None);
+        assert!(entries.by_client.is_empty());
+    }
+
+    #[test]
+    fn test_write_in() {
+        let mut db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+        let id = |d| (Chain::L2, ip(d).to_string());
+        let entry = |weight| Entry {
+            weight,
+            updated_at: 10,
+        };
+        let writes = [
+            Write::Save(id(1), entry(1.0)),
+            Write::Save(id(2), entry(1.0)),
+            Write::Save(id(1), entry(2.0)),
+            Write::Delete(id(2)),
+        ];
+        write_in(&mut db, &writes).unwrap();
+
+        let entries = load_in(&db, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[&id(1)], entry(2.0));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+        for (d, updated_at) in [(1, 30), (2, 10), (3, 20)] {
+            let id = (Chain::L1, ip(d).to_string());
+            let entry = Entry {
+                weight: 1.5,
+                updated_at,
+            };
+            save_in(&db, &id, &entry).unwrap();
+        }
+
+        // only the most recent entries are kept
+        let entries = load_in(&db, 2).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[&(Chain::L1, ip(1).to_string())],
+            Entry {
+                weight: 1.5,
+                updated_at: 30
+            }
+        );
+        assert!(!entries.contains_key(&(Chain::L1, ip(2).to_string())));
+        assert_eq!(load_in(&db, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_extra_difficulty() {
+        let cfg = ReputationConfig {
+            bits_per_claim: 2,
+            max_extra_difficulty: 5,
+            ..Default::default()
+        };
+        assert_eq!(cfg.extra_difficulty(0.0), 0);
+        // a claim made moments ago still counts fully
+        assert_eq!(cfg.extra_difficulty(0.9999), 2);
+        assert_eq!(cfg.extra_difficulty(1.5), 3);
+        assert_eq!(cfg.extra_difficulty(100.0), 5);
+        assert_eq!(ReputationConfig::default().extra_difficulty(100.0), 0);
+    }
+}
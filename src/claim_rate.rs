@@ -0,0 +1,158 @@
+//! Raising the difficulty while claims come in faster than expected.
+//!
+//! The recent claim rate of each chain is an exponentially weighted moving
+//! average, so it climbs during a burst of claims and relaxes afterwards.
+
+use std::{
+    f64::consts::LN_2,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClaimRateConfig {
+    /// Claims per minute the faucet expects at most. While the recent claim
+    /// rate is above it, the difficulty goes up by one every time the rate
+    /// doubles. Disabled when not set.
+    ///
+    /// Defaults to `None`.
+    pub target_per_minute: Option<f64>,
+
+    /// How long it takes for a claim to count half as much towards the
+    /// recent claim rate.
+    ///
+    /// Defaults to `300` seconds.
+    pub half_life: Duration,
+
+    /// Most the claim rate can add to the difficulty.
+    ///
+    /// Defaults to `8`.
+    pub max_extra_difficulty: u8,
+}
+
+impl Default for ClaimRateConfig {
+    fn default() -> Self {
+        Self {
+            target_per_minute: None,
+            half_life: Duration::from_secs(300),
+            max_extra_difficulty: 8,
+        }
+    }
+}
+
+impl ClaimRateConfig {
+    /// Whether the target rate is positive and the half life isn't zero.
+    pub fn is_valid(&self) -> bool {
+        self.target_per_minute
+            .is_none_or(|target| target.is_finite() && target > 0.0)
+            && !self.half_life.is_zero()
+    }
+
+    /// Difficulty to add while claims come in at `claims_per_minute`.
+    pub fn extra_difficulty(&self, claims_per_minute: f64) -> u8 {
+        let Some(target) = self.target_per_minute else {
+            return 0;
+        };
+        let ratio = claims_per_minute / target;
+        if ratio < 2.0 {
+            return 0;
+        }
+        (ratio.log2().floor() as u64).min(self.max_extra_difficulty.into()) as u8
+    }
+}
+
+/// Exponentially weighted moving average of the claim rate.
+#[derive(Debug)]
+pub struct ClaimRate {
+    /// Claims so far, each weighted by how long ago it was made
+    weight: f64,
+    updated_at: Instant,
+}
+
+impl ClaimRate {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            weight: 0.0,
+            updated_at: now,
+        }
+    }
+
+    /// Counts a claim made at `now`.
+    pub fn record(&mut self, now: Instant, half_life: Duration) {
+        self.decay(now, half_life);
+        self.weight += 1.0;
+    }
+
+    /// Recent claims per minute, as of `now`.
+    pub fn per_minute(&mut self, now: Instant, half_life: Duration) -> f64 {
+        self.decay(now, half_life);
+        // at a steady rate, the weight settles at the rate times the mean
+        // lifetime of a claim, which is `half_life / ln 2`
+        self.weight * LN_2 / half_life.as_secs_f64() * 60.0
+    }
+
+    fn decay(&mut self, now: Instant, half_life: Duration) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.weight *= 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        self.updated_at = self.updated_at.max(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_claim_rate() {
+        let half_life = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut rate = ClaimRate::new(start);
+
+        // one claim a second for a long while settles at 60 a minute
+        let mut now = start;
+        for _ in 0..3600 {
+            now += Duration::from_secs(1);
+            rate.record(now, half_life);
+        }
+        assert_relative_eq!(rate.per_minute(now, half_life), 60.0, max_relative = 0.02);
+
+        // and halves every half life once they stop
+        let settled = rate.per_minute(now, half_life);
+        assert_relative_eq!(
+            rate.per_minute(now + half_life, half_life),
+            settled / 2.0,
+            max_relative = 1e-9
+        );
+        assert_relative_eq!(
+            rate.per_minute(now + 3 * half_life, half_life),
+            settled / 8.0,
+            max_relative = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_extra_difficulty() {
+        let cfg = ClaimRateConfig {
+            target_per_minute: Some(10.0),
+            max_extra_difficulty: 4,
+            ..Default::default()
+        };
+        assert_eq!(cfg.extra_difficulty(0.0), 0);
+        assert_eq!(cfg.extra_difficulty(19.9), 0);
+        assert_eq!(cfg.extra_difficulty(20.0), 1);
+        assert_eq!(cfg.extra_difficulty(45.0), 2);
+        assert_eq!(cfg.extra_difficulty(1e9), 4);
+        assert_eq!(ClaimRateConfig::default().extra_difficulty(1e9), 0);
+
+        assert!(cfg.is_valid());
+        assert!(!ClaimRateConfig {
+            target_per_minute: Some(0.0),
+            ..Default::default()
+        }
+        .is_valid());
+    }
+}
@@ -0,0 +1,229 @@
+//! Tracking of individual claims as they move through the payout pipeline.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::TxHash;
+use bdk_wallet::bitcoin::Txid;
+use parking_lot::{Mutex, RwLock};
+use rand::{rng, Rng};
+use serde::Serialize;
+
+use crate::Chain;
+
+pub type ClaimId = [u8; 16];
+
+/// How long a claim is remembered after it reaches a terminal state.
+const CLAIM_RETENTION: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long a claim is remembered without changing state, whatever that state
+/// is. Broadcast claims only become confirmed when they're polled, so without
+/// this, claims nobody asks about again would be remembered forever.
+const CLAIM_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// How often finished claims are pruned from the tracker.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// ID of the transaction paying out a claim, on either chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum PayoutTxid {
+    L1(Txid),
+    L2(TxHash),
+}
+
+impl From<Txid> for PayoutTxid {
+    fn from(txid: Txid) -> Self {
+        Self::L1(txid)
+    }
+}
+
+impl From<TxHash> for PayoutTxid {
+    fn from(hash: TxHash) -> Self {
+        Self::L2(hash)
+    }
+}
+
+/// Where a claim currently is in its lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ClaimState {
+    /// Waiting in the batcher's queue, or for the L2 payout worker to send
+    /// it.
+    Queued,
+    /// Drained from the queue and added to a batch transaction that is being
+    /// built and signed.
+    InBatch,
+    /// Included in a transaction that has been broadcast.
+    Broadcast { txid: PayoutTxid },
+    /// Included in a transaction that has been confirmed.
+    Confirmed { txid: PayoutTxid, height: u64 },
+    /// The payout failed and will not be retried.
+    Failed { reason: String },
+}
+
+impl ClaimState {
+    /// Whether the claim will not change state anymore.
+    fn is_terminal(&self) -> bool {
+        matches!(self, Self::Confirmed { .. } | Self::Failed { .. })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClaimRecord {
+    pub chain: Chain,
+    pub state: ClaimState,
+    updated_at: Instant,
+}
+
+impl ClaimRecord {
+    /// Whether the claim can be forgotten at `now`.
+    fn is_expired(&self, now: Instant) -> bool {
+        let age = now.saturating_duration_since(self.updated_at);
+        age > CLAIM_MAX_AGE || (self.state.is_terminal() && age > CLAIM_RETENTION)
+    }
+}
+
+/// In-memory registry of claims and their current [`ClaimState`].
+#[derive(Debug)]
+pub struct ClaimTracker {
+    claims: RwLock<HashMap<ClaimId, ClaimRecord>>,
+    last_prune: Mutex<Instant>,
+}
+
+impl Default for ClaimTracker {
+    fn default() -> Self {
+        Self {
+            claims: Default::default(),
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl ClaimTracker {
+    /// Registers a new claim in the [`ClaimState::Queued`] state and returns
+    /// its freshly generated ID.
+    pub fn register(&self, chain: Chain) -> ClaimId {
+        self.prune();
+        let id = rng().random();
+        self.claims.write().insert(
+            id,
+            ClaimRecord {
+                chain,
+                state: ClaimState::Queued,
+                updated_at: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// Re-registers a claim that was persisted before the faucet restarted.
+    pub fn restore(&self, id: ClaimId, chain: Chain, state: ClaimState) {
+        self.claims.write().insert(
+            id,
+            ClaimRecord {
+                chain,
+                state,
+                updated_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Moves the claim with the given ID to a new state.
+    pub fn set_state(&self, id: &ClaimId, state: ClaimState) {
+        if let Some(record) = self.claims.write().get_mut(id) {
+            record.state = state;
+            record.updated_at = Instant::now();
+        }
+    }
+
+    /// Moves all claims with the given IDs to a new state.
+    pub fn set_state_many<'a>(
+        &self,
+        ids: impl IntoIterator<Item = &'a ClaimId>,
+        state: ClaimState,
+    ) {
+        let mut claims = self.claims.write();
+        let now = Instant::now();
+        for id in ids {
+            if let Some(record) = claims.get_mut(id) {
+                record.state = state.clone();
+                record.updated_at = now;
+            }
+        }
+    }
+
+    pub fn get(&self, id: &ClaimId) -> Option<ClaimRecord> {
+        self.claims.read().get(id).cloned()
+    }
+
+    /// Drops claims that reached a terminal state more than
+    /// [`CLAIM_RETENTION`] ago, and any claim that hasn't changed state for
+    /// [`CLAIM_MAX_AGE`]. Runs at most once every [`PRUNE_INTERVAL`].
+    fn prune(&self) {
+        let mut last_prune = self.last_prune.lock();
+        if last_prune.elapsed() < PRUNE_INTERVAL {
+            return;
+        }
+        *last_prune = Instant::now();
+        drop(last_prune);
+
+        let now = Instant::now();
+        self.claims.write().retain(|_, r| !r.is_expired(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_lifecycle() {
+        let tracker = ClaimTracker::default();
+        let id = tracker.register(Chain::L1);
+        let other = tracker.register(Chain::L1);
+        assert_ne!(id, other);
+        assert_eq!(tracker.get(&id).unwrap().state, ClaimState::Queued);
+
+        tracker.set_state_many([&id, &other], ClaimState::InBatch);
+        assert_eq!(tracker.get(&id).unwrap().state, ClaimState::InBatch);
+        assert_eq!(tracker.get(&other).unwrap().state, ClaimState::InBatch);
+
+        let reason = "nope".to_owned();
+        tracker.set_state(&id, ClaimState::Failed { reason });
+        assert!(tracker.get(&id).unwrap().state.is_terminal());
+        assert!(!tracker.get(&other).unwrap().state.is_terminal());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let now = Instant::now();
+        let is_expired = |state, age| {
+            let record = ClaimRecord {
+                chain: Chain::L1,
+                state,
+                updated_at: now,
+            };
+            record.is_expired(now + age)
+        };
+        let failed = || ClaimState::Failed {
+            reason: "nope".to_owned(),
+        };
+        let hour = Duration::from_secs(60 * 60);
+
+        assert!(!is_expired(failed(), hour));
+        assert!(is_expired(failed(), CLAIM_RETENTION + hour));
+        // claims that never reach a terminal state are dropped eventually
+        assert!(!is_expired(ClaimState::Queued, CLAIM_RETENTION + hour));
+        assert!(is_expired(ClaimState::Queued, CLAIM_MAX_AGE + hour));
+    }
+
+    #[test]
+    fn test_unknown_claim() {
+        let tracker = ClaimTracker::default();
+        tracker.set_state(&[0; 16], ClaimState::InBatch);
+        assert!(tracker.get(&[0; 16]).is_none());
+    }
+}
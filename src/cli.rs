@@ -0,0 +1,292 @@
+//! Command line interface of the faucet.
+//!
+//! Besides serving the API, the binary has a few subcommands for operating
+//! the faucet's wallets without starting the server.
+
+use std::{
+    io::{self, IsTerminal},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use alloy::{
+    network::TransactionBuilder,
+    primitives::{Address as L2Address, U256},
+    providers::Provider,
+    rpc::types::TransactionRequest,
+    transports::TransportError,
+};
+use bdk_wallet::{
+    bitcoin::{address::NetworkUnchecked, Address as L1Address, Amount, Txid},
+    KeychainKind, Wallet,
+};
+use bip39::Mnemonic;
+use clap::{Parser, Subcommand};
+use parking_lot::RwLock;
+
+use crate::{
+    batcher::{self, BroadcastStatus},
+    chain_source::CHAIN_SOURCE,
+    l1::{self, Derivation, L1Wallet, Persister},
+    l2::L2Wallet,
+    seed::{self, Seed},
+    settings::{config_path, Settings, DEFAULT_CONFIG_PATH, SETTINGS},
+    SATS_TO_WEI,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of the config file
+    #[arg(short, long, global = true, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+    /// What to do, `serve` if not given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serve the faucet API
+    Serve,
+    /// Print the L1 and L2 funding addresses
+    Address,
+    /// Sync the wallets and print their balances
+    Balance,
+    /// Validate the config and print the effective settings
+    CheckConfig,
+    /// Print the public descriptors of the L1 wallet
+    ExportDescriptor,
+    /// Send the whole balance of the L1 or L2 wallet to an address. Stop the
+    /// faucet first so it doesn't try to spend the same funds.
+    Sweep {
+        /// L1 or L2 address to send the funds to. The wallet to drain is
+        /// picked by the kind of address.
+        address: String,
+    },
+    /// Print the seed as a BIP39 mnemonic
+    Mnemonic,
+    /// Create the seed file from a 24 word BIP39 mnemonic, prompted for or
+    /// read from stdin
+    ImportMnemonic,
+    /// Send the funds of the legacy L1 wallet to the first address of the
+    /// `bip39` derivation. Run with `derivation = "legacy"` and the faucet
+    /// stopped, then switch to `derivation = "bip39"`.
+    MigrateDerivation,
+}
+
+pub async fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::Serve => {
+            crate::serve().await;
+            Ok(())
+        }
+        Command::Address => address(),
+        Command::Balance => balance().await,
+        Command::CheckConfig => check_config(),
+        Command::ExportDescriptor => export_descriptor(),
+        Command::Sweep { address } => sweep(&address).await,
+        Command::Mnemonic => mnemonic(),
+        Command::ImportMnemonic => import_mnemonic(),
+        Command::MigrateDerivation => migrate_derivation().await,
+    }
+}
+
+fn load_seed() -> Result<Seed, String> {
+    seed::load().map_err(|e| format!("failed loading seed: {e}"))
+}
+
+fn l1_wallet(seed: &Seed) -> Result<L1Wallet, String> {
+    L1Wallet::new(SETTINGS.network, seed).map_err(|e| format!("failed opening l1 wallet: {e}"))
+}
+
+fn l2_wallet(seed: &Seed) -> Result<L2Wallet, String> {
+    L2Wallet::new(seed).map_err(|_| "invalid l2 endpoint".to_owned())
+}
+
+fn address() -> Result<(), String> {
+    let seed = load_seed()?;
+    let mut l1_wallet = l1_wallet(&seed)?;
+    let l1_address = l1_wallet.next_unused_address(KeychainKind::External);
+    l1_wallet
+        .persist(&mut Persister)
+        .map_err(|e| format!("failed persisting l1 wallet: {e}"))?;
+    let l2_address = l2_wallet(&seed)?.default_signer_address();
+
+    println!("L1: {}", l1_address.address);
+    println!("L2: {l2_address}");
+    Ok(())
+}
+
+async fn balance() -> Result<(), String> {
+    let seed = load_seed()?;
+    let l1_wallet = Arc::new(RwLock::new(l1_wallet(&seed)?));
+    CHAIN_SOURCE
+        .sync(&l1_wallet)
+        .await
+        .map_err(|e| format!("failed syncing l1 wallet: {e}"))?;
+    let l1_balance = l1_wallet.read().balance();
+    let l2_wei = l2_wallet(&seed)?.get_default_signer_balance().await?;
+
+    println!(
+        "L1: {} ({} unconfirmed)",
+        l1_balance.confirmed,
+        l1_balance.trusted_pending + l1_balance.untrusted_pending
+    );
+    println!(
+        "L2: {} ({l2_wei} wei)",
+        Amount::from_sat((l2_wei / SATS_TO_WEI as u128) as u64)
+    );
+    Ok(())
+}
+
+/// Loads the settings itself instead of through [`SETTINGS`], so an invalid
+/// config is reported instead of panicking.
+fn check_config() -> Result<(), String> {
+    let settings = Settings::load(config_path()).map_err(|e| format!("invalid config: {e:?}"))?;
+    println!("{settings:#?}");
+    Ok(())
+}
+
+fn export_descriptor() -> Result<(), String> {
+    let seed = load_seed()?;
+    let l1_wallet = l1_wallet(&seed)?;
+    for keychain in [KeychainKind::External, KeychainKind::Internal] {
+        println!("{}", l1_wallet.public_descriptor(keychain));
+    }
+    Ok(())
+}
+
+async fn sweep(address: &str) -> Result<(), String> {
+    if let Ok(address) = address.parse::<L2Address>() {
+        return sweep_l2(address).await;
+    }
+    let address = address
+        .parse::<L1Address<NetworkUnchecked>>()
+        .map_err(|_| "not a valid L1 or L2 address".to_owned())?
+        .require_network(SETTINGS.network)
+        .map_err(|_| format!("not an address on {}", SETTINGS.network))?;
+    sweep_l1(address).await
+}
+
+async fn sweep_l1(address: L1Address) -> Result<(), String> {
+    let txid = drain_l1(&load_seed()?, address).await?;
+    println!("{txid}");
+    Ok(())
+}
+
+/// Sends all of the L1 wallet's funds to `address`.
+async fn drain_l1(seed: &Seed, address: L1Address) -> Result<Txid, String> {
+    let l1_wallet = Arc::new(RwLock::new(l1_wallet(seed)?));
+    CHAIN_SOURCE
+        .sync(&l1_wallet)
+        .await
+        .map_err(|e| format!("failed syncing l1 wallet: {e}"))?;
+    let fee_rate = l1::estimate_fee_rate().await;
+
+    let tx = {
+        let mut l1w = l1_wallet.write();
+        let mut builder = l1w.build_tx();
+        builder
+            .drain_wallet()
+            .drain_to(address.script_pubkey())
+            .fee_rate(fee_rate);
+        let mut psbt = builder
+            .finish()
+            .map_err(|e| format!("failed building sweep tx: {e}"))?;
+        l1w.sign(&mut psbt, Default::default())
+            .expect("signing should not fail");
+        psbt.extract_tx().expect("fully signed psbt")
+    };
+    let txid = tx.compute_txid();
+    match batcher::broadcast(&tx).await {
+        BroadcastStatus::Sent => {}
+        BroadcastStatus::Dropped => return Err(format!("failed broadcasting sweep tx {txid}")),
+        BroadcastStatus::Unknown => {
+            return Err(format!(
+                "couldn't tell whether sweep tx {txid} went out, check before sweeping again"
+            ))
+        }
+    }
+    batcher::apply_unconfirmed_tx(&l1_wallet, tx);
+    Ok(txid)
+}
+
+/// Sends the L2 balance minus the most the transaction can cost in fees, so
+/// a little is left behind if the base fee ends up lower than estimated.
+async fn sweep_l2(address: L2Address) -> Result<(), String> {
+    let seed = load_seed()?;
+    let l2_wallet = l2_wallet(&seed)?;
+    let from = l2_wallet.default_signer_address();
+
+    let res = async {
+        let balance = l2_wallet.get_balance(from).await?;
+        let nonce = l2_wallet.get_transaction_count(from).pending().await?;
+        let fees = l2_wallet.estimate_eip1559_fees().await?;
+        let tx = TransactionRequest::default()
+            .with_from(from)
+            .with_to(address)
+            .with_nonce(nonce)
+            .with_max_fee_per_gas(fees.max_fee_per_gas)
+            .with_max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
+        let gas_limit = l2_wallet.estimate_gas(tx.clone()).await?;
+        Ok::<_, TransportError>((balance, tx, gas_limit, fees.max_fee_per_gas))
+    }
+    .await;
+    let (balance, tx, gas_limit, max_fee_per_gas) =
+        res.map_err(|e| format!("l2 request failed: {e}"))?;
+
+    let max_fee = U256::from(gas_limit) * U256::from(max_fee_per_gas);
+    if balance <= max_fee {
+        return Err(format!(
+            "l2 balance of {balance} wei doesn't cover the fee of up to {max_fee} wei"
+        ));
+    }
+    let tx = tx.with_gas_limit(gas_limit).with_value(balance - max_fee);
+    let pending = l2_wallet
+        .send_transaction(tx)
+        .await
+        .map_err(|e| format!("failed sending sweep tx: {e}"))?;
+
+    println!("{}", pending.tx_hash());
+    Ok(())
+}
+
+fn mnemonic() -> Result<(), String> {
+    println!("{}", seed::mnemonic(&load_seed()?));
+    Ok(())
+}
+
+fn import_mnemonic() -> Result<(), String> {
+    let words = if io::stdin().is_terminal() {
+        rpassword::prompt_password("Mnemonic: ")
+    } else {
+        io::read_to_string(io::stdin())
+    }
+    .map_err(|e| format!("failed reading mnemonic: {e}"))?;
+    let mnemonic =
+        Mnemonic::parse_normalized(words.trim()).map_err(|e| format!("invalid mnemonic: {e}"))?;
+    let seed = Seed::try_from(mnemonic.to_entropy())
+        .map_err(|_| "only 24 word mnemonics are supported".to_owned())?;
+    seed::import(&seed).map_err(|e| format!("failed saving seed: {e}"))?;
+    eprintln!("seed imported");
+    Ok(())
+}
+
+async fn migrate_derivation() -> Result<(), String> {
+    if SETTINGS.derivation != Derivation::Legacy {
+        return Err("migrating needs derivation = \"legacy\" in the config".to_owned());
+    }
+    let seed = load_seed()?;
+    let (external_desc, internal_desc) = Derivation::Bip39.descriptors(SETTINGS.network, &seed);
+    let new_wallet = Wallet::create(external_desc, internal_desc)
+        .network(SETTINGS.network)
+        .create_wallet_no_persist()
+        .expect("valid descriptors");
+    let address = new_wallet.peek_address(KeychainKind::External, 0).address;
+
+    let txid = drain_l1(&seed, address.clone()).await?;
+    println!("{txid}");
+    eprintln!("swept the legacy wallet to {address}, set derivation = \"bip39\" to use it");
+    Ok(())
+}
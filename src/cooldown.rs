@@ -0,0 +1,184 @@
+//! Long-lived per-IP and per-address claim cooldowns.
+//!
+//! Cooldowns are stored in the wallet's sqlite database so that they survive
+//! restarts of the faucet.
+
+use std::{fmt, time::Duration};
+
+use bdk_wallet::rusqlite::{self, params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::{
+    db::{self, now_secs},
+    ip_bucket::IpBucket,
+    Chain,
+};
+
+/// Something that a cooldown can be applied to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CooldownKey {
+    Ip(IpBucket),
+    Address(String),
+}
+
+impl fmt::Display for CooldownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CooldownKey::Ip(ip) => write!(f, "ip:{ip}"),
+            CooldownKey::Address(address) => write!(f, "address:{address}"),
+        }
+    }
+}
+
+/// Creates the cooldown table if it doesn't exist yet and clears out
+/// expired cooldowns.
+pub fn init() -> rusqlite::Result<()> {
+    db::with(|db| init_in(db))
+}
+
+fn init_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_cooldowns (
+            key TEXT NOT NULL,
+            chain TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            PRIMARY KEY (key, chain)
+        ) WITHOUT ROWID;
+        CREATE INDEX IF NOT EXISTS faucet_cooldowns_expires_at
+            ON faucet_cooldowns (expires_at);",
+    )?;
+    db.execute(
+        "DELETE FROM faucet_cooldowns WHERE expires_at <= ?1",
+        [now_secs()],
+    )?;
+    Ok(())
+}
+
+/// Returns how long is left on the longest running cooldown of any of the
+/// `keys`, or `None` if none of them are cooling down.
+pub fn remaining(chain: Chain, keys: &[CooldownKey]) -> rusqlite::Result<Option<Duration>> {
+    db::with(|db| remaining_in(db, chain, keys))
+}
+
+/// Starts a cooldown of length `duration` for all the `keys`.
+///
+/// This is atomic with respect to other calls, so if any key is already
+/// cooling down, no cooldowns are started and the remaining time is returned
+/// instead.
+pub fn start(
+    chain: Chain,
+    keys: &[CooldownKey],
+    duration: Duration,
+) -> rusqlite::Result<Option<Duration>> {
+    db::with(|db| start_in(db, chain, keys, duration))
+}
+
+fn start_in(
+    db: &mut Connection,
+    chain: Chain,
+    keys: &[CooldownKey],
+    duration: Duration,
+) -> rusqlite::Result<Option<Duration>> {
+    let db_tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    if let Some(remaining) = remaining_in(&db_tx, chain, keys)? {
+        return Ok(Some(remaining));
+    }
+
+    let expires_at = now_secs().saturating_add(duration.as_secs());
+    for key in keys {
+        db_tx.execute(
+            "INSERT INTO faucet_cooldowns (key, chain, expires_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (key, chain) DO UPDATE SET expires_at = excluded.expires_at",
+            params![key.to_string(), chain.as_str(), expires_at],
+        )?;
+    }
+    db_tx.commit()?;
+    Ok(None)
+}
+
+fn remaining_in(
+    db: &Connection,
+    chain: Chain,
+    keys: &[CooldownKey],
+) -> rusqlite::Result<Option<Duration>> {
+    let now = now_secs();
+    let mut stmt = db.prepare_cached(
+        "SELECT expires_at FROM faucet_cooldowns WHERE key = ?1 AND chain = ?2 AND expires_at > ?3",
+    )?;
+    let mut longest = None;
+    for key in keys {
+        let expires_at: Option<u64> = stmt
+            .query_row(params![key.to_string(), chain.as_str(), now], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if let Some(expires_at) = expires_at {
+            let remaining = Duration::from_secs(expires_at - now);
+            longest = longest.max(Some(remaining));
+        }
+    }
+    Ok(longest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn ipv4(a: u8, b: u8, c: u8, d: u8) -> IpBucket {
+        IpBucket::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), 56)
+    }
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    fn db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_cooldown_blocks_all_keys() {
+        let mut db = db();
+        let ip = CooldownKey::Ip(ipv4(1, 2, 3, 4));
+        let other_ip = CooldownKey::Ip(ipv4(5, 6, 7, 8));
+        let address = CooldownKey::Address("tb1qexample".to_owned());
+
+        let keys = [ip, address];
+        assert_eq!(remaining_in(&db, Chain::L1, &keys).unwrap(), None);
+        assert_eq!(start_in(&mut db, Chain::L1, &keys, DAY).unwrap(), None);
+
+        // both the IP and the address are cooling down, independently
+        for key in &keys {
+            let remaining = remaining_in(&db, Chain::L1, std::slice::from_ref(key))
+                .unwrap()
+                .unwrap();
+            assert!(remaining <= DAY && remaining > DAY - Duration::from_secs(5));
+        }
+
+        // a new claim from another IP to the same address is rejected
+        let [ip, address] = keys;
+        let new_keys = [other_ip, address];
+        assert!(start_in(&mut db, Chain::L1, &new_keys, DAY)
+            .unwrap()
+            .is_some());
+        assert_eq!(remaining_in(&db, Chain::L1, &new_keys[..1]).unwrap(), None);
+
+        // cooldowns are per chain
+        assert_eq!(remaining_in(&db, Chain::L2, &[ip]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_expired_cooldown() {
+        let mut db = db();
+        let keys = [CooldownKey::Ip(ipv4(1, 2, 3, 4))];
+
+        assert_eq!(
+            start_in(&mut db, Chain::L2, &keys, Duration::ZERO).unwrap(),
+            None
+        );
+        assert_eq!(remaining_in(&db, Chain::L2, &keys).unwrap(), None);
+        assert_eq!(start_in(&mut db, Chain::L2, &keys, DAY).unwrap(), None);
+    }
+}
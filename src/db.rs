@@ -0,0 +1,37 @@
+//! Helpers shared by the faucet's own tables in the wallet's sqlite database.
+//!
+//! Each module owning a table keeps its queries in functions taking a
+//! [`Connection`], so they can be tested against an in-memory database, and
+//! runs them on this thread's connection with [`with`].
+
+use std::{
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bdk_wallet::rusqlite::{self, types::Type, Connection};
+
+use crate::l1::Persister;
+
+/// Runs `f` with this thread's connection to the sqlite db.
+///
+/// The connection is borrowed mutably for the duration of `f`, so `f` must
+/// not call [`with`] itself.
+pub fn with<T>(f: impl FnOnce(&mut Connection) -> T) -> T {
+    let db = Persister::db();
+    let mut db = db.borrow_mut();
+    f(&mut db)
+}
+
+/// Error for a value read from the db that doesn't parse.
+pub fn bad_data(e: impl Into<Box<dyn Error + Send + Sync>>) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, e.into())
+}
+
+/// Current unix time, the way timestamps are stored in the db.
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_secs()
+}
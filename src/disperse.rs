@@ -0,0 +1,167 @@
+//! The disperse contract used to batch L2 payouts.
+//!
+//! The contract is hand-assembled by `utils/disperse_asm.py`, which documents
+//! how it works. CI checks that [`CREATION_CODE`] matches the script's
+//! output, and the tests run it in an EVM.
+//!
+//! It pays out a list of `(recipient, value)` pairs, each packed into a
+//! single word, and emits a [`Disperse::Payout`] log for each of them, in
+//! order, telling whether the transfer succeeded. A failed transfer doesn't
+//! revert the rest of the batch, its value is refunded to the caller instead.
+
+use alloy::{
+    primitives::{hex, Address, U256},
+    sol,
+};
+
+sol! {
+    contract Disperse {
+        event Payout(address indexed recipient, uint256 value, bool success);
+
+        function disperseEther(uint256[] payouts) external payable;
+    }
+}
+
+/// Creation bytecode of the disperse contract, as printed by
+/// `utils/disperse_asm.py`.
+pub const CREATION_CODE: &[u8] = &hex!(
+    "6100918061000d6000396000f360003560e01c630e82dc381461001457600080fd5b6004356004018035906020"
+    "019060051b81015b808210156100855781358060601c906bffffffffffffffffffffffff166000808080848661"
+    "c350f16020526000527f85d302949c5f5ba2bc82ae37e5483c096d2454c26c5526bd2252232cfac0fa91604060"
+    "00a29060200190610027565b600080808047335af15000"
+);
+
+/// Packs a payout into a single word for [`Disperse::disperseEtherCall`].
+/// Returns `None` if `value` doesn't fit in the 96 bits available to it.
+pub fn pack_payout(recipient: Address, value: U256) -> Option<U256> {
+    if value.bit_len() > 96 {
+        return None;
+    }
+    Some((U256::from_be_slice(recipient.as_slice()) << 96) | value)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        primitives::{Bytes, TxKind},
+        sol_types::{SolCall, SolEvent},
+    };
+    use revm::{
+        bytecode::Bytecode,
+        context::{
+            result::{ExecutionResult, Output},
+            Context, TxEnv,
+        },
+        database::{CacheDB, EmptyDB},
+        state::AccountInfo,
+        DatabaseRef, ExecuteCommitEvm, MainBuilder, MainContext,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_bytecode_in_evm() {
+        let caller = Address::repeat_byte(0xca);
+        let initial_balance = U256::from(1_000_000);
+        // a recipient that rejects every transfer: PUSH0 PUSH0 REVERT
+        let rejecting = Address::repeat_byte(0xee);
+        let mut db = CacheDB::<EmptyDB>::default();
+        db.insert_account_info(caller, AccountInfo::from_balance(initial_balance));
+        db.insert_account_info(
+            rejecting,
+            AccountInfo::from_bytecode(Bytecode::new_raw(Bytes::from_static(&[0x5f, 0x5f, 0xfd]))),
+        );
+        let mut evm = Context::mainnet().with_db(db).build_mainnet();
+
+        let tx = |nonce, kind, value, data: Vec<u8>| TxEnv {
+            caller,
+            gas_limit: 1_000_000,
+            kind,
+            value,
+            data: data.into(),
+            nonce,
+            ..Default::default()
+        };
+        let result = evm
+            .transact_commit(tx(0, TxKind::Create, U256::ZERO, CREATION_CODE.to_vec()))
+            .unwrap();
+        let ExecutionResult::Success {
+            output: Output::Create(_, Some(contract)),
+            ..
+        } = result
+        else {
+            panic!("deployment failed: {result:?}");
+        };
+
+        let payouts = [
+            (Address::repeat_byte(1), U256::from(1000)),
+            (rejecting, U256::from(2000)),
+            (Address::repeat_byte(2), U256::from(3000)),
+        ];
+        let total = payouts.iter().map(|(_, value)| *value).sum::<U256>();
+        let call = Disperse::disperseEtherCall {
+            payouts: payouts
+                .iter()
+                .map(|(recipient, value)| pack_payout(*recipient, *value).unwrap())
+                .collect(),
+        };
+        let result = evm
+            .transact_commit(tx(1, TxKind::Call(contract), total, call.abi_encode()))
+            .unwrap();
+        let ExecutionResult::Success { logs, .. } = result else {
+            panic!("disperse call failed: {result:?}");
+        };
+
+        // one log per payout, in order, telling whether the transfer went
+        // through
+        assert_eq!(logs.len(), payouts.len());
+        for (log, (recipient, value)) in logs.iter().zip(payouts) {
+            assert_eq!(log.address, contract);
+            let log = Disperse::Payout::decode_log(log).unwrap();
+            assert_eq!(log.recipient, recipient);
+            assert_eq!(log.value, value);
+            assert_eq!(log.success, recipient != rejecting);
+        }
+
+        // the rejected transfer is refunded to the caller, and the contract
+        // keeps nothing
+        let balance = |address| {
+            evm.ctx
+                .journaled_state
+                .database
+                .basic_ref(address)
+                .unwrap()
+                .map(|info| info.balance)
+                .unwrap_or_default()
+        };
+        assert_eq!(balance(Address::repeat_byte(1)), U256::from(1000));
+        assert_eq!(balance(Address::repeat_byte(2)), U256::from(3000));
+        assert_eq!(balance(rejecting), U256::ZERO);
+        assert_eq!(balance(contract), U256::ZERO);
+        assert_eq!(balance(caller), initial_balance - U256::from(4000));
+
+        // calls to anything but disperseEther revert
+        let result = evm
+            .transact_commit(tx(2, TxKind::Call(contract), U256::ZERO, vec![0; 4]))
+            .unwrap();
+        assert!(matches!(result, ExecutionResult::Revert { .. }));
+    }
+
+    #[test]
+    fn test_bytecode_matches_interface() {
+        let contains = |needle: &[u8]| CREATION_CODE.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(&Disperse::disperseEtherCall::SELECTOR));
+        assert!(contains(Disperse::Payout::SIGNATURE_HASH.as_slice()));
+    }
+
+    #[test]
+    fn test_pack_payout() {
+        let recipient = Address::repeat_byte(0xab);
+        let packed = pack_payout(recipient, U256::from(5)).unwrap();
+        let bytes = packed.to_be_bytes::<32>();
+        assert_eq!(&bytes[..20], recipient.as_slice());
+        assert_eq!(U256::from_be_slice(&bytes[20..]), U256::from(5));
+
+        assert!(pack_payout(recipient, U256::MAX).is_none());
+    }
+}
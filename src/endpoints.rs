@@ -0,0 +1,383 @@
+//! Failover between several endpoints serving the same chain.
+//!
+//! Endpoints are listed in order of preference. Requests go to the most
+//! preferred endpoint that's healthy. An endpoint that fails is health checked
+//! with exponential backoff until it recovers, at which point requests fail
+//! back to it.
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, timeout};
+use tracing::{info, warn};
+
+/// How long a health check can take before it counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointsConfig {
+    /// How often healthy endpoints are health checked.
+    ///
+    /// Defaults to `30` seconds.
+    pub check_interval: Duration,
+
+    /// How long to wait before checking an endpoint again after it fails.
+    /// Doubles with every failed check.
+    ///
+    /// Defaults to `5` seconds.
+    pub initial_backoff: Duration,
+
+    /// Longest time to wait between checks of a failing endpoint.
+    ///
+    /// Defaults to `300` seconds.
+    pub max_backoff: Duration,
+}
+
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(30),
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl EndpointsConfig {
+    /// How long to wait before checking an endpoint that has failed
+    /// `failures` times in a row.
+    fn backoff(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+/// Errors returned by endpoints.
+pub trait EndpointError: fmt::Display {
+    /// Whether the error means the endpoint is down or misbehaving, rather
+    /// than the request being rejected.
+    fn is_endpoint_failure(&self) -> bool;
+}
+
+impl<E: EndpointError> EndpointError for Box<E> {
+    fn is_endpoint_failure(&self) -> bool {
+        (**self).is_endpoint_failure()
+    }
+}
+
+#[derive(Debug)]
+struct Health {
+    healthy: bool,
+    /// Failed requests or checks since the endpoint was last healthy
+    failures: u32,
+    last_error: Option<String>,
+    next_check: Instant,
+}
+
+#[derive(Debug)]
+struct Endpoint<T> {
+    url: String,
+    client: T,
+    health: Mutex<Health>,
+}
+
+/// The health of an endpoint, as reported by the API.
+#[derive(Debug, Serialize)]
+pub struct EndpointStatus {
+    url: String,
+    healthy: bool,
+    /// Whether requests are currently going to this endpoint
+    active: bool,
+    consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Endpoints<T> {
+    /// What the endpoints are for, used in logs
+    name: &'static str,
+    endpoints: Vec<Endpoint<T>>,
+    /// Index of the endpoint requests last went to
+    active: AtomicUsize,
+    cfg: EndpointsConfig,
+}
+
+impl<T: Clone> Endpoints<T> {
+    /// Creates a new set of endpoints, most preferred first.
+    ///
+    /// # Panics
+    ///
+    /// If `endpoints` is empty.
+    pub fn new(
+        name: &'static str,
+        endpoints: impl IntoIterator<Item = (String, T)>,
+        cfg: EndpointsConfig,
+    ) -> Self {
+        let now = Instant::now();
+        let endpoints = endpoints
+            .into_iter()
+            .map(|(url, client)| Endpoint {
+                url,
+                client,
+                health: Mutex::new(Health {
+                    healthy: true,
+                    failures: 0,
+                    last_error: None,
+                    next_check: now + cfg.check_interval,
+                }),
+            })
+            .collect::<Vec<_>>();
+        assert!(!endpoints.is_empty(), "at least one {name} endpoint");
+        Self {
+            name,
+            endpoints,
+            active: AtomicUsize::new(0),
+            cfg,
+        }
+    }
+
+    /// The endpoint requests should go to: the most preferred healthy one, or
+    /// the last one used if none of them are healthy.
+    pub fn active(&self) -> &T {
+        let healthy = self.healthy().next();
+        let idx = healthy.unwrap_or_else(|| self.active.load(Ordering::Relaxed));
+        self.set_active(idx);
+        &self.endpoints[idx].client
+    }
+
+    /// Makes a request to the most preferred healthy endpoint, failing over
+    /// to the next one while the request fails because of the endpoint. If
+    /// none are healthy, all of them are tried.
+    pub async fn call<R, E, Fut>(&self, f: impl Fn(T) -> Fut) -> Result<R, E>
+    where
+        E: EndpointError,
+        Fut: Future<Output = Result<R, E>>,
+    {
+        let mut candidates = self.healthy().collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = (0..self.endpoints.len()).collect();
+        }
+
+        let mut res = None;
+        for idx in candidates {
+            self.set_active(idx);
+            match f(self.endpoints[idx].client.clone()).await {
+                Ok(r) => {
+                    self.record_success(idx);
+                    return Ok(r);
+                }
+                Err(e) if e.is_endpoint_failure() => {
+                    self.record_failure(idx, &e);
+                    res = Some(Err(e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        res.expect("at least one endpoint")
+    }
+
+    /// Records an error from a request made to the [active](Self::active)
+    /// endpoint.
+    pub fn report(&self, e: &impl EndpointError) {
+        if e.is_endpoint_failure() {
+            self.record_failure(self.active.load(Ordering::Relaxed), e);
+        }
+    }
+
+    pub fn status(&self) -> Vec<EndpointStatus> {
+        let active = self.active.load(Ordering::Relaxed);
+        self.endpoints
+            .iter()
+            .enumerate()
+            .map(|(idx, endpoint)| {
+                let health = endpoint.health.lock();
+                EndpointStatus {
+                    url: endpoint.url.clone(),
+                    healthy: health.healthy,
+                    active: idx == active,
+                    consecutive_failures: health.failures,
+                    last_error: health.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Spawns a tokio task that checks each endpoint with `check` when it's
+    /// due, marking it healthy or failed.
+    pub fn spawn_health_checks<Fut>(self: &Arc<Self>, check: impl Fn(T) -> Fut + Send + 'static)
+    where
+        T: Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send,
+    {
+        let endpoints = self.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                for (idx, endpoint) in endpoints.endpoints.iter().enumerate() {
+                    if endpoint.health.lock().next_check > Instant::now() {
+                        continue;
+                    }
+                    match timeout(CHECK_TIMEOUT, check(endpoint.client.clone())).await {
+                        Ok(Ok(())) => endpoints.record_success(idx),
+                        Ok(Err(e)) => endpoints.record_failure(idx, &e),
+                        Err(_) => endpoints.record_failure(idx, &"health check timed out"),
+                    }
+                }
+            }
+        });
+    }
+
+    /// Indices of the healthy endpoints, most preferred first.
+    fn healthy(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.endpoints.len()).filter(|idx| self.endpoints[*idx].health.lock().healthy)
+    }
+
+    fn set_active(&self, idx: usize) {
+        let prev = self.active.swap(idx, Ordering::Relaxed);
+        if prev != idx {
+            info!(
+                "switched {} endpoint from {} to {}",
+                self.name, self.endpoints[prev].url, self.endpoints[idx].url
+            );
+        }
+    }
+
+    fn record_success(&self, idx: usize) {
+        let endpoint = &self.endpoints[idx];
+        let mut health = endpoint.health.lock();
+        if !health.healthy {
+            info!("{} endpoint {} is back up", self.name, endpoint.url);
+        }
+        health.healthy = true;
+        health.failures = 0;
+        health.next_check = Instant::now() + self.cfg.check_interval;
+    }
+
+    fn record_failure(&self, idx: usize, e: &impl fmt::Display) {
+        let endpoint = &self.endpoints[idx];
+        let mut health = endpoint.health.lock();
+        health.failures += 1;
+        let backoff = self.cfg.backoff(health.failures);
+        if health.healthy {
+            warn!(
+                "{} endpoint {} is down, checking again in {backoff:?}: {e}",
+                self.name, endpoint.url
+            );
+        } else {
+            warn!(
+                "{} endpoint {} is still down after {} checks, checking again in {backoff:?}: {e}",
+                self.name, endpoint.url, health.failures
+            );
+        }
+        health.healthy = false;
+        health.last_error = Some(e.to_string());
+        health.next_check = Instant::now() + backoff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake endpoint, telling whether it's up
+    type Up = Arc<std::sync::atomic::AtomicBool>;
+
+    #[derive(Debug)]
+    enum FakeError {
+        Down,
+        Rejected,
+    }
+
+    impl fmt::Display for FakeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{self:?}")
+        }
+    }
+
+    impl EndpointError for FakeError {
+        fn is_endpoint_failure(&self) -> bool {
+            matches!(self, FakeError::Down)
+        }
+    }
+
+    async fn request(up: Up) -> Result<(), FakeError> {
+        if up.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(FakeError::Down)
+        }
+    }
+
+    fn endpoints(n: usize) -> (Endpoints<Up>, Vec<Up>) {
+        let ups = (0..n).map(|_| Up::new(true.into())).collect::<Vec<_>>();
+        let endpoints = Endpoints::new(
+            "test",
+            ups.iter()
+                .enumerate()
+                .map(|(i, up)| (format!("http://{i}"), up.clone())),
+            EndpointsConfig::default(),
+        );
+        (endpoints, ups)
+    }
+
+    #[test]
+    fn test_backoff() {
+        let cfg = EndpointsConfig {
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(cfg.backoff(1), Duration::from_secs(5));
+        assert_eq!(cfg.backoff(2), Duration::from_secs(10));
+        assert_eq!(cfg.backoff(4), Duration::from_secs(40));
+        assert_eq!(cfg.backoff(5), Duration::from_secs(60));
+        assert_eq!(cfg.backoff(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_failback() {
+        let (endpoints, ups) = endpoints(3);
+
+        ups[0].store(false, Ordering::Relaxed);
+        endpoints.call(request).await.unwrap();
+        assert!(Arc::ptr_eq(endpoints.active(), &ups[1]));
+        let status = endpoints.status();
+        assert!(!status[0].healthy && status[1].healthy && status[1].active);
+
+        // all down, every endpoint is tried
+        ups[1].store(false, Ordering::Relaxed);
+        ups[2].store(false, Ordering::Relaxed);
+        assert!(endpoints.call(request).await.is_err());
+        assert!(endpoints.status().iter().all(|s| !s.healthy));
+
+        // the first one passing a health check takes over again
+        ups[0].store(true, Ordering::Relaxed);
+        endpoints.record_success(0);
+        assert!(Arc::ptr_eq(endpoints.active(), &ups[0]));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_requests_dont_fail_over() {
+        let (endpoints, _) = endpoints(2);
+        let res = endpoints
+            .call(|_| async { Err::<(), _>(FakeError::Rejected) })
+            .await;
+        assert!(matches!(res, Err(FakeError::Rejected)));
+        assert!(endpoints.status().iter().all(|s| s.healthy));
+        assert!(endpoints.status()[0].active);
+    }
+}
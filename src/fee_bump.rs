@@ -0,0 +1,410 @@
+//! Replace-by-fee bumping of stuck L1 batch transactions.
+//!
+//! Only batches without unconfirmed descendants are bumped, since replacing a
+//! batch would invalidate any later batch spending its change. When batches
+//! are chained, bumping the last one pays for the whole chain (CPFP), so its
+//! new fee covers its unconfirmed ancestors too.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bdk_wallet::{
+    bitcoin::{Amount, FeeRate, Transaction, Txid, Weight},
+    chain::ChainPosition,
+};
+use parking_lot::{Mutex, RwLock};
+use tokio::task::spawn_blocking;
+use tracing::{error, info, warn};
+
+use crate::{
+    batcher::{apply_unconfirmed_tx, broadcast, BatcherConfig, BroadcastStatus},
+    claims::{ClaimId, ClaimState, ClaimTracker},
+    l1::{batch_fee_rate, L1Wallet},
+    payout_store,
+};
+
+/// Batch transactions that have been broadcast, by the txid of their first
+/// version, along with the claims they pay out.
+#[derive(Debug, Default)]
+pub struct SentBatches(Mutex<HashMap<Txid, SentBatch>>);
+
+/// A batch that went out and hasn't confirmed yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentBatch {
+    pub claims: Vec<ClaimId>,
+    /// Fee paid by the first version of the batch, which bumps are capped
+    /// relative to
+    pub original_fee: Amount,
+    /// Every version of the batch broadcast so far, oldest first. Any of them
+    /// can be the one that ends up confirming.
+    pub txids: Vec<Txid>,
+}
+
+impl SentBatch {
+    /// The version of the batch that confirmed and its height, if any did.
+    fn confirmed(&self, height_of: impl Fn(Txid) -> Option<u32>) -> Option<(Txid, u32)> {
+        self.txids
+            .iter()
+            .find_map(|txid| height_of(*txid).map(|height| (*txid, height)))
+    }
+}
+
+impl SentBatches {
+    pub fn insert(&self, batch: SentBatch) {
+        self.0.lock().insert(batch.txids[0], batch);
+    }
+
+    /// Finds the version of the batch `txid` belongs to that confirmed, and
+    /// its height, given the height each transaction confirmed at.
+    pub fn confirmed(
+        &self,
+        txid: Txid,
+        height_of: impl Fn(Txid) -> Option<u32>,
+    ) -> Option<(Txid, u32)> {
+        let sent = self.0.lock();
+        match sent.values().find(|batch| batch.txids.contains(&txid)) {
+            Some(batch) => batch.confirmed(height_of),
+            None => height_of(txid).map(|height| (txid, height)),
+        }
+    }
+}
+
+/// Height the transaction confirmed at in the wallet, if it has.
+pub(crate) fn confirmed_height(l1w: &L1Wallet, txid: Txid) -> Option<u32> {
+    l1w.get_tx(txid).and_then(|tx| match tx.chain_position {
+        ChainPosition::Confirmed { anchor, .. } => Some(anchor.block_id.height),
+        ChainPosition::Unconfirmed { .. } => None,
+    })
+}
+
+/// Fees and weights of an unconfirmed batch and its unconfirmed ancestors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Package {
+    tip_fee: Amount,
+    tip_weight: Weight,
+    ancestors_fee: Amount,
+    ancestors_weight: Weight,
+}
+
+impl Package {
+    fn fee_rate(&self) -> FeeRate {
+        let fee = self.tip_fee + self.ancestors_fee;
+        fee / (self.tip_weight + self.ancestors_weight)
+    }
+}
+
+/// Settings for when and how far to bump batches.
+#[derive(Debug, Clone, Copy)]
+struct BumpPolicy {
+    target: FeeRate,
+    stale: bool,
+    gap_percent: u64,
+    max_extra_fee: Amount,
+}
+
+/// Works out the fee to replace the tip of `package` with, or `None` if it
+/// doesn't need to be or can't be bumped.
+///
+/// The package is bumped if it's `stale`, or if the `target` fee rate is more
+/// than `gap_percent` above what it pays. It's then raised to at least
+/// `gap_percent` above its current fee rate, so repeated bumps make progress,
+/// while never paying more than `max_extra_fee` over `original_fee`.
+fn replacement_fee(package: &Package, policy: BumpPolicy, original_fee: Amount) -> Option<Amount> {
+    let current = package.fee_rate();
+    let raised =
+        FeeRate::from_sat_per_kwu(current.to_sat_per_kwu() * (100 + policy.gap_percent) / 100);
+    if !policy.stale && policy.target <= raised {
+        return None;
+    }
+
+    let new_rate = policy.target.max(raised);
+    let package_weight = package.tip_weight + package.ancestors_weight;
+    let package_fee = new_rate.fee_wu(package_weight)?;
+    // replacements must pay at least 1 sat/vB more than what they replace
+    let min_fee =
+        package.tip_fee + FeeRate::from_sat_per_vb_unchecked(1).fee_wu(package.tip_weight)?;
+    let new_fee = package_fee
+        .checked_sub(package.ancestors_fee)
+        .unwrap_or_default()
+        .max(min_fee)
+        .min(original_fee + policy.max_extra_fee);
+    (new_fee >= min_fee).then_some(new_fee)
+}
+
+/// Replaces unconfirmed batches that have been stuck for too long, or pay
+/// too little compared to the current fee rate.
+///
+/// Batches that have confirmed are settled first, with whichever of their
+/// versions made it in. Only batches the faucet sent are bumped, never other
+/// transactions spending from the wallet.
+pub(crate) async fn bump_stuck_batches(
+    l1_wallet: &Arc<RwLock<L1Wallet>>,
+    claims: &ClaimTracker,
+    sent: &SentBatches,
+    cfg: &BatcherConfig,
+) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_secs();
+    // in economy mode, batches that are keeping up with the economy target
+    // don't need bumping
+    let target = batch_fee_rate(false);
+
+    let mut settled = Vec::new();
+    let bumps = {
+        let l1w = l1_wallet.read();
+        let mut sent = sent.0.lock();
+
+        // unconfirmed transactions sent by the faucet, along with when they
+        // were first seen
+        let unconfirmed = l1w
+            .transactions()
+            .filter_map(|tx| match tx.chain_position {
+                ChainPosition::Unconfirmed { first_seen, .. } => {
+                    Some((tx.tx_node.txid, (tx.tx_node.tx.clone(), first_seen)))
+                }
+                ChainPosition::Confirmed { .. } => None,
+            })
+            .filter(|(_, (tx, _))| l1w.sent_and_received(tx).0 > Amount::ZERO)
+            .collect::<HashMap<_, _>>();
+        // settle batches that have confirmed, and forget ones none of whose
+        // versions are in the wallet anymore
+        sent.retain(|batch_txid, batch| {
+            if let Some((txid, height)) = batch.confirmed(|txid| confirmed_height(&l1w, txid)) {
+                claims.set_state_many(
+                    &batch.claims,
+                    ClaimState::Confirmed {
+                        txid: txid.into(),
+                        height: height.into(),
+                    },
+                );
+            } else if batch
+                .txids
+                .iter()
+                .any(|txid| unconfirmed.contains_key(txid))
+            {
+                return true;
+            } else {
+                warn!("batch {batch_txid} is no longer in the wallet, forgetting it");
+            }
+            settled.push(*batch_txid);
+            false
+        });
+
+        let has_descendants = unconfirmed
+            .values()
+            .flat_map(|(tx, _)| tx.input.iter().map(|input| input.previous_output.txid))
+            .collect::<HashSet<_>>();
+
+        let mut bumps = Vec::new();
+        for (batch_txid, batch) in sent.iter() {
+            // versions that failed to broadcast are never in the wallet
+            let Some((txid, (tx, first_seen))) = batch
+                .txids
+                .iter()
+                .rev()
+                .find_map(|txid| unconfirmed.get_key_value(txid))
+            else {
+                continue;
+            };
+            if has_descendants.contains(txid) {
+                continue;
+            }
+            let Ok(tip_fee) = l1w.calculate_fee(tx) else {
+                continue;
+            };
+
+            let mut ancestors_fee = Amount::ZERO;
+            let mut ancestors_weight = Weight::ZERO;
+            let mut seen = HashSet::new();
+            let mut to_visit = vec![tx.clone()];
+            while let Some(tx) = to_visit.pop() {
+                for input in &tx.input {
+                    let parent = input.previous_output.txid;
+                    let Some((parent_tx, _)) = unconfirmed.get(&parent) else {
+                        continue;
+                    };
+                    if !seen.insert(parent) {
+                        continue;
+                    }
+                    ancestors_fee += l1w.calculate_fee(parent_tx).unwrap_or_default();
+                    ancestors_weight += parent_tx.weight();
+                    to_visit.push(parent_tx.clone());
+                }
+            }
+
+            let package = Package {
+                tip_fee,
+                tip_weight: tx.weight(),
+                ancestors_fee,
+                ancestors_weight,
+            };
+            let policy = BumpPolicy {
+                target,
+                stale: first_seen.is_some_and(|first_seen| {
+                    now.saturating_sub(first_seen) >= cfg.rbf_after.as_secs()
+                }),
+                gap_percent: cfg.rbf_fee_rate_gap_percent,
+                max_extra_fee: cfg.rbf_max_fee,
+            };
+            let original_fee = batch.original_fee;
+            if let Some(new_fee) = replacement_fee(&package, policy, original_fee) {
+                bumps.push((*batch_txid, *txid, tip_fee, new_fee));
+            } else if policy.stale && tip_fee >= original_fee + policy.max_extra_fee {
+                warn!("batch {txid} is stuck but has reached the fee bump cap");
+            }
+        }
+        bumps
+    };
+    if !settled.is_empty() {
+        if let Err(e) = payout_store::remove_sent(&settled) {
+            error!("failed removing settled batches from store: {e:?}");
+        }
+    }
+
+    for (batch_txid, txid, old_fee, new_fee) in bumps {
+        let tx = match sign_replacement(&mut l1_wallet.write(), txid, new_fee) {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!("failed building replacement for {txid}: {e}");
+                continue;
+            }
+        };
+        let new_txid = tx.compute_txid();
+        // stored before it's broadcast, so that if it's the version that
+        // confirms, its claims are still settled after a restart
+        if let Err(e) = payout_store::insert_replacement(batch_txid, new_txid) {
+            error!("failed recording replacement {new_txid} for {txid}: {e:?}");
+            continue;
+        }
+        // replacements conflict with the batch they replace, so they can't
+        // pay it out twice
+        if broadcast(&tx).await != BroadcastStatus::Sent {
+            error!("failed broadcasting replacement {new_txid} for {txid}");
+            continue;
+        }
+        info!("replaced batch {txid} with {new_txid}, raising its fee from {old_fee} to {new_fee}");
+
+        if let Some(batch) = sent.0.lock().get_mut(&batch_txid) {
+            batch.txids.push(new_txid);
+            claims.set_state_many(
+                &batch.claims,
+                ClaimState::Broadcast {
+                    txid: new_txid.into(),
+                },
+            );
+        }
+        let l1_wallet = l1_wallet.clone();
+        spawn_blocking(move || apply_unconfirmed_tx(&l1_wallet, tx))
+            .await
+            .expect("successful blocking update");
+    }
+}
+
+fn sign_replacement(l1w: &mut L1Wallet, txid: Txid, fee: Amount) -> Result<Transaction, String> {
+    let mut builder = l1w.build_fee_bump(txid).map_err(|e| format!("{e:?}"))?;
+    builder.fee_absolute(fee);
+    let mut psbt = builder.finish().map_err(|e| format!("{e:?}"))?;
+    l1w.sign(&mut psbt, Default::default())
+        .expect("signing should not fail");
+    Ok(psbt.extract_tx().expect("fully signed psbt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk_wallet::bitcoin::hashes::Hash;
+
+    use super::*;
+
+    fn package(tip_fee: u64, ancestors_fee: u64) -> Package {
+        Package {
+            tip_fee: Amount::from_sat(tip_fee),
+            tip_weight: Weight::from_vb_unchecked(100),
+            ancestors_fee: Amount::from_sat(ancestors_fee),
+            ancestors_weight: Weight::from_vb_unchecked(if ancestors_fee > 0 { 100 } else { 0 }),
+        }
+    }
+
+    fn policy(target_sat_vb: u64, stale: bool) -> BumpPolicy {
+        BumpPolicy {
+            target: FeeRate::from_sat_per_vb_unchecked(target_sat_vb),
+            stale,
+            gap_percent: 50,
+            max_extra_fee: Amount::from_sat(10_000),
+        }
+    }
+
+    #[test]
+    fn test_confirmed_version() {
+        let [first, bumped, other] = [1, 2, 3].map(|i| Txid::from_byte_array([i; 32]));
+        let sent = SentBatches::default();
+        sent.insert(SentBatch {
+            claims: vec![[1; 16]],
+            original_fee: Amount::from_sat(200),
+            txids: vec![first, bumped],
+        });
+
+        // the original confirmed even though it was replaced
+        let height_of = |txid| (txid == first).then_some(100);
+        assert_eq!(sent.confirmed(bumped, height_of), Some((first, 100)));
+        assert_eq!(sent.confirmed(first, height_of), Some((first, 100)));
+
+        let height_of = |txid| (txid == bumped).then_some(101);
+        assert_eq!(sent.confirmed(first, height_of), Some((bumped, 101)));
+        assert_eq!(sent.confirmed(bumped, |_| None), None);
+
+        // transactions that aren't tracked are looked up on their own
+        assert_eq!(sent.confirmed(other, |_| Some(102)), Some((other, 102)));
+    }
+
+    #[test]
+    fn test_replacement_fee() {
+        // 2 sat/vB, within the gap of a 3 sat/vB target
+        let pkg = package(200, 0);
+        let original = Amount::from_sat(200);
+        assert_eq!(replacement_fee(&pkg, policy(3, false), original), None);
+        // target well above what it pays
+        assert_eq!(
+            replacement_fee(&pkg, policy(10, false), original),
+            Some(Amount::from_sat(1000))
+        );
+        // stale, so raised by the gap even though the target is low
+        assert_eq!(
+            replacement_fee(&pkg, policy(1, true), original),
+            Some(Amount::from_sat(300))
+        );
+    }
+
+    #[test]
+    fn test_replacement_fee_pays_for_ancestors() {
+        // tip and ancestor both pay 1 sat/vB, so to get the package to
+        // 10 sat/vB the tip has to pay 2000 - 100
+        let pkg = package(100, 100);
+        assert_eq!(
+            replacement_fee(&pkg, policy(10, false), Amount::from_sat(100)),
+            Some(Amount::from_sat(1900))
+        );
+    }
+
+    #[test]
+    fn test_replacement_fee_cap() {
+        let pkg = package(200, 0);
+        let original = Amount::from_sat(200);
+        let capped = BumpPolicy {
+            max_extra_fee: Amount::from_sat(500),
+            ..policy(100, false)
+        };
+        assert_eq!(
+            replacement_fee(&pkg, capped, original),
+            Some(Amount::from_sat(700))
+        );
+
+        // already bumped up to the cap, can't pay the extra 1 sat/vB needed
+        let pkg = package(700, 0);
+        assert_eq!(replacement_fee(&pkg, capped, original), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! Liveness and readiness of the faucet's background tasks and backends, for
+//! the `/healthz` and `/readyz` endpoints.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bdk_wallet::bitcoin::Amount;
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinHandle, time::timeout};
+
+use crate::{l1, settings::SETTINGS, AppState, SATS_TO_WEI};
+
+/// How long the L2 balance check can take before L2 counts as unreachable
+const L2_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// How long since the last successful L1 wallet sync before the faucet
+    /// isn't ready.
+    ///
+    /// Defaults to `300` seconds.
+    pub max_sync_age: Duration,
+
+    /// How long since the last fee rate estimate before the faucet isn't
+    /// ready.
+    ///
+    /// Defaults to `600` seconds.
+    pub max_fee_rate_age: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_sync_age: Duration::from_secs(300),
+            max_fee_rate_age: Duration::from_secs(600),
+        }
+    }
+}
+
+/// The last time something happened, in seconds since the unix epoch.
+#[derive(Debug, Default)]
+pub struct Heartbeat(AtomicU64);
+
+impl Heartbeat {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn beat(&self) {
+        self.0.store(unix_now(), Ordering::Relaxed);
+    }
+
+    /// Time since the last beat, or `None` if there hasn't been one yet.
+    pub fn age(&self) -> Option<Duration> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(Duration::from_secs(unix_now().saturating_sub(last))),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_secs()
+}
+
+/// State of a background task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    NotStarted,
+    Running,
+    Finished,
+}
+
+impl TaskState {
+    pub fn of(handle: Option<&JoinHandle<()>>) -> Self {
+        match handle {
+            None => Self::NotStarted,
+            Some(handle) if handle.is_finished() => Self::Finished,
+            Some(_) => Self::Running,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub l1_sync: AgeCheck,
+    pub fee_rate: FeeRateCheck,
+    pub batcher: BatcherCheck,
+    pub l2_rpc: RpcCheck,
+    pub l1_balance: BalanceCheck,
+    pub l2_balance: BalanceCheck,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgeCheck {
+    pub ok: bool,
+    /// Seconds since it last succeeded, `null` if it never has
+    pub secs_ago: Option<u64>,
+}
+
+impl AgeCheck {
+    fn new(age: Option<Duration>, max_age: Duration) -> Self {
+        Self {
+            ok: age.is_some_and(|age| age <= max_age),
+            secs_ago: age.map(|age| age.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeeRateCheck {
+    #[serde(flatten)]
+    pub age: AgeCheck,
+    /// Whether the fallback fee rate is in use
+    pub fallback: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatcherCheck {
+    pub ok: bool,
+    pub task: TaskState,
+    pub l2_task: TaskState,
+    /// Seconds since the batcher last went around its loop
+    pub heartbeat_secs_ago: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcCheck {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalanceCheck {
+    pub ok: bool,
+    /// Spendable balance in sats, `null` if it couldn't be fetched
+    pub balance: Option<u64>,
+    /// `min_balance` in sats
+    pub min_balance: u64,
+}
+
+impl BalanceCheck {
+    fn new(balance: Option<Amount>, min_balance: Amount) -> Self {
+        Self {
+            ok: balance.is_some_and(|balance| balance >= min_balance),
+            balance: balance.map(Amount::to_sat),
+            min_balance: min_balance.to_sat(),
+        }
+    }
+}
+
+/// Checks everything the faucet needs to be able to pay out claims.
+pub async fn readiness(state: &AppState) -> Readiness {
+    let cfg = &SETTINGS.health;
+
+    let l1_sync = AgeCheck::new(l1::LAST_SYNC.age(), cfg.max_sync_age);
+    let fee_rate = FeeRateCheck {
+        age: AgeCheck::new(l1::LAST_FEE_ESTIMATE.age(), cfg.max_fee_rate_age),
+        fallback: l1::using_fallback_fee_rate(),
+    };
+
+    let task = state.batcher.task_state();
+    let l2_task = state.batcher.l2_task_state();
+    let heartbeat = state.batcher.heartbeat_age();
+    let batcher = BatcherCheck {
+        ok: task == TaskState::Running
+            && l2_task == TaskState::Running
+            && heartbeat.is_some_and(|age| age <= state.batcher.max_heartbeat_age()),
+        task,
+        l2_task,
+        heartbeat_secs_ago: heartbeat.map(|age| age.as_secs()),
+    };
+
+    // fetching the balance doubles as the reachability check
+    let l2_balance = match timeout(
+        L2_CHECK_TIMEOUT,
+        state.l2_wallet.get_default_signer_balance(),
+    )
+    .await
+    {
+        Ok(Ok(wei)) => Ok(Amount::from_sat((wei / SATS_TO_WEI as u128) as u64)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err("timed out".to_owned()),
+    };
+    let l2_rpc = RpcCheck {
+        ok: l2_balance.is_ok(),
+        error: l2_balance.as_ref().err().cloned(),
+    };
+    let l2_balance = BalanceCheck::new(l2_balance.ok(), state.l2.config().min_balance);
+
+    let l1_balance = state.l1_wallet.read().balance().trusted_spendable();
+    let l1_balance = BalanceCheck::new(Some(l1_balance), state.l1.config().min_balance);
+
+    Readiness {
+        ready: l1_sync.ok
+            && fee_rate.age.ok
+            && batcher.ok
+            && l2_rpc.ok
+            && l1_balance.ok
+            && l2_balance.ok,
+        l1_sync,
+        fee_rate,
+        batcher,
+        l2_rpc,
+        l1_balance,
+        l2_balance,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() {
+        let max_age = Duration::from_secs(60);
+        assert!(!AgeCheck::new(None, max_age).ok);
+        assert!(AgeCheck::new(Some(Duration::from_secs(60)), max_age).ok);
+        assert!(!AgeCheck::new(Some(Duration::from_secs(61)), max_age).ok);
+
+        let min_balance = Amount::from_sat(1000);
+        assert!(!BalanceCheck::new(None, min_balance).ok);
+        assert!(BalanceCheck::new(Some(min_balance), min_balance).ok);
+        assert!(!BalanceCheck::new(Some(Amount::from_sat(999)), min_balance).ok);
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let heartbeat = Heartbeat::new();
+        assert_eq!(heartbeat.age(), None);
+        heartbeat.beat();
+        assert!(heartbeat.age().unwrap() < Duration::from_secs(2));
+    }
+}
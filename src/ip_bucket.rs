@@ -0,0 +1,94 @@
+//! Grouping of client IP addresses for rate limiting purposes.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use concurrent_map::Minimum;
+
+/// A group of client IP addresses that the faucet treats as a single client.
+///
+/// IPv4 addresses each get their own bucket. IPv6 addresses are grouped by
+/// their first `ipv6_prefix_len` bits, since a single client is usually
+/// allocated a whole prefix (often a /56 or /64) and can trivially hop
+/// between the addresses in it.
+///
+/// Internally, IPv4 addresses are stored as IPv4-mapped IPv6 addresses so
+/// both address families share one key space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IpBucket(u128);
+
+impl IpBucket {
+    pub fn new(ip: IpAddr, ipv6_prefix_len: u8) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => Self(ip.to_ipv6_mapped().to_bits()),
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(ipv6_prefix_len.min(128)))
+                    .unwrap_or(0);
+                Self(ip.to_bits() & mask)
+            }
+        }
+    }
+
+    /// Raw bits of the bucket, as an IPv6 address.
+    pub fn to_bits(self) -> u128 {
+        self.0
+    }
+
+    pub fn from_bits(bits: u128) -> Self {
+        Self(bits)
+    }
+}
+
+impl Minimum for IpBucket {
+    const MIN: Self = IpBucket(u128::MIN);
+}
+
+impl fmt::Display for IpBucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // IPv4 buckets are shown as plain IPv4 addresses
+        write!(f, "{}", Ipv6Addr::from_bits(self.0).to_canonical())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_ipv4_buckets() {
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let bucket = IpBucket::new(ip, 56);
+        assert_eq!(bucket.to_string(), "1.2.3.4");
+        assert_ne!(
+            bucket,
+            IpBucket::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 5)), 56)
+        );
+        // IPv4-mapped IPv6 addresses share the IPv4 address's bucket
+        let mapped = IpAddr::V6(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped());
+        assert_eq!(bucket, IpBucket::new(mapped, 56));
+    }
+
+    #[test]
+    fn test_ipv6_buckets() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let bucket = IpBucket::new(ip("2001:db8:aaaa:bb01::1"), 56);
+        assert_eq!(bucket.to_string(), "2001:db8:aaaa:bb00::");
+        assert_eq!(bucket, IpBucket::new(ip("2001:db8:aaaa:bbff:1:2:3:4"), 56));
+        assert_ne!(bucket, IpBucket::new(ip("2001:db8:aaaa:bc00::1"), 56));
+
+        assert_ne!(
+            IpBucket::new(ip("2001:db8::1"), 128),
+            IpBucket::new(ip("2001:db8::2"), 128)
+        );
+        assert_eq!(
+            IpBucket::new(ip("2001:db8::1"), 0),
+            IpBucket::new(ip("fe80::1"), 0)
+        );
+    }
+}
@@ -1,72 +1,281 @@
 use std::{
     cell::RefCell,
+    collections::HashMap,
     io,
     ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
     rc::Rc,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use bdk_esplora::{
-    esplora_client::{self, AsyncClient},
-    EsploraAsyncExt,
-};
 use bdk_wallet::{
     bitcoin::{bip32::Xpriv, FeeRate, Network},
     rusqlite::{self, Connection},
     ChangeSet, KeychainKind, PersistedWallet, Wallet, WalletPersister,
 };
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use tokio::time::sleep;
 use tracing::{error, info, warn};
 
-use crate::{seed::Seed, SETTINGS};
+use crate::{
+    chain_source::CHAIN_SOURCE,
+    health::Heartbeat,
+    seed::{self, Seed},
+    SETTINGS,
+};
 
-/// Live updating fee rate in sat/kwu
+/// Live updating fee rate in sat/kwu for the confirmation target
 static FEE_RATE: AtomicU64 = AtomicU64::new(250);
 
-/// Spawns a tokio task that updates the FEE_RATE every 20 seconds
+/// Live updating fee rate in sat/kwu for the economy confirmation target
+static ECONOMY_FEE_RATE: AtomicU64 = AtomicU64::new(250);
+
+/// Whether the fee rates are the configured fallback rather than estimates
+static USING_FALLBACK: AtomicBool = AtomicBool::new(true);
+
+/// When the fee rates were last updated from an estimate
+pub static LAST_FEE_ESTIMATE: Heartbeat = Heartbeat::new();
+
+/// When the wallet was last synced
+pub static LAST_SYNC: Heartbeat = Heartbeat::new();
+
+/// How the L1 wallet's keys are derived from the seed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Derivation {
+    /// The seed is used as the BIP32 master seed directly, with keys at
+    /// `m/86'/0'/0'` on every network. Other wallets can't recover these keys
+    /// from the mnemonic.
+    #[default]
+    Legacy,
+    /// Keys are derived from the BIP39 seed of the mnemonic, like the L2
+    /// wallet's, on the standard BIP86 path `m/86'/<coin type>'/0'`.
+    Bip39,
+}
+
+impl Derivation {
+    /// External and internal descriptors of the L1 wallet, with private keys.
+    pub fn descriptors(&self, network: Network, seed: &Seed) -> (String, String) {
+        let base_desc = match self {
+            Derivation::Legacy => {
+                let rootpriv = Xpriv::new_master(Network::Signet, seed).expect("valid xpriv");
+                format!("tr({rootpriv}/86h/0h/0h")
+            }
+            Derivation::Bip39 => bip86_base_descriptor(network, &seed::bip39_seed(seed)),
+        };
+        (format!("{base_desc}/0/*)"), format!("{base_desc}/1/*)"))
+    }
+
+    /// sqlite file the wallet is kept in, if it isn't `sqlite_file`. BDK only
+    /// keeps one wallet per database, so each derivation gets its own.
+    fn wallet_file(&self, sqlite_file: &Path) -> Option<PathBuf> {
+        match self {
+            Derivation::Legacy => None,
+            Derivation::Bip39 => Some(sqlite_file.with_extension("bip39.sqlite")),
+        }
+    }
+}
+
+/// Descriptor for the first BIP86 account, without the keychain.
+fn bip86_base_descriptor(network: Network, bip39_seed: &[u8]) -> String {
+    let rootpriv = Xpriv::new_master(network, bip39_seed).expect("valid xpriv");
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+    format!("tr({rootpriv}/86h/{coin_type}h/0h")
+}
+
+/// How L1 batch fees are picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeMode {
+    /// Every batch targets [`FeeConfig::target_blocks`].
+    #[default]
+    Normal,
+    /// Batches target [`FeeConfig::economy_target_blocks`], unless there's a
+    /// backlog of claims waiting behind them.
+    Economy,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeeConfig {
+    /// Confirmation target in blocks.
+    ///
+    /// Defaults to `1`.
+    pub target_blocks: u16,
+
+    /// How batch fees are picked.
+    ///
+    /// Defaults to [`FeeMode::Normal`].
+    pub mode: FeeMode,
+
+    /// Confirmation target in blocks for batches that aren't urgent in
+    /// [`FeeMode::Economy`].
+    ///
+    /// Defaults to `6`.
+    pub economy_target_blocks: u16,
+
+    /// Lowest fee rate to pay, in sat/vB.
+    ///
+    /// Defaults to `1.0`.
+    pub min_sat_per_vb: f64,
+
+    /// Highest fee rate to pay, in sat/vB.
+    ///
+    /// Defaults to `500.0`.
+    pub max_sat_per_vb: f64,
+
+    /// Fee rate to use when there's no recent estimate from the chain source,
+    /// in sat/vB.
+    ///
+    /// Defaults to `1.0`.
+    pub fallback_sat_per_vb: f64,
+
+    /// How long without a good estimate before falling back to
+    /// `fallback_sat_per_vb`.
+    ///
+    /// Defaults to `300` seconds.
+    pub fallback_after: Duration,
+
+    /// How often fee estimates are fetched.
+    ///
+    /// Defaults to `20` seconds.
+    pub poll_interval: Duration,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        Self {
+            target_blocks: 1,
+            mode: FeeMode::Normal,
+            economy_target_blocks: 6,
+            min_sat_per_vb: 1.0,
+            max_sat_per_vb: 500.0,
+            fallback_sat_per_vb: 1.0,
+            fallback_after: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(20),
+        }
+    }
+}
+
+impl FeeConfig {
+    /// Picks the estimate for `target_blocks` out of the chain source's
+    /// estimates and clamps it between the configured floor and ceiling.
+    /// Returns `None` if there's no usable estimate.
+    fn pick(&self, target_blocks: u16, estimates: &HashMap<u16, f64>) -> Option<FeeRate> {
+        // estimates are only given for some targets, so use the closest one
+        // that's at least as fast
+        let (_, sat_per_vb) = estimates
+            .iter()
+            .filter(|(target, fr)| **target <= target_blocks && fr.is_finite() && **fr >= 0.0)
+            .max_by_key(|(target, _)| **target)?;
+        Some(self.clamp(*sat_per_vb))
+    }
+
+    fn clamp(&self, sat_per_vb: f64) -> FeeRate {
+        let sat_per_vb = sat_per_vb.clamp(self.min_sat_per_vb, self.max_sat_per_vb);
+        // 1 sat/vB is 250 sat/kwu
+        FeeRate::from_sat_per_kwu((sat_per_vb * 250.0).round() as u64)
+    }
+}
+
+/// Spawns a tokio task that keeps the fee rates up to date according to
+/// `SETTINGS.fee`.
 pub fn spawn_fee_rate_task() {
+    let cfg = &SETTINGS.fee;
+    let fallback = cfg.clamp(cfg.fallback_sat_per_vb);
+    FEE_RATE.store(fallback.to_sat_per_kwu(), Ordering::Relaxed);
+    ECONOMY_FEE_RATE.store(fallback.to_sat_per_kwu(), Ordering::Relaxed);
+
     tokio::spawn(async move {
+        let mut last_estimate = Instant::now();
         loop {
-            match ESPLORA_CLIENT
-                .get_fee_estimates()
-                .await
-                .map(|frs| frs.get(&1).cloned())
-            {
-                Ok(Some(fr)) => {
-                    // convert sat/vbyte to sat/kwu
-                    let Some(new) = (fr as u64).checked_mul(1000 / 4) else {
-                        warn!("got bad fee rate from esplora: {fr}");
-                        return;
-                    };
-                    let prev = FEE_RATE.swap(new, Ordering::Relaxed);
-                    if new != prev {
-                        info!("updated fee rate from {prev} to {new} sat/kwu")
+            let targets = [cfg.target_blocks, cfg.economy_target_blocks];
+            match CHAIN_SOURCE.fee_estimates(&targets).await {
+                Ok(estimates) => match (
+                    cfg.pick(cfg.target_blocks, &estimates),
+                    cfg.pick(cfg.economy_target_blocks, &estimates),
+                ) {
+                    (Some(new), Some(economy)) => {
+                        let prev = FEE_RATE.swap(new.to_sat_per_kwu(), Ordering::Relaxed);
+                        ECONOMY_FEE_RATE.store(economy.to_sat_per_kwu(), Ordering::Relaxed);
+                        if USING_FALLBACK.swap(false, Ordering::Relaxed) {
+                            info!("got fee estimates, no longer using fallback");
+                        }
+                        if new.to_sat_per_kwu() != prev {
+                            info!(
+                                "updated fee rate from {prev} to {} sat/kwu",
+                                new.to_sat_per_kwu()
+                            )
+                        }
+                        last_estimate = Instant::now();
+                        LAST_FEE_ESTIMATE.beat();
                     }
-                }
-                Ok(None) => warn!("failed to fetch latest fee rates - got none back"),
-                Err(e) => warn!("failed to fetch latest fee rates: {e:?}"),
+                    _ => warn!("got no usable fee rates: {estimates:?}"),
+                },
+                Err(e) => warn!("failed to fetch latest fee rates: {e}"),
+            }
+
+            if last_estimate.elapsed() >= cfg.fallback_after
+                && !USING_FALLBACK.swap(true, Ordering::Relaxed)
+            {
+                warn!(
+                    "no fee estimates for {:?}, using fallback fee rate",
+                    cfg.fallback_after
+                );
+                FEE_RATE.store(fallback.to_sat_per_kwu(), Ordering::Relaxed);
+                ECONOMY_FEE_RATE.store(fallback.to_sat_per_kwu(), Ordering::Relaxed);
             }
-            sleep(Duration::from_secs(20)).await;
+            sleep(cfg.poll_interval).await;
         }
     });
 }
 
+/// Fetches a fee rate for `SETTINGS.fee.target_blocks` once, falling back to
+/// the configured fallback fee rate. For use when the fee rate task isn't
+/// running.
+pub async fn estimate_fee_rate() -> FeeRate {
+    let cfg = &SETTINGS.fee;
+    let picked = match CHAIN_SOURCE.fee_estimates(&[cfg.target_blocks]).await {
+        Ok(estimates) => cfg.pick(cfg.target_blocks, &estimates),
+        Err(e) => {
+            warn!("failed to fetch fee rates: {e}");
+            None
+        }
+    };
+    picked.unwrap_or_else(|| cfg.clamp(cfg.fallback_sat_per_vb))
+}
+
 /// Read-only public getter for the live updating fee rate
 pub fn fee_rate() -> FeeRate {
     FeeRate::from_sat_per_kwu(FEE_RATE.load(Ordering::Relaxed))
 }
 
-/// Shared async client for esplora
-pub static ESPLORA_CLIENT: LazyLock<AsyncClient> = LazyLock::new(|| {
-    esplora_client::Builder::new(&SETTINGS.esplora)
-        .build_async()
-        .expect("valid esplora config")
-});
+/// Live updating fee rate for the economy confirmation target
+pub fn economy_fee_rate() -> FeeRate {
+    FeeRate::from_sat_per_kwu(ECONOMY_FEE_RATE.load(Ordering::Relaxed))
+}
+
+/// Whether the fee rates are the configured fallback rather than estimates
+pub fn using_fallback_fee_rate() -> bool {
+    USING_FALLBACK.load(Ordering::Relaxed)
+}
+
+/// Fee rate to build a batch with. Batches are `urgent` when there are more
+/// claims waiting than fit in them.
+pub fn batch_fee_rate(urgent: bool) -> FeeRate {
+    match SETTINGS.fee.mode {
+        FeeMode::Economy if !urgent => economy_fee_rate(),
+        _ => fee_rate(),
+    }
+}
 
 /// Wrapper around the built-in rusqlite db that allows PersistedWallet to be
 /// shared across multiple threads by lazily initializing per core connections
@@ -77,19 +286,30 @@ pub struct Persister;
 
 thread_local! {
     static DB: Rc<RefCell<Connection>> = RefCell::new(Connection::open(&SETTINGS.sqlite_file).unwrap()).into();
+    /// Connection to the db the wallet is kept in, if it's not `DB`
+    static WALLET_DB: Option<Rc<RefCell<Connection>>> = SETTINGS
+        .derivation
+        .wallet_file(&SETTINGS.sqlite_file)
+        .map(|path| RefCell::new(Connection::open(path).unwrap()).into());
 }
 
 impl Persister {
-    fn db() -> Rc<RefCell<Connection>> {
+    /// This thread's connection to the sqlite db.
+    pub(crate) fn db() -> Rc<RefCell<Connection>> {
         DB.with(|db| db.clone())
     }
+
+    /// This thread's connection to the db the wallet is kept in.
+    fn wallet_db() -> Rc<RefCell<Connection>> {
+        WALLET_DB.with(|db| db.clone()).unwrap_or_else(Self::db)
+    }
 }
 
 impl WalletPersister for Persister {
     type Error = rusqlite::Error;
 
     fn initialize(_persister: &mut Self) -> Result<bdk_wallet::ChangeSet, Self::Error> {
-        let db = Self::db();
+        let db = Self::wallet_db();
         let mut db_ref = db.borrow_mut();
         let db_tx = db_ref.transaction()?;
         ChangeSet::init_sqlite_tables(&db_tx)?;
@@ -102,7 +322,7 @@ impl WalletPersister for Persister {
         _persister: &mut Self,
         changeset: &bdk_wallet::ChangeSet,
     ) -> Result<(), Self::Error> {
-        let db = Self::db();
+        let db = Self::wallet_db();
         let mut db_ref = db.borrow_mut();
         let db_tx = db_ref.transaction()?;
         changeset.persist_to_sqlite(&db_tx)?;
@@ -117,10 +337,7 @@ pub struct L1Wallet(PersistedWallet<Persister>);
 impl L1Wallet {
     /// Create a wallet using the seed file and sqlite database.
     pub fn new(network: Network, seed: &Seed) -> io::Result<Self> {
-        let rootpriv = Xpriv::new_master(Network::Signet, seed).expect("valid xpriv");
-        let base_desc = format!("tr({rootpriv}/86h/0h/0h");
-        let external_desc = format!("{base_desc}/0/*)");
-        let internal_desc = format!("{base_desc}/1/*)");
+        let (external_desc, internal_desc) = SETTINGS.derivation.descriptors(network, seed);
 
         Ok(Self(
             Wallet::load()
@@ -144,23 +361,9 @@ impl L1Wallet {
     pub fn spawn_syncer(l1_wallet: Arc<RwLock<L1Wallet>>) {
         tokio::spawn(async move {
             loop {
-                let req = l1_wallet.read().start_sync_with_revealed_spks().build();
-                // sync is ok because we only receive on our single address
-                // logged on startup
-                let update = match ESPLORA_CLIENT.sync(req, 10).await {
-                    Ok(u) => u,
-                    Err(e) => {
-                        error!("{e:?}");
-                        continue;
-                    }
-                };
-                {
-                    // in a separate block otherwise compiler gets upset that we're holding
-                    // this over the await point
-                    let mut l1w = l1_wallet.write();
-                    l1w.apply_update(update)
-                        .expect("should be able to connect to db");
-                    l1w.persist(&mut Persister).expect("persist should work");
+                match CHAIN_SOURCE.sync(&l1_wallet).await {
+                    Ok(()) => LAST_SYNC.beat(),
+                    Err(e) => error!("failed syncing l1 wallet: {e}"),
                 }
                 sleep(Duration::from_secs(30)).await;
             }
@@ -181,3 +384,73 @@ impl DerefMut for L1Wallet {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_estimate_picking() {
+        let cfg = FeeConfig {
+            min_sat_per_vb: 1.0,
+            max_sat_per_vb: 50.0,
+            ..Default::default()
+        };
+        let estimates = HashMap::from([(1, 20.5), (3, 8.25), (6, 0.5), (144, f64::NAN)]);
+
+        // fractional rates are kept
+        assert_eq!(
+            cfg.pick(1, &estimates),
+            Some(FeeRate::from_sat_per_kwu(5125))
+        );
+        // targets without an estimate use the next fastest one
+        assert_eq!(
+            cfg.pick(5, &estimates),
+            Some(FeeRate::from_sat_per_kwu(2063))
+        );
+        // clamped to the floor
+        assert_eq!(
+            cfg.pick(6, &estimates),
+            Some(FeeRate::from_sat_per_kwu(250))
+        );
+        // bad estimates are skipped
+        assert_eq!(
+            cfg.pick(144, &estimates),
+            Some(FeeRate::from_sat_per_kwu(250))
+        );
+        assert_eq!(cfg.pick(1, &HashMap::from([(1, f64::NAN)])), None);
+        // clamped to the ceiling
+        assert_eq!(
+            cfg.pick(1, &HashMap::from([(1, 1e9)])),
+            Some(FeeRate::from_sat_per_kwu(12_500))
+        );
+    }
+
+    #[test]
+    fn test_bip86_derivation() {
+        // test vector from BIP86
+        let mnemonic: bip39::Mnemonic = "abandon abandon abandon abandon abandon abandon abandon \
+            abandon abandon abandon abandon about"
+            .parse()
+            .unwrap();
+        let base_desc = bip86_base_descriptor(Network::Bitcoin, &mnemonic.to_seed(""));
+        let wallet = Wallet::create(format!("{base_desc}/0/*)"), format!("{base_desc}/1/*)"))
+            .network(Network::Bitcoin)
+            .create_wallet_no_persist()
+            .unwrap();
+        assert_eq!(
+            wallet
+                .peek_address(KeychainKind::External, 0)
+                .address
+                .to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        assert_eq!(
+            wallet
+                .peek_address(KeychainKind::Internal, 0)
+                .address
+                .to_string(),
+            "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7"
+        );
+    }
+}
@@ -1,53 +1,56 @@
-use std::ops::{Deref, DerefMut};
+use std::{ops::Deref, sync::Arc};
 
 use alloy::{
     network::{Ethereum, EthereumWallet, NetworkWallet},
     primitives::Address,
     providers::{
-        fillers::{
-            BlobGasFiller, ChainIdFiller, FillProvider, GasFiller, JoinFill, NonceFiller,
-            WalletFiller,
-        },
+        fillers::{ChainIdFiller, FillProvider, GasFiller, JoinFill, WalletFiller},
         Identity, Provider as AProvider, ProviderBuilder, RootProvider, WalletProvider,
     },
+    rpc::client::ClientBuilder,
     signers::local::PrivateKeySigner,
+    transports::{http::reqwest::Url, RpcError, TransportError},
 };
 use bdk_wallet::bitcoin::{
     bip32::{ChildNumber, DerivationPath, Xpriv},
     secp256k1::Secp256k1,
     Network,
 };
-use bip39::Mnemonic;
 use tracing::{error, info};
 
-use crate::{seed::Seed, settings::SETTINGS};
+use crate::{
+    endpoints::{EndpointError, EndpointStatus, Endpoints, EndpointsConfig},
+    metrics::{self, RpcMetricsLayer},
+    seed::{self, Seed},
+    settings::SETTINGS,
+    Chain, SATS_TO_WEI,
+};
 
 // alloy moment 💀
+// there's deliberately no NonceFiller, nonces are managed by the L2 payout
+// worker
 type Provider = FillProvider<
-    JoinFill<
-        JoinFill<
-            Identity,
-            JoinFill<GasFiller, JoinFill<BlobGasFiller, JoinFill<NonceFiller, ChainIdFiller>>>,
-        >,
-        WalletFiller<EthereumWallet>,
-    >,
+    JoinFill<JoinFill<JoinFill<Identity, GasFiller>, ChainIdFiller>, WalletFiller<EthereumWallet>>,
     RootProvider<Ethereum>,
     Ethereum,
 >;
 
-pub struct L2Wallet(Provider);
-
-impl DerefMut for L2Wallet {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
+/// The L2 wallet, connected to the configured L2 endpoints. Derefs to the
+/// provider for the [active](Endpoints::active) endpoint.
+pub struct L2Wallet(Arc<Endpoints<Provider>>);
 
 impl Deref for L2Wallet {
     type Target = Provider;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        self.0.active()
+    }
+}
+
+impl EndpointError for TransportError {
+    fn is_endpoint_failure(&self) -> bool {
+        // error responses come from a working node rejecting the request
+        !matches!(self, RpcError::ErrorResp(_))
     }
 }
 
@@ -78,22 +81,62 @@ impl L2Wallet {
             <EthereumWallet as NetworkWallet<Ethereum>>::default_signer_address(&wallet)
         );
 
-        let provider = ProviderBuilder::new().wallet(wallet).connect_http(
-            SETTINGS
-                .l2_http_endpoint
-                .parse()
-                .map_err(|_| L2EndpointParseError)?,
-        );
-        Ok(Self(provider))
+        let endpoints = SETTINGS
+            .l2_http_endpoints
+            .iter()
+            .map(|endpoint| endpoint.parse().map_err(|_| L2EndpointParseError))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::connect(wallet, endpoints, SETTINGS.endpoints.clone()))
+    }
+
+    /// Connects the wallet to the L2 HTTP endpoints, most preferred first.
+    pub(crate) fn connect(
+        wallet: EthereumWallet,
+        endpoints: Vec<Url>,
+        cfg: EndpointsConfig,
+    ) -> Self {
+        let providers = endpoints.into_iter().map(|endpoint| {
+            let client = ClientBuilder::default()
+                .layer(RpcMetricsLayer)
+                .http(endpoint.clone());
+            let provider = ProviderBuilder::new()
+                .disable_recommended_fillers()
+                .with_gas_estimation()
+                .fetch_chain_id()
+                .wallet(wallet.clone())
+                .connect_client(client);
+            (endpoint.to_string(), provider)
+        });
+        Self(Arc::new(Endpoints::new("l2", providers, cfg)))
+    }
+
+    /// Spawns a tokio task that health checks the L2 endpoints.
+    pub fn spawn_health_checks(&self) {
+        self.0.spawn_health_checks(|provider| async move {
+            provider
+                .get_block_number()
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+    }
+
+    /// Records an error from a request made through the wallet, failing over
+    /// to another endpoint if it was the endpoint's fault.
+    pub fn report_error(&self, e: &TransportError) {
+        self.0.report(e);
+    }
+
+    /// Health of the L2 endpoints.
+    pub fn endpoint_status(&self) -> Vec<EndpointStatus> {
+        self.0.status()
     }
 
     /// Create a new Ethereum wallet using the given seed and
     /// BIP44 derivation path `m/44'/60'/0'/0/0`.
     pub(crate) fn get_bip44_evm_wallet(seed: &Seed) -> EthereumWallet {
         let derivation_path = DerivationPath::master().extend(BIP44_EVM_WALLET_PATH);
-        let mnemonic = Mnemonic::from_entropy(seed).expect("valid entropy");
-        // We do not use a passphrase.
-        let bip39_seed = mnemonic.to_seed("");
+        let bip39_seed = seed::bip39_seed(seed);
 
         // Network choice affects how extended public and private keys are serialized.
         // See https://github.com/bitcoin/bips/blob/master/bip-0032.mediawiki#serialization-format.
@@ -114,14 +157,21 @@ impl L2Wallet {
     }
 
     pub fn default_signer_address(&self) -> Address {
-        self.0.default_signer_address()
+        self.0.active().default_signer_address()
     }
 
     pub async fn get_default_signer_balance(&self) -> Result<u128, String> {
-        let signer_addr = self.0.default_signer_address();
-        match self.0.get_balance(signer_addr).await {
-            Ok(x) => Ok(x.to()),
+        let signer_addr = self.default_signer_address();
+        match self.get_balance(signer_addr).await {
+            Ok(x) => {
+                let wei: u128 = x.to();
+                metrics::BALANCE
+                    .with_label_values(&[Chain::L2.as_str()])
+                    .set((wei / SATS_TO_WEI as u128) as i64);
+                Ok(wei)
+            }
             Err(e) => {
+                self.report_error(&e);
                 error!("Could not fetch l2 balance {:?}", e);
                 Err("Could not fetch l2 balance".to_string())
             }
@@ -0,0 +1,252 @@
+//! Durable storage for L2 payouts.
+//!
+//! Like [L1 payouts](crate::payout_store), every accepted L2 claim is written
+//! to the wallet's sqlite database before the claim is acknowledged. Payout
+//! transactions are signed and stored before they're sent, along with every
+//! version later re-sent with higher fees, so a restarted faucet keeps
+//! waiting on the same nonces instead of paying the claims out again.
+
+use std::collections::BTreeMap;
+
+use alloy::{
+    consensus::{Transaction as _, TxEnvelope},
+    network::eip2718::{Decodable2718, Encodable2718},
+    primitives::{Address, U256},
+};
+use bdk_wallet::rusqlite::{self, params, Connection};
+
+use crate::{
+    claims::ClaimId,
+    db::{self, bad_data, now_secs},
+    l2_payouts::L2PayoutRequest,
+};
+
+/// Payouts loaded back from the store.
+#[derive(Debug, Default)]
+pub struct StoredPayouts {
+    /// Payouts that haven't been sent yet, oldest first.
+    pub queued: Vec<L2PayoutRequest>,
+    /// Payouts that have been sent, by the nonce of their transaction.
+    pub sent: BTreeMap<u64, SentPayout>,
+}
+
+/// A payout transaction loaded back from the store.
+#[derive(Debug, Default)]
+pub struct SentPayout {
+    /// Claims paid out by the transaction, in the order they're paid out in.
+    pub ids: Vec<ClaimId>,
+    /// Every version of the transaction signed so far, oldest first.
+    pub txs: Vec<TxEnvelope>,
+}
+
+/// Creates the payout tables if they don't exist yet.
+pub fn init() -> rusqlite::Result<()> {
+    db::with(|db| init_in(db))
+}
+
+fn init_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_l2_payouts (
+            id BLOB PRIMARY KEY NOT NULL,
+            address TEXT NOT NULL,
+            amount TEXT NOT NULL,
+            nonce INTEGER,
+            position INTEGER,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS faucet_l2_txs (
+            hash BLOB PRIMARY KEY NOT NULL,
+            nonce INTEGER NOT NULL,
+            tx BLOB NOT NULL
+        );",
+    )
+}
+
+/// Adds a newly accepted payout to the store.
+pub fn insert(req: &L2PayoutRequest) -> rusqlite::Result<()> {
+    db::with(|db| insert_in(db, req))
+}
+
+fn insert_in(db: &Connection, req: &L2PayoutRequest) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO faucet_l2_payouts (id, address, amount, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            req.id.as_slice(),
+            req.address.to_string(),
+            req.amount.to_string(),
+            now_secs()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Records that the payouts are about to be sent in `tx`.
+pub fn mark_sending(ids: &[ClaimId], tx: &TxEnvelope) -> rusqlite::Result<()> {
+    db::with(|db| mark_sending_in(db, ids, tx))
+}
+
+fn mark_sending_in(db: &mut Connection, ids: &[ClaimId], tx: &TxEnvelope) -> rusqlite::Result<()> {
+    let db_tx = db.transaction()?;
+    insert_tx_in(&db_tx, tx)?;
+    {
+        let mut stmt = db_tx.prepare_cached(
+            "UPDATE faucet_l2_payouts SET nonce = ?2, position = ?3 WHERE id = ?1",
+        )?;
+        for (position, id) in ids.iter().enumerate() {
+            stmt.execute(params![id.as_slice(), tx.nonce(), position])?;
+        }
+    }
+    db_tx.commit()
+}
+
+/// Records another version of a payout transaction, about to be sent to
+/// replace the earlier ones.
+pub fn insert_tx(tx: &TxEnvelope) -> rusqlite::Result<()> {
+    db::with(|db| insert_tx_in(db, tx))
+}
+
+fn insert_tx_in(db: &Connection, tx: &TxEnvelope) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT OR IGNORE INTO faucet_l2_txs (hash, nonce, tx) VALUES (?1, ?2, ?3)",
+        params![tx.tx_hash().as_slice(), tx.nonce(), tx.encoded_2718()],
+    )?;
+    Ok(())
+}
+
+/// Removes payouts that have been paid out or given up on, along with
+/// transactions no payout is waiting on anymore.
+pub fn remove(ids: &[ClaimId]) -> rusqlite::Result<()> {
+    db::with(|db| remove_in(db, ids))
+}
+
+fn remove_in(db: &mut Connection, ids: &[ClaimId]) -> rusqlite::Result<()> {
+    let db_tx = db.transaction()?;
+    {
+        let mut stmt = db_tx.prepare_cached("DELETE FROM faucet_l2_payouts WHERE id = ?1")?;
+        for id in ids {
+            stmt.execute([id.as_slice()])?;
+        }
+    }
+    db_tx.execute(
+        "DELETE FROM faucet_l2_txs WHERE nonce NOT IN
+            (SELECT nonce FROM faucet_l2_payouts WHERE nonce IS NOT NULL)",
+        [],
+    )?;
+    db_tx.commit()
+}
+
+/// Loads all outstanding payouts.
+pub fn load() -> rusqlite::Result<StoredPayouts> {
+    db::with(|db| load_from(db))
+}
+
+fn load_from(db: &Connection) -> rusqlite::Result<StoredPayouts> {
+    let mut stored = StoredPayouts::default();
+
+    let mut stmt = db.prepare(
+        "SELECT id, address, amount, nonce FROM faucet_l2_payouts
+        ORDER BY nonce, position, created_at, rowid",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let id: Vec<u8> = row.get(0)?;
+        let address: String = row.get(1)?;
+        let amount: String = row.get(2)?;
+        Ok((id, address, amount, row.get::<_, Option<u64>>(3)?))
+    })?;
+    for row in rows {
+        let (id, address, amount, nonce) = row?;
+        let id = ClaimId::try_from(id).map_err(|_| bad_data("invalid claim id"))?;
+        match nonce {
+            Some(nonce) => stored.sent.entry(nonce).or_default().ids.push(id),
+            None => stored.queued.push(L2PayoutRequest {
+                id,
+                address: address.parse::<Address>().map_err(bad_data)?,
+                amount: amount.parse::<U256>().map_err(bad_data)?,
+                reply: None,
+            }),
+        }
+    }
+
+    let mut stmt = db.prepare("SELECT nonce, tx FROM faucet_l2_txs ORDER BY rowid")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, u64>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+    for row in rows {
+        let (nonce, tx) = row?;
+        let tx = TxEnvelope::decode_2718(&mut tx.as_slice()).map_err(bad_data)?;
+        if let Some(payout) = stored.sent.get_mut(&nonce) {
+            payout.txs.push(tx);
+        }
+    }
+    // payouts are only marked as sent together with storing their
+    // transaction, so this only drops rows written by hand
+    stored.sent.retain(|_, payout| !payout.txs.is_empty());
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        consensus::{SignableTransaction, TxEip1559},
+        primitives::TxKind,
+        signers::{local::PrivateKeySigner, SignerSync},
+    };
+
+    use super::*;
+
+    fn req(id: u8) -> L2PayoutRequest {
+        L2PayoutRequest {
+            id: [id; 16],
+            address: Address::repeat_byte(id),
+            amount: U256::from(1000 * u64::from(id)),
+            reply: None,
+        }
+    }
+
+    fn tx(nonce: u64, max_fee_per_gas: u128) -> TxEnvelope {
+        let tx = TxEip1559 {
+            chain_id: 1,
+            nonce,
+            gas_limit: 21_000,
+            max_fee_per_gas,
+            to: TxKind::Call(Address::repeat_byte(9)),
+            ..Default::default()
+        };
+        let signature = PrivateKeySigner::random()
+            .sign_hash_sync(&tx.signature_hash())
+            .unwrap();
+        tx.into_signed(signature).into()
+    }
+
+    #[test]
+    fn test_l2_payout_store() {
+        let mut db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+
+        for id in 1..=4 {
+            insert_in(&db, &req(id)).unwrap();
+        }
+        let (first, bumped, second) = (tx(5, 10), tx(5, 20), tx(6, 10));
+        mark_sending_in(&mut db, &[[3; 16], [1; 16]], &first).unwrap();
+        insert_tx_in(&db, &bumped).unwrap();
+        mark_sending_in(&mut db, &[[2; 16]], &second).unwrap();
+        remove_in(&mut db, &[[2; 16]]).unwrap();
+
+        let stored = load_from(&db).unwrap();
+        assert_eq!(stored.queued.len(), 1);
+        assert_eq!(stored.queued[0].id, [4; 16]);
+        assert_eq!(stored.queued[0].address, req(4).address);
+        assert_eq!(stored.queued[0].amount, req(4).amount);
+        // payouts keep the order they're paid out in, and transactions the
+        // order they were signed in
+        assert_eq!(stored.sent.len(), 1);
+        assert_eq!(stored.sent[&5].ids, [[3; 16], [1; 16]]);
+        assert_eq!(stored.sent[&5].txs, [first, bumped]);
+
+        // transactions are dropped once no payout is waiting on them
+        let txs: u32 = db
+            .query_row("SELECT COUNT(*) FROM faucet_l2_txs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(txs, 2);
+    }
+}
@@ -0,0 +1,781 @@
+//! Sending of L2 payouts.
+//!
+//! All L2 payouts go through a single worker task that hands out nonces
+//! itself instead of asking the node for each transaction, so concurrent
+//! claims can't race each other for the same nonce. Transactions are sent
+//! without waiting for earlier ones to be mined, and the worker keeps polling
+//! for their receipts, re-sending any that get stuck with higher fees.
+//!
+//! Accepted payouts and every payout transaction signed for them are kept in
+//! the [payout store](crate::l2_payout_store), so they survive restarts. A
+//! payout is only ever sent in transactions with the nonce it was first
+//! signed with, so it can't be paid out twice.
+//!
+//! Optionally, payouts can be batched through the [disperse
+//! contract](crate::disperse), paying out up to the batcher's `max_per_tx`
+//! claims in a single transaction every `period`.
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use alloy::{
+    consensus::{Transaction as _, TxEnvelope},
+    network::TransactionBuilder,
+    primitives::{Address, TxHash, U256},
+    providers::Provider,
+    rpc::types::{TransactionReceipt, TransactionRequest},
+    sol_types::{SolCall, SolEvent},
+    transports::{RpcError, TransportError},
+};
+use bdk_wallet::rusqlite::{self, params, Connection, OptionalExtension};
+use kanal::AsyncReceiver;
+use tokio::{
+    select,
+    sync::{oneshot, watch},
+    time::{interval, interval_at, sleep},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    batcher::{BatcherConfig, BroadcastStatus},
+    claims::{ClaimId, ClaimState, ClaimTracker},
+    db::{self, bad_data},
+    disperse::{pack_payout, Disperse, CREATION_CODE},
+    l2::L2Wallet,
+    l2_payout_store, metrics, Chain, SATS_TO_WEI,
+};
+
+/// How often receipts of pending payouts are checked for.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many times sending a new payout is tried before giving up on it.
+const SEND_ATTEMPTS: usize = 2;
+
+/// Replacement transactions need to pay at least 10% more than the
+/// transaction they replace to be accepted into the mempool.
+const MIN_FEE_BUMP_PERCENT: u128 = 10;
+
+#[derive(Debug)]
+pub struct L2PayoutRequest {
+    pub id: ClaimId,
+    pub address: Address,
+    pub amount: U256,
+    /// Receives the hash of the payout transaction once it has been sent.
+    pub reply: Option<oneshot::Sender<Result<TxHash, L2PayoutFailed>>>,
+}
+
+/// The payout transaction couldn't be sent.
+#[derive(Debug)]
+pub struct L2PayoutFailed;
+
+/// A payout transaction that has been sent but not mined yet.
+#[derive(Debug)]
+struct PendingPayout {
+    /// Claims paid out by the transaction. When batching, these are in the
+    /// same order as the payouts in the disperse call.
+    ids: Vec<ClaimId>,
+    tx: TransactionRequest,
+    /// Hashes of every version of the transaction sent so far, oldest first.
+    /// Any one of them may end up being mined.
+    hashes: Vec<TxHash>,
+    last_sent: Instant,
+}
+
+struct Worker {
+    wallet: Arc<L2Wallet>,
+    claims: Arc<ClaimTracker>,
+    resend_after: Duration,
+    fee_bump_percent: u128,
+    /// Nonce to use for the next payout, or `None` if it needs to be fetched
+    /// from the node again.
+    next_nonce: Option<u64>,
+    pending: BTreeMap<u64, PendingPayout>,
+    /// Address of the disperse contract, if batching
+    disperse: Option<Address>,
+    max_per_tx: usize,
+    batch: Vec<L2PayoutRequest>,
+}
+
+/// Runs the L2 payout worker until the request channel is closed.
+pub(crate) async fn run(
+    wallet: Arc<L2Wallet>,
+    claims: Arc<ClaimTracker>,
+    rx: AsyncReceiver<L2PayoutRequest>,
+    mut cfg_rx: watch::Receiver<BatcherConfig>,
+) {
+    let cfg = cfg_rx.borrow_and_update().clone();
+    let disperse = match cfg.l2_batching {
+        true => loop {
+            match disperse_contract(&wallet, cfg.l2_disperse_contract).await {
+                Ok(address) => break Some(address),
+                Err(e) => {
+                    error!("failed setting up disperse contract, retrying: {e}");
+                    sleep(POLL_INTERVAL).await;
+                }
+            }
+        },
+        false => None,
+    };
+
+    let mut worker = Worker {
+        wallet,
+        claims,
+        resend_after: cfg.l2_resend_after,
+        fee_bump_percent: u128::from(cfg.l2_fee_bump_percent).max(MIN_FEE_BUMP_PERCENT),
+        next_nonce: None,
+        pending: BTreeMap::new(),
+        disperse,
+        max_per_tx: cfg.max_per_tx,
+        batch: Vec::new(),
+    };
+    worker.restore().await;
+    let mut poll_interval = interval(POLL_INTERVAL);
+    let mut batch_interval = interval(cfg.period);
+
+    loop {
+        select! {
+            req = rx.recv() => match req {
+                Ok(req) if worker.disperse.is_some() => {
+                    worker.batch.push(req);
+                    if worker.batch.len() >= worker.max_per_tx {
+                        worker.send_batch().await;
+                    }
+                }
+                Ok(req) => worker.send_payout(req).await,
+                Err(e) => {
+                    error!("L2 payout channel closed: {e:?}");
+                    return;
+                }
+            },
+            _ = batch_interval.tick(), if worker.disperse.is_some() => {
+                if !worker.batch.is_empty() {
+                    worker.send_batch().await;
+                }
+            }
+            _ = poll_interval.tick() => worker.poll_pending().await,
+            Ok(()) = cfg_rx.changed() => {
+                let new_cfg = cfg_rx.borrow_and_update().clone();
+                if new_cfg.period != batch_interval.period() {
+                    let start = Instant::now() + new_cfg.period;
+                    batch_interval = interval_at(start.into(), new_cfg.period);
+                }
+                worker.resend_after = new_cfg.l2_resend_after;
+                worker.fee_bump_percent =
+                    u128::from(new_cfg.l2_fee_bump_percent).max(MIN_FEE_BUMP_PERCENT);
+                worker.max_per_tx = new_cfg.max_per_tx;
+            }
+        }
+    }
+}
+
+impl Worker {
+    /// Picks up the payouts that were outstanding when the faucet last
+    /// stopped.
+    async fn restore(&mut self) {
+        let stored = match l2_payout_store::load() {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("failed loading stored l2 payouts: {e:?}");
+                return;
+            }
+        };
+        let num_sent = stored
+            .sent
+            .values()
+            .map(|payout| payout.ids.len())
+            .sum::<usize>();
+        if stored.queued.is_empty() && num_sent == 0 {
+            return;
+        }
+        info!(
+            "restoring {} stored l2 payouts",
+            stored.queued.len() + num_sent
+        );
+
+        let from = self.wallet.default_signer_address();
+        for (nonce, payout) in stored.sent {
+            let latest = payout.txs.last().expect("stored payout transaction");
+            let txid = (*latest.tx_hash()).into();
+            for id in &payout.ids {
+                self.claims
+                    .restore(*id, Chain::L2, ClaimState::Broadcast { txid });
+            }
+            self.pending.insert(
+                nonce,
+                PendingPayout {
+                    tx: TransactionRequest::from_transaction_with_sender(latest.clone(), from),
+                    hashes: payout.txs.iter().map(|tx| *tx.tx_hash()).collect(),
+                    ids: payout.ids,
+                    last_sent: Instant::now(),
+                },
+            );
+        }
+        for req in stored.queued {
+            self.claims.restore(req.id, Chain::L2, ClaimState::Queued);
+            match self.disperse {
+                Some(_) => self.batch.push(req),
+                None => self.send_payout(req).await,
+            }
+        }
+    }
+
+    async fn send_payout(&mut self, req: L2PayoutRequest) {
+        let L2PayoutRequest {
+            id,
+            address,
+            amount,
+            reply,
+        } = req;
+        let tx = TransactionRequest::default()
+            .with_from(self.wallet.default_signer_address())
+            .with_to(address)
+            .with_value(amount);
+
+        let res = self.send_new(vec![id], tx).await;
+        if let Ok(hash) = res {
+            info!("l2 claim to {address} via tx {hash}");
+        }
+        if let Some(reply) = reply {
+            // the claimant may have disconnected, which is fine
+            let _ = reply.send(res);
+        }
+    }
+
+    /// Pays out all batched requests in one call to the disperse contract.
+    async fn send_batch(&mut self) {
+        let contract = self.disperse.expect("batching");
+        let batch = mem::take(&mut self.batch);
+
+        let mut ids = Vec::with_capacity(batch.len());
+        let mut payouts = Vec::with_capacity(batch.len());
+        let mut replies = Vec::with_capacity(batch.len());
+        let mut total = U256::ZERO;
+        for req in batch {
+            let Some(payout) = pack_payout(req.address, req.amount) else {
+                error!("l2 payout of {} is too large to batch", req.amount);
+                let _ = self.fail(&[req.id], "payout is too large");
+                continue;
+            };
+            ids.push(req.id);
+            payouts.push(payout);
+            replies.extend(req.reply);
+            total += req.amount;
+        }
+        if ids.is_empty() {
+            return;
+        }
+
+        let num_payouts = ids.len();
+        let tx = TransactionRequest::default()
+            .with_from(self.wallet.default_signer_address())
+            .with_to(contract)
+            .with_value(total)
+            .with_input(Disperse::disperseEtherCall { payouts }.abi_encode());
+
+        let res = self.send_new(ids, tx).await;
+        if let Ok(hash) = res {
+            info!("sent {total} wei to {num_payouts} l2 requestors in {hash}");
+        }
+        for reply in replies {
+            let _ = reply.send(res.as_ref().copied().map_err(|_| L2PayoutFailed));
+        }
+    }
+
+    /// Signs a new payout transaction with the next nonce, sends it and
+    /// updates the state of the claims it pays out.
+    ///
+    /// The transaction is stored before it's sent, and it's the only one
+    /// that's retried if sending it fails.
+    async fn send_new(
+        &mut self,
+        ids: Vec<ClaimId>,
+        tx: TransactionRequest,
+    ) -> Result<TxHash, L2PayoutFailed> {
+        let (tx, envelope) = match self.sign_new(tx).await {
+            Ok(signed) => signed,
+            Err(e) => {
+                error!("error preparing l2 payout: {e:?}");
+                self.wallet.report_error(&e);
+                // in case the nonce went out of sync
+                self.next_nonce = None;
+                return self.fail(&ids, "failed to send payout transaction");
+            }
+        };
+        let nonce = envelope.nonce();
+        let hash = *envelope.tx_hash();
+        if let Err(e) = l2_payout_store::mark_sending(&ids, &envelope) {
+            error!("failed recording l2 payout {hash}: {e:?}");
+            return self.fail(&ids, "failed to store payout transaction");
+        }
+
+        match self.send_signed(&envelope).await {
+            BroadcastStatus::Sent => {}
+            // kept pending, so it's re-sent with the same nonce until it's
+            // mined or replaced
+            BroadcastStatus::Unknown => {
+                warn!("couldn't tell whether l2 payout {hash} was sent, waiting for it")
+            }
+            BroadcastStatus::Dropped => {
+                // the node doesn't count the transaction towards the pending
+                // nonce, so the nonce is handed out again and the next
+                // payout replaces this one should it still turn up
+                self.next_nonce = None;
+                return self.fail(&ids, "failed to send payout transaction");
+            }
+        }
+
+        self.next_nonce = Some(nonce + 1);
+        self.claims
+            .set_state_many(&ids, ClaimState::Broadcast { txid: hash.into() });
+        self.pending.insert(
+            nonce,
+            PendingPayout {
+                ids,
+                tx,
+                hashes: vec![hash],
+                last_sent: Instant::now(),
+            },
+        );
+        Ok(hash)
+    }
+
+    /// Fills in the next nonce, fees and gas limit of a new payout
+    /// transaction and signs it.
+    async fn sign_new(
+        &mut self,
+        tx: TransactionRequest,
+    ) -> Result<(TransactionRequest, TxEnvelope), TransportError> {
+        let nonce = self.next_nonce().await?;
+        let fees = self.wallet.estimate_eip1559_fees().await?;
+        let tx = tx
+            .with_nonce(nonce)
+            .with_max_fee_per_gas(fees.max_fee_per_gas)
+            .with_max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
+        let gas_limit = self.wallet.estimate_gas(tx.clone()).await?;
+        let tx = tx.with_gas_limit(gas_limit);
+        let envelope = self.sign(tx.clone()).await?;
+        Ok((tx, envelope))
+    }
+
+    /// Signs a transaction without sending it.
+    async fn sign(&self, tx: TransactionRequest) -> Result<TxEnvelope, TransportError> {
+        self.wallet
+            .fill(tx)
+            .await?
+            .try_into_envelope()
+            .map_err(|_| RpcError::local_usage_str("transaction wasn't signed"))
+    }
+
+    /// Sends a signed payout transaction. If sending errors, checks whether
+    /// the node got the transaction anyway before trying again.
+    async fn send_signed(&self, envelope: &TxEnvelope) -> BroadcastStatus {
+        let hash = *envelope.tx_hash();
+        let mut status = BroadcastStatus::Unknown;
+        for _ in 0..SEND_ATTEMPTS {
+            match self.wallet.send_tx_envelope(envelope.clone()).await {
+                Ok(_) => return BroadcastStatus::Sent,
+                Err(e) => {
+                    error!("error sending l2 payout {hash}: {e:?}");
+                    self.wallet.report_error(&e);
+                }
+            }
+            status = match self.wallet.get_transaction_by_hash(hash).await {
+                Ok(Some(_)) => return BroadcastStatus::Sent,
+                Ok(None) => BroadcastStatus::Dropped,
+                Err(e) => {
+                    warn!("failed looking up l2 payout {hash}: {e:?}");
+                    self.wallet.report_error(&e);
+                    BroadcastStatus::Unknown
+                }
+            };
+        }
+        status
+    }
+
+    /// Gives up on payouts.
+    fn fail(&self, ids: &[ClaimId], reason: &str) -> Result<TxHash, L2PayoutFailed> {
+        self.claims.set_state_many(
+            ids,
+            ClaimState::Failed {
+                reason: reason.to_owned(),
+            },
+        );
+        forget(ids);
+        Err(L2PayoutFailed)
+    }
+
+    async fn next_nonce(&mut self) -> Result<u64, TransportError> {
+        if let Some(nonce) = self.next_nonce {
+            return Ok(nonce);
+        }
+        let node_nonce = self
+            .wallet
+            .get_transaction_count(self.wallet.default_signer_address())
+            .pending()
+            .await?;
+        // if the node dropped some of our pending transactions, don't reuse
+        // their nonces, they'll be re-sent when polling
+        let nonce = match self.pending.last_key_value() {
+            Some((last, _)) => node_nonce.max(last + 1),
+            None => node_nonce,
+        };
+        self.next_nonce = Some(nonce);
+        Ok(nonce)
+    }
+
+    /// Checks pending payouts for receipts, and re-sends those that have been
+    /// pending for too long with higher fees.
+    async fn poll_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        // fetched before the receipts so that any transaction mined with a
+        // nonce below this has a receipt by the time we look for it
+        let mined_nonce = match self
+            .wallet
+            .get_transaction_count(self.wallet.default_signer_address())
+            .latest()
+            .await
+        {
+            Ok(nonce) => nonce,
+            Err(e) => {
+                warn!("failed fetching l2 nonce: {e:?}");
+                self.wallet.report_error(&e);
+                return;
+            }
+        };
+
+        let nonces = self.pending.keys().copied().collect::<Vec<_>>();
+        for nonce in nonces {
+            match self.find_receipt(nonce).await {
+                Ok(Some((hash, receipt))) => {
+                    let payout = self.pending.remove(&nonce).expect("pending payout");
+                    self.settle(&payout, hash, &receipt);
+                    forget(&payout.ids);
+                }
+                Ok(None) if nonce < mined_nonce => {
+                    let payout = self.pending.remove(&nonce).expect("pending payout");
+                    error!("l2 payout with nonce {nonce} was replaced by another transaction");
+                    let _ = self.fail(&payout.ids, "payout transaction was replaced");
+                }
+                Ok(None) => {
+                    if self.pending[&nonce].last_sent.elapsed() >= self.resend_after {
+                        self.resend(nonce).await;
+                    }
+                }
+                Err(e) => {
+                    warn!("failed fetching l2 receipt: {e:?}");
+                    self.wallet.report_error(&e);
+                }
+            }
+        }
+    }
+
+    /// Looks for a receipt for any version of the payout with the given
+    /// nonce.
+    async fn find_receipt(
+        &self,
+        nonce: u64,
+    ) -> Result<Option<(TxHash, TransactionReceipt)>, TransportError> {
+        for hash in self.pending[&nonce].hashes.iter().rev() {
+            if let Some(receipt) = self.wallet.get_transaction_receipt(*hash).await? {
+                return Ok(Some((*hash, receipt)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Updates the claims paid out by a mined payout transaction.
+    fn settle(&self, payout: &PendingPayout, hash: TxHash, receipt: &TransactionReceipt) {
+        if !receipt.status() {
+            error!("l2 payout {hash} reverted");
+            self.claims.set_state_many(
+                &payout.ids,
+                ClaimState::Failed {
+                    reason: "payout transaction reverted".to_owned(),
+                },
+            );
+            return;
+        }
+
+        let confirmed = ClaimState::Confirmed {
+            txid: hash.into(),
+            height: receipt.block_number.unwrap_or_default(),
+        };
+        let dispensed = |wei: U256| {
+            let sats = wei / U256::from(SATS_TO_WEI);
+            metrics::SATS_DISPENSED
+                .with_label_values(&[Chain::L2.as_str()])
+                .inc_by(sats.saturating_to());
+        };
+        let Some(contract) = self.disperse.filter(|c| payout.tx.to == Some((*c).into())) else {
+            self.claims.set_state_many(&payout.ids, confirmed);
+            dispensed(payout.tx.value.unwrap_or_default());
+            return;
+        };
+
+        // the contract logs one Payout per claim, in order
+        let mut results = receipt
+            .inner
+            .logs()
+            .iter()
+            .filter(|log| log.address() == contract)
+            .filter_map(|log| Disperse::Payout::decode_log(&log.inner).ok());
+        for id in &payout.ids {
+            let state = match results.next() {
+                Some(log) if log.data.success => {
+                    dispensed(log.data.value);
+                    confirmed.clone()
+                }
+                _ => ClaimState::Failed {
+                    reason: "transfer to recipient failed".to_owned(),
+                },
+            };
+            self.claims.set_state(id, state);
+        }
+    }
+
+    /// Re-sends a stuck payout with the same nonce and bumped fees.
+    async fn resend(&mut self, nonce: u64) {
+        let fees = match self.wallet.estimate_eip1559_fees().await {
+            Ok(fees) => fees,
+            Err(e) => {
+                warn!("failed estimating l2 fees: {e:?}");
+                self.wallet.report_error(&e);
+                return;
+            }
+        };
+        let payout = &self.pending[&nonce];
+        let bump = |fee: Option<u128>| {
+            let fee = fee.unwrap_or_default();
+            fee + (fee * self.fee_bump_percent).div_ceil(100)
+        };
+        let tx = payout
+            .tx
+            .clone()
+            .with_max_fee_per_gas(bump(payout.tx.max_fee_per_gas).max(fees.max_fee_per_gas))
+            .with_max_priority_fee_per_gas(
+                bump(payout.tx.max_priority_fee_per_gas).max(fees.max_priority_fee_per_gas),
+            );
+        let envelope = match self.sign(tx.clone()).await {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("failed signing l2 payout with nonce {nonce}: {e:?}");
+                self.wallet.report_error(&e);
+                return;
+            }
+        };
+        let hash = *envelope.tx_hash();
+        if let Err(e) = l2_payout_store::insert_tx(&envelope) {
+            error!("failed recording l2 payout {hash}: {e:?}");
+            return;
+        }
+
+        // once stored, it may be mined even if sending it seems to fail
+        let payout = self.pending.get_mut(&nonce).expect("pending payout");
+        payout.hashes.push(hash);
+        match self.wallet.send_tx_envelope(envelope).await {
+            Ok(_) => {
+                info!("re-sent stuck l2 payout with nonce {nonce} as {hash}");
+                payout.tx = tx;
+                payout.last_sent = Instant::now();
+                self.claims
+                    .set_state_many(&payout.ids, ClaimState::Broadcast { txid: hash.into() });
+            }
+            // most likely an earlier version was mined in the meantime, which
+            // is picked up on the next poll
+            Err(e) => {
+                warn!("failed re-sending l2 payout with nonce {nonce}: {e:?}");
+                self.wallet.report_error(&e);
+            }
+        }
+    }
+}
+
+/// Removes payouts the worker is done with from the payout store.
+fn forget(ids: &[ClaimId]) {
+    if let Err(e) = l2_payout_store::remove(ids) {
+        error!("failed removing l2 payouts from store: {e:?}");
+    }
+}
+
+/// Returns the address of the disperse contract to batch payouts through.
+///
+/// Uses the configured contract if there is one. Otherwise, uses the contract
+/// this faucet deployed before on the same chain, deploying it first if it
+/// hasn't been yet.
+async fn disperse_contract(
+    wallet: &L2Wallet,
+    configured: Option<Address>,
+) -> Result<Address, String> {
+    let has_code = |address| async move {
+        wallet
+            .get_code_at(address)
+            .await
+            .map(|code| !code.is_empty())
+            .map_err(|e| format!("failed fetching contract code: {e:?}"))
+    };
+
+    if let Some(address) = configured {
+        return match has_code(address).await? {
+            true => Ok(address),
+            false => Err(format!("no contract deployed at {address}")),
+        };
+    }
+
+    let chain_id = wallet
+        .get_chain_id()
+        .await
+        .map_err(|e| format!("failed fetching chain id: {e:?}"))?;
+    if let Some(address) =
+        db::with(|db| load_disperse_address(db, chain_id)).map_err(|e| format!("{e:?}"))?
+    {
+        if has_code(address).await? {
+            return Ok(address);
+        }
+        warn!("previously deployed disperse contract {address} is gone, deploying a new one");
+    }
+
+    let address = deploy_disperse(wallet).await?;
+    info!("deployed disperse contract at {address}");
+    db::with(|db| store_disperse_address(db, chain_id, address)).map_err(|e| format!("{e:?}"))?;
+    Ok(address)
+}
+
+/// Deploys the disperse contract and waits for it to be mined.
+async fn deploy_disperse(wallet: &L2Wallet) -> Result<Address, String> {
+    let from = wallet.default_signer_address();
+    let nonce = wallet
+        .get_transaction_count(from)
+        .pending()
+        .await
+        .map_err(|e| format!("failed fetching nonce: {e:?}"))?;
+    let tx = TransactionRequest::default()
+        .with_from(from)
+        .with_nonce(nonce)
+        .with_deploy_code(CREATION_CODE);
+    let receipt = wallet
+        .send_transaction(tx)
+        .await
+        .map_err(|e| format!("failed sending deployment: {e:?}"))?
+        .get_receipt()
+        .await
+        .map_err(|e| format!("failed waiting for deployment: {e:?}"))?;
+    match receipt.contract_address {
+        Some(address) if receipt.status() => Ok(address),
+        _ => Err(format!("deployment {} failed", receipt.transaction_hash)),
+    }
+}
+
+fn init_disperse_table(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_l2_disperse (
+            chain_id INTEGER PRIMARY KEY NOT NULL,
+            address TEXT NOT NULL
+        );",
+    )
+}
+
+fn load_disperse_address(db: &Connection, chain_id: u64) -> rusqlite::Result<Option<Address>> {
+    init_disperse_table(db)?;
+    let address: Option<String> = db
+        .query_row(
+            "SELECT address FROM faucet_l2_disperse WHERE chain_id = ?1",
+            [chain_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    address
+        .map(|address| address.parse().map_err(bad_data))
+        .transpose()
+}
+
+fn store_disperse_address(
+    db: &Connection,
+    chain_id: u64,
+    address: Address,
+) -> rusqlite::Result<()> {
+    init_disperse_table(db)?;
+    db.execute(
+        "INSERT OR REPLACE INTO faucet_l2_disperse (chain_id, address) VALUES (?1, ?2)",
+        params![chain_id, address.to_string()],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{node_bindings::Anvil, signers::local::PrivateKeySigner};
+
+    use super::*;
+
+    #[test]
+    fn test_disperse_address_store() {
+        let db = Connection::open_in_memory().unwrap();
+        assert_eq!(load_disperse_address(&db, 1).unwrap(), None);
+
+        let address = Address::repeat_byte(1);
+        store_disperse_address(&db, 1, address).unwrap();
+        assert_eq!(load_disperse_address(&db, 1).unwrap(), Some(address));
+        assert_eq!(load_disperse_address(&db, 2).unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs anvil to be installed"]
+    async fn test_batched_payouts_on_anvil() {
+        let anvil = Anvil::new().spawn();
+        let signer: PrivateKeySigner = anvil.keys()[0].clone().into();
+        let wallet = Arc::new(L2Wallet::connect(
+            signer.into(),
+            vec![anvil.endpoint_url()],
+            Default::default(),
+        ));
+        let claims = Arc::new(ClaimTracker::default());
+        l2_payout_store::init().unwrap();
+        let contract = deploy_disperse(&wallet).await.unwrap();
+
+        let mut worker = Worker {
+            wallet: wallet.clone(),
+            claims: claims.clone(),
+            resend_after: Duration::from_secs(60),
+            fee_bump_percent: 20,
+            next_nonce: None,
+            pending: BTreeMap::new(),
+            disperse: Some(contract),
+            max_per_tx: 10,
+            batch: Vec::new(),
+        };
+
+        let recipients = [Address::repeat_byte(1), Address::repeat_byte(2)];
+        let mut ids = Vec::new();
+        for (i, address) in recipients.into_iter().enumerate() {
+            let id = claims.register(crate::Chain::L2);
+            ids.push(id);
+            worker.batch.push(L2PayoutRequest {
+                id,
+                address,
+                amount: U256::from(1000 * (i + 1)),
+                reply: None,
+            });
+        }
+        worker.send_batch().await;
+        assert_eq!(worker.pending.len(), 1);
+
+        // anvil mines transactions straight away
+        worker.poll_pending().await;
+        assert!(worker.pending.is_empty());
+        for (i, (id, address)) in ids.iter().zip(recipients).enumerate() {
+            assert!(matches!(
+                claims.get(id).unwrap().state,
+                ClaimState::Confirmed { .. }
+            ));
+            let balance = wallet.get_balance(address).await.unwrap();
+            assert_eq!(balance, U256::from(1000 * (i + 1)));
+        }
+        assert!(wallet.get_balance(contract).await.unwrap().is_zero());
+    }
+}
@@ -0,0 +1,227 @@
+//! Per-chain claim settings that can be changed while the faucet is running.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use bdk_wallet::bitcoin::Amount;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    claim_rate::ClaimRate,
+    pow::{calculate_difficulty, DifficultyConfig, DifficultyConfigError},
+    settings::LayerConfig,
+};
+
+/// The live claim settings of a chain, starting out as configured in
+/// `faucet.toml`.
+#[derive(Debug)]
+pub struct LayerState {
+    paused: AtomicBool,
+    live: RwLock<LiveLayer>,
+    claim_rate: Mutex<ClaimRate>,
+}
+
+/// Difficulty of new challenges, along with the terms it's made of.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Difficulty {
+    /// Sum of the terms, capped at `max_difficulty`
+    #[serde(skip)]
+    pub total: u8,
+    /// Difficulty from the faucet's balance
+    pub balance: u8,
+    /// Extra difficulty while claims come in faster than the target rate
+    pub claim_rate: u8,
+    /// Recent claims per minute
+    pub claims_per_minute: f64,
+    /// Extra difficulty for the client's own recent claims
+    pub reputation: u8,
+    /// Recent claims of the client, each counting less the longer ago it was
+    /// made
+    pub recent_claims: f64,
+}
+
+/// A [`LayerConfig`] along with the [`DifficultyConfig`] built from it, so the
+/// two are always swapped together.
+#[derive(Debug)]
+struct LiveLayer {
+    config: LayerConfig,
+    difficulty: DifficultyConfig,
+}
+
+impl LayerState {
+    pub fn new(config: LayerConfig) -> Result<Self, DifficultyConfigError> {
+        Ok(Self {
+            paused: AtomicBool::new(false),
+            live: RwLock::new(LiveLayer {
+                difficulty: difficulty_config(&config)?,
+                config,
+            }),
+            claim_rate: Mutex::new(ClaimRate::new(Instant::now())),
+        })
+    }
+
+    pub fn config(&self) -> LayerConfig {
+        self.live.read().config.clone()
+    }
+
+    /// Difficulty of challenges handed out while the faucet holds `balance`,
+    /// to a client with `recent_claims`.
+    pub fn difficulty(&self, balance: Amount, recent_claims: f64) -> Difficulty {
+        let live = self.live.read();
+        let balance = calculate_difficulty(&live.difficulty, balance);
+        let cfg = &live.config.claim_rate;
+        let claims_per_minute = self
+            .claim_rate
+            .lock()
+            .per_minute(Instant::now(), cfg.half_life);
+        let claim_rate = cfg.extra_difficulty(claims_per_minute);
+        let reputation = live.config.reputation.extra_difficulty(recent_claims);
+        Difficulty {
+            total: balance
+                .saturating_add(claim_rate)
+                .saturating_add(reputation)
+                .min(live.config.max_difficulty),
+            balance,
+            claim_rate,
+            claims_per_minute,
+            reputation,
+            recent_claims,
+        }
+    }
+
+    /// Counts a successful claim towards the recent claim rate.
+    pub fn record_claim(&self) {
+        let half_life = self.live.read().config.claim_rate.half_life;
+        self.claim_rate.lock().record(Instant::now(), half_life);
+    }
+
+    /// Changes the settings with `f`, rebuilding the difficulty config. The
+    /// settings are left as they were if the new ones don't make a valid
+    /// difficulty config.
+    pub fn update(
+        &self,
+        f: impl FnOnce(&mut LayerConfig),
+    ) -> Result<LayerConfig, DifficultyConfigError> {
+        let mut live = self.live.write();
+        let mut config = live.config.clone();
+        f(&mut config);
+        live.difficulty = difficulty_config(&config)?;
+        live.config = config.clone();
+        Ok(config)
+    }
+
+    /// Whether new challenges and claims are being turned away.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn difficulty_config(
+    config: &LayerConfig,
+) -> Result<DifficultyConfig, DifficultyConfigError> {
+    DifficultyConfig::new(
+        config.max_difficulty,
+        config.min_difficulty,
+        config.min_balance,
+        config.amount_per_claim,
+        config.difficulty_increase_coeff,
+    )?
+    .with_curve(&config.difficulty_curve)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        claim_history::ReputationConfig, claim_rate::ClaimRateConfig, pow::DifficultyCurve,
+        pow_algorithm::PowAlgorithm,
+    };
+
+    fn layer_config() -> LayerConfig {
+        LayerConfig {
+            min_difficulty: 18,
+            max_difficulty: 64,
+            min_balance: Amount::ZERO,
+            amount_per_claim: Amount::from_sat(10_000),
+            difficulty_increase_coeff: 20.,
+            difficulty_curve: DifficultyCurve::Linear,
+            claim_rate: ClaimRateConfig::default(),
+            reputation: ReputationConfig::default(),
+            pow_algorithm: PowAlgorithm::Sha256,
+            pow_shares: 1,
+            challenge_duration: Duration::from_secs(120),
+            cooldown: None,
+        }
+    }
+
+    #[test]
+    fn test_invalid_update_is_rejected() {
+        let layer = LayerState::new(layer_config()).unwrap();
+
+        let config = layer.update(|c| c.min_difficulty = 20).unwrap();
+        assert_eq!(config.min_difficulty, 20);
+        assert_eq!(layer.difficulty(Amount::from_int_btc(1), 0.0).total, 20);
+
+        assert!(layer.update(|c| c.min_difficulty = 65).is_err());
+        assert_eq!(layer.config().min_difficulty, 20);
+        assert_eq!(layer.difficulty(Amount::from_int_btc(1), 0.0).total, 20);
+    }
+
+    #[test]
+    fn test_claim_rate_raises_difficulty() {
+        let layer = LayerState::new(LayerConfig {
+            claim_rate: ClaimRateConfig {
+                target_per_minute: Some(1.0),
+                ..Default::default()
+            },
+            ..layer_config()
+        })
+        .unwrap();
+        let difficulty = layer.difficulty(Amount::from_int_btc(1), 0.0);
+        assert_eq!((difficulty.total, difficulty.claim_rate), (18, 0));
+
+        // 100 claims at once count as about 14 a minute with a 5 minute half
+        // life, over 8 times the target
+        for _ in 0..100 {
+            layer.record_claim();
+        }
+        let difficulty = layer.difficulty(Amount::from_int_btc(1), 0.0);
+        assert_eq!(difficulty.balance, 18);
+        assert_eq!(difficulty.claim_rate, 3);
+        assert_eq!(difficulty.total, 21);
+
+        // never above the maximum
+        let difficulty = layer.difficulty(Amount::ZERO, 0.0);
+        assert_eq!((difficulty.balance, difficulty.total), (64, 64));
+    }
+
+    #[test]
+    fn test_reputation_raises_difficulty() {
+        let layer = LayerState::new(LayerConfig {
+            reputation: ReputationConfig {
+                bits_per_claim: 2,
+                ..Default::default()
+            },
+            ..layer_config()
+        })
+        .unwrap();
+        let difficulty = layer.difficulty(Amount::from_int_btc(1), 0.0);
+        assert_eq!((difficulty.total, difficulty.reputation), (18, 0));
+
+        let difficulty = layer.difficulty(Amount::from_int_btc(1), 3.0);
+        assert_eq!((difficulty.total, difficulty.reputation), (24, 6));
+
+        // capped at `max_extra_difficulty`
+        let difficulty = layer.difficulty(Amount::from_int_btc(1), 50.0);
+        assert_eq!((difficulty.total, difficulty.reputation), (26, 8));
+    }
+}
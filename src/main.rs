@@ -1,29 +1,51 @@
 //! A simple faucet server that uses [`axum`] and [`bdk_wallet`]
 //! to generate and dispense bitcoin.
 
+pub mod admin;
+pub mod bans;
 mod batcher;
+pub mod chain_source;
+pub mod challenge_token;
+pub mod claim_history;
+pub mod claim_rate;
+pub mod claims;
+pub mod cli;
+pub mod cooldown;
+pub mod db;
+pub mod disperse;
+pub mod endpoints;
+pub mod error;
+pub mod fee_bump;
+pub mod health;
+pub mod ip_bucket;
 pub mod l1;
 pub mod l2;
+pub mod l2_payout_store;
+pub mod l2_payouts;
+pub mod layer;
 pub mod macros;
+pub mod metrics;
+pub mod payout_store;
 pub mod pow;
+pub mod pow_algorithm;
+pub mod reload;
 pub mod seed;
 pub mod settings;
 
 use std::{
-    env,
-    net::{IpAddr, SocketAddr},
+    env, io,
+    net::SocketAddr,
+    process,
     sync::{Arc, LazyLock},
+    time::Duration,
 };
 
 use alloy::{
     consensus::constants::ETH_TO_WEI,
-    network::TransactionBuilder,
     primitives::{Address as L2Address, U256},
-    providers::Provider,
-    rpc::types::TransactionRequest,
 };
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     routing::get,
     Json, Router,
@@ -31,32 +53,54 @@ use axum::{
 use axum_client_ip::ClientIp;
 use batcher::{Batcher, L1PayoutRequest, PayoutRequest};
 use bdk_wallet::{
-    bitcoin::{address::NetworkUnchecked, Address as L1Address, Amount},
+    bitcoin::{address::NetworkUnchecked, Address as L1Address, Amount, FeeRate},
     KeychainKind,
 };
+use chain_source::CHAIN_SOURCE;
+use challenge_token::{ChallengeToken, SpentTokensUnavailable};
+use claim_history::ClaimHistory;
+use claims::{ClaimId, ClaimState, PayoutTxid};
+use clap::Parser;
+use cli::{Cli, Command};
 use concurrent_map::Minimum;
-use l1::{L1Wallet, Persister};
+use cooldown::CooldownKey;
+use endpoints::EndpointStatus;
+use error::ApiError;
+use fee_bump::confirmed_height;
+use health::Readiness;
+use ip_bucket::IpBucket;
+use l1::{FeeConfig, L1Wallet, Persister};
 use l2::L2Wallet;
+use l2_payouts::L2PayoutRequest;
+use layer::{Difficulty, LayerState};
 use parking_lot::RwLock;
-use pow::{Challenge, Nonce, Solution};
-use seed::SavableSeed;
+use pow::{Challenge, ChallengeMode, Nonce};
+use pow_algorithm::PowAlgorithm;
 use serde::{Deserialize, Serialize};
-use settings::SETTINGS;
+use settings::{LayerConfig, SETTINGS};
 use shrex::Hex;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, sync::oneshot, task};
 use tracing::{error, info};
-use tracing_subscriber::EnvFilter;
-
-use crate::pow::{calculate_difficulty, DifficultyConfig};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
 
 pub struct AppState {
     l1_wallet: Arc<RwLock<L1Wallet>>,
-    l2_wallet: L2Wallet,
-    l1_difficulty_config: DifficultyConfig,
-    l2_difficulty_config: DifficultyConfig,
+    l2_wallet: Arc<L2Wallet>,
+    l1: LayerState,
+    l2: LayerState,
+    claim_history: ClaimHistory,
     batcher: Batcher,
 }
 
+impl AppState {
+    fn layer(&self, chain: Chain) -> &LayerState {
+        match chain {
+            Chain::L1 => &self.l1,
+            Chain::L2 => &self.l2,
+        }
+    }
+}
+
 pub static CRATE_NAME: LazyLock<String> =
     LazyLock::new(|| env!("CARGO_PKG_NAME").replace("-", "_"));
 
@@ -66,8 +110,17 @@ const SATS_TO_WEI: u64 = (BTC_TO_WEI / BTC_TO_SATS as u128) as u64;
 
 #[tokio::main]
 async fn main() {
-    let builder = tracing_subscriber::fmt();
-    if let Ok(level) = std::env::var("RUST_LOG") {
+    let cli = Cli::parse();
+    settings::set_config_path(cli.config);
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // keep stdout clean for commands that print something to use in scripts
+    let writer = match command {
+        Command::Serve => BoxMakeWriter::new(io::stdout),
+        _ => BoxMakeWriter::new(io::stderr),
+    };
+    let builder = tracing_subscriber::fmt().with_writer(writer);
+    if let Ok(level) = env::var("RUST_LOG") {
         builder
             .with_env_filter(EnvFilter::new(format!("{}={level}", *CRATE_NAME,)))
             .init();
@@ -75,9 +128,18 @@ async fn main() {
         builder.init();
     }
 
+    if let Err(e) = cli::run(command).await {
+        eprintln!("error: {e}");
+        process::exit(1);
+    }
+}
+
+async fn serve() {
     let (host, port) = (SETTINGS.host, SETTINGS.port);
+    metrics::init();
 
-    let seed = SavableSeed::load_or_create().expect("seed load should work");
+    let seed = seed::load_or_create().unwrap_or_else(|e| panic!("failed loading seed: {e}"));
+    challenge_token::init_key(&seed);
 
     let mut l1_wallet =
         L1Wallet::new(SETTINGS.network, &seed).expect("l1 wallet creation to succeed");
@@ -87,45 +149,53 @@ async fn main() {
         .expect("successful persist");
     info!("L1 address: {}", l1_address.address);
     l1::spawn_fee_rate_task();
+    cooldown::init().expect("cooldown table creation to succeed");
+    payout_store::init().expect("payout table creation to succeed");
+    l2_payout_store::init().expect("l2 payout table creation to succeed");
+    bans::init().expect("ban table creation to succeed");
+    challenge_token::init().expect("spent token table creation to succeed");
 
-    let l2_wallet = L2Wallet::new(&seed).expect("l2 wallet creation to succeed");
+    let l2_wallet = Arc::new(L2Wallet::new(&seed).expect("l2 wallet creation to succeed"));
     let l1_wallet = Arc::new(RwLock::new(l1_wallet));
     let mut batcher = Batcher::new(SETTINGS.batcher.clone());
-    batcher.start(l1_wallet.clone());
+    batcher.start(l1_wallet.clone(), l2_wallet.clone());
 
     L1Wallet::spawn_syncer(l1_wallet.clone());
+    CHAIN_SOURCE.spawn_health_checks();
+    l2_wallet.spawn_health_checks();
 
-    let l1_difficulty_config = DifficultyConfig::new(
-        SETTINGS.l1.max_difficulty,
-        SETTINGS.l1.min_difficulty,
-        SETTINGS.l1.min_balance,
-        SETTINGS.l1.amount_per_claim,
-        SETTINGS.l1.difficulty_increase_coeff,
-    )
-    .expect("good difficulty config");
-    let l2_difficulty_config = DifficultyConfig::new(
-        SETTINGS.l2.max_difficulty,
-        SETTINGS.l2.min_difficulty,
-        SETTINGS.l2.min_balance,
-        SETTINGS.l2.amount_per_claim,
-        SETTINGS.l2.difficulty_increase_coeff,
-    )
-    .expect("good difficulty config");
+    let l1 = LayerState::new(SETTINGS.l1.clone()).expect("good difficulty config");
+    let l2 = LayerState::new(SETTINGS.l2.clone()).expect("good difficulty config");
+    let claim_history = ClaimHistory::new(SETTINGS.claim_history.clone())
+        .expect("claim history table creation to succeed");
 
     let state = Arc::new(AppState {
         l1_wallet,
-        l1_difficulty_config,
         l2_wallet,
-        l2_difficulty_config,
+        l1,
+        l2,
+        claim_history,
         batcher,
     });
+    reload::spawn(state.clone());
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/pow_challenge/{chain}", get(get_pow_challenge))
         .route("/claim_l1/{solution}/{address}", get(claim_l1))
         .route("/claim_l2/{solution}/{address}", get(claim_l2))
+        .route("/claims/{id}", get(get_claim))
+        .route("/queue", get(get_queue))
+        .route("/fee_rate", get(get_fee_rate))
+        .route("/endpoints", get(get_endpoints))
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .route("/metrics", get(get_metrics))
         .route("/balance/{chain}", get(get_balance))
-        .route("/sats_to_claim/{chain}", get(get_sats_per_claim))
+        .route("/sats_to_claim/{chain}", get(get_sats_per_claim));
+    if let Some(token) = &SETTINGS.admin.token {
+        app = app.nest("/admin", admin::router(token));
+    }
+    let app = app
         .layer(SETTINGS.ip_src.clone().into_extension())
         .with_state(state);
 
@@ -139,14 +209,44 @@ async fn main() {
     .unwrap();
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ChallengeParams {
+    /// Recipient address to bind the challenge to
+    address: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProvidedChallenge {
     nonce: Hex<Nonce>,
+    /// Difficulty each share of the solution has to meet
     difficulty: u8,
+    /// Number of distinct shares the solution has to consist of
+    shares: u8,
+    /// What the current difficulty is made of. An outstanding challenge that
+    /// is handed out again keeps the difficulty it was issued with.
+    difficulty_components: Difficulty,
+    /// Hash function the challenge has to be solved with
+    algorithm: PowAlgorithm,
+    /// Recipient address the challenge is bound to, exactly as it has to be
+    /// hashed by the solver
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    /// Signed challenge token to send back with the solution when the faucet
+    /// runs with stateless challenges
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<Hex<Vec<u8>>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaimParams {
+    /// Challenge token as returned by `/pow_challenge`, required when the
+    /// faucet runs with stateless challenges
+    token: Option<Hex<Vec<u8>>>,
 }
 
 /// Which chain the faucet is reasoning about.
-#[derive(Debug, PartialEq, PartialOrd, Ord, Clone, Copy, Eq, Hash)]
+#[derive(Debug, PartialEq, PartialOrd, Ord, Clone, Copy, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Chain {
     L1,
     L2,
@@ -156,6 +256,16 @@ impl Minimum for Chain {
     const MIN: Self = Chain::L1;
 }
 
+impl Chain {
+    /// Stable name of the chain, as used in routes and when persisting data.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Chain::L1 => "l1",
+            Chain::L2 => "l2",
+        }
+    }
+}
+
 impl TryFrom<&str> for Chain {
     type Error = (StatusCode, String);
 
@@ -174,14 +284,27 @@ impl TryFrom<&str> for Chain {
 async fn get_pow_challenge(
     ClientIp(ip): ClientIp,
     Path(chain): Path<String>,
+    Query(params): Query<ChallengeParams>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<ProvidedChallenge>, (StatusCode, String)> {
+) -> Result<Json<ProvidedChallenge>, ApiError> {
     let chain = Chain::try_from(chain.as_str())?;
+    let ip = IpBucket::new(ip, SETTINGS.ipv6_prefix_len);
+    check_ban(&ip)?;
+    check_paused(&state, chain)?;
+
+    let address = params
+        .address
+        .map(|address| normalize_address(chain, &address))
+        .transpose()?;
+    if address.is_none() && SETTINGS.require_address_binding {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "An address is required to request a challenge",
+        ));
+    }
 
-    let layer_config = match chain {
-        Chain::L1 => &SETTINGS.l1,
-        Chain::L2 => &SETTINGS.l2,
-    };
+    let layer = state.layer(chain);
+    let layer_config = layer.config();
 
     let balance = match chain {
         Chain::L1 => state.l1_wallet.read().balance().trusted_spendable(),
@@ -196,99 +319,538 @@ async fn get_pow_challenge(
         }
     };
 
-    let difficulty = match chain {
-        Chain::L1 => calculate_difficulty(&state.l1_difficulty_config, balance),
-        Chain::L2 => calculate_difficulty(&state.l2_difficulty_config, balance),
+    let history_keys = history_keys(&layer_config, ip, address.as_deref());
+    let recent_claims =
+        state
+            .claim_history
+            .recent_claims(chain, &history_keys, layer_config.reputation.half_life);
+    let components = layer.difficulty(balance, recent_claims);
+    let difficulty = pow::share_difficulty(components.total, layer_config.pow_shares);
+    metrics::CHALLENGES_ISSUED
+        .with_label_values(&[chain.as_str()])
+        .inc();
+    metrics::DIFFICULTY
+        .with_label_values(&[chain.as_str()])
+        .set(components.total.into());
+    metrics::BALANCE
+        .with_label_values(&[chain.as_str()])
+        .set(balance.to_sat() as i64);
+
+    let challenge = match SETTINGS.challenge_mode {
+        ChallengeMode::Stateful => {
+            let challenge = Challenge::get(
+                chain,
+                &ip,
+                difficulty,
+                layer_config.pow_algorithm,
+                layer_config.pow_shares,
+                layer_config.challenge_duration,
+                address,
+            );
+            ProvidedChallenge {
+                nonce: Hex(challenge.nonce()),
+                difficulty: challenge.difficulty(),
+                shares: challenge.shares(),
+                difficulty_components: components,
+                algorithm: challenge.algorithm(),
+                address: challenge.address().map(str::to_owned),
+                token: None,
+            }
+        }
+        ChallengeMode::Stateless => {
+            let token = ChallengeToken::issue(
+                chain,
+                &ip,
+                difficulty,
+                layer_config.pow_algorithm,
+                layer_config.pow_shares,
+                layer_config.challenge_duration,
+                address,
+            );
+            ProvidedChallenge {
+                nonce: Hex(token.nonce()),
+                difficulty: token.difficulty(),
+                shares: token.shares(),
+                difficulty_components: components,
+                algorithm: token.algorithm(),
+                address: token.address().map(str::to_owned),
+                token: Some(Hex(token.encode())),
+            }
+        }
     };
-
-    if let IpAddr::V4(ip) = ip {
-        let challenge = Challenge::get(chain, &ip, difficulty, layer_config.challenge_duration);
-        Ok(Json(ProvidedChallenge {
-            nonce: Hex(challenge.nonce()),
-            difficulty: challenge.difficulty(),
-        }))
-    } else {
-        Err((
-            StatusCode::UNPROCESSABLE_ENTITY,
-            "IPV6 is not supported at the moment".to_string(),
-        ))
-    }
+    Ok(Json(challenge))
 }
 
 async fn claim_l1(
     ClientIp(ip): ClientIp,
-    Path((solution, address)): Path<(Hex<Solution>, L1Address<NetworkUnchecked>)>,
+    Path((solution, address)): Path<(Hex<Vec<u8>>, L1Address<NetworkUnchecked>)>,
+    Query(params): Query<ClaimParams>,
     State(state): State<Arc<AppState>>,
-) -> Result<(), (StatusCode, String)> {
-    let IpAddr::V4(ip) = ip else {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "IPV6 is not supported at this time".to_string(),
-        ));
-    };
-
-    // num hashes on average to solve challenge: 2^15
-    if let Err(e) = Challenge::check_solution(Chain::L1, &ip, solution.0) {
-        return Err((StatusCode::BAD_REQUEST, e.to_string()));
-    }
-
-    let address = address.require_network(SETTINGS.network).map_err(|_| {
-        (
-            StatusCode::BAD_REQUEST,
-            "wrong address network type".to_string(),
+) -> Result<String, ApiError> {
+    let ip = IpBucket::new(ip, SETTINGS.ipv6_prefix_len);
+    check_ban(&ip)?;
+    check_paused(&state, Chain::L1)?;
+
+    let address = address
+        .require_network(SETTINGS.network)
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "wrong address network type"))?;
+
+    let cooldown_keys = [
+        CooldownKey::Ip(ip),
+        CooldownKey::Address(address.to_string()),
+    ];
+    check_cooldown(&state, Chain::L1, &cooldown_keys)?;
+
+    // reserve a place in the queue before the challenge is spent so clients
+    // can retry with the same solution when the queue is full
+    let slot = state.batcher.reserve_slot().map_err(|_| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The payout queue is full. Please try again later.",
         )
+        .with_retry_after(state.batcher.period())
     })?;
 
+    check_solution(
+        Chain::L1,
+        &ip,
+        &address.to_string(),
+        &solution.0,
+        params.token,
+    )?;
+
+    start_cooldown(&state, Chain::L1, &cooldown_keys)?;
+    record_claim(&state, Chain::L1, ip, &address.to_string());
+
+    let id = state.batcher.claims().register(Chain::L1);
     state
         .batcher
-        .queue_payout_request(PayoutRequest::L1(L1PayoutRequest {
-            address,
-            amount: SETTINGS.l1.amount_per_claim,
-        }))
+        .queue_payout_request(PayoutRequest::L1(
+            slot,
+            L1PayoutRequest {
+                id,
+                address,
+                amount: state.l1.config().amount_per_claim,
+            },
+        ))
         .await
-        .expect("successful queuing");
+        .map_err(|e| {
+            error!("failed queuing payout: {e:?}");
+            state.batcher.claims().set_state(
+                &id,
+                ClaimState::Failed {
+                    reason: "failed to queue payout".to_owned(),
+                },
+            );
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to queue payout. Please try again later.",
+            )
+        })?;
 
-    Ok(())
+    Ok(Hex(id).to_string())
 }
 
 async fn claim_l2(
     ClientIp(ip): ClientIp,
-    Path((solution, address)): Path<(Hex<Solution>, L2Address)>,
+    Path((solution, address)): Path<(Hex<Vec<u8>>, L2Address)>,
+    Query(params): Query<ClaimParams>,
     State(state): State<Arc<AppState>>,
-) -> Result<String, (StatusCode, String)> {
-    let IpAddr::V4(ip) = ip else {
-        return Err((
+) -> Result<String, ApiError> {
+    let ip = IpBucket::new(ip, SETTINGS.ipv6_prefix_len);
+    check_ban(&ip)?;
+    check_paused(&state, Chain::L2)?;
+
+    let cooldown_keys = [
+        CooldownKey::Ip(ip),
+        CooldownKey::Address(address.to_string()),
+    ];
+    check_cooldown(&state, Chain::L2, &cooldown_keys)?;
+
+    check_solution(
+        Chain::L2,
+        &ip,
+        &address.to_string(),
+        &solution.0,
+        params.token,
+    )?;
+
+    start_cooldown(&state, Chain::L2, &cooldown_keys)?;
+    record_claim(&state, Chain::L2, ip, &address.to_string());
+
+    let id = state.batcher.claims().register(Chain::L2);
+    // batched payouts can take a whole batching period to be sent, so return
+    // the claim ID straight away for the client to track instead of waiting
+    // for the txid
+    let batching = SETTINGS.batcher.l2_batching;
+    let (reply, txid) = oneshot::channel();
+    let send_error = || {
+        state.batcher.claims().set_state(
+            &id,
+            ClaimState::Failed {
+                reason: "failed to send payout transaction".to_owned(),
+            },
+        );
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "error sending tx")
+    };
+    if let Err(e) = state
+        .batcher
+        .queue_payout_request(PayoutRequest::L2(L2PayoutRequest {
+            id,
+            address,
+            // 1 btc == 1 "eth" => 1 sat = 1e10 "wei"
+            amount: U256::from(state.l2.config().amount_per_claim.to_sat() * SATS_TO_WEI),
+            reply: (!batching).then_some(reply),
+        }))
+        .await
+    {
+        error!("failed queuing payout: {e:?}");
+        return Err(send_error());
+    }
+    if batching {
+        return Ok(Hex(id).to_string());
+    }
+
+    match txid.await {
+        Ok(Ok(txid)) => Ok(txid.to_string()),
+        _ => Err(send_error()),
+    }
+}
+
+/// Parses a recipient address for the given chain and returns it in the
+/// canonical form used when binding challenges to it.
+fn normalize_address(chain: Chain, address: &str) -> Result<String, (StatusCode, String)> {
+    let bad_address = |e: String| (StatusCode::BAD_REQUEST, format!("invalid address: {e}"));
+    match chain {
+        Chain::L1 => address
+            .parse::<L1Address<NetworkUnchecked>>()
+            .map_err(|e| bad_address(e.to_string()))?
+            .require_network(SETTINGS.network)
+            .map(|address| address.to_string())
+            .map_err(|_| bad_address("wrong address network type".to_string())),
+        Chain::L2 => address
+            .parse::<L2Address>()
+            .map(|address| address.to_string())
+            .map_err(|e| bad_address(e.to_string())),
+    }
+}
+
+/// Validates the client's proof of work solution against its outstanding
+/// challenge.
+fn check_solution(
+    chain: Chain,
+    ip: &IpBucket,
+    address: &str,
+    solution: &[u8],
+    token: Option<Hex<Vec<u8>>>,
+) -> Result<(), ApiError> {
+    let Some(solution) = pow::parse_solution(solution) else {
+        return Err(ApiError::new(
             StatusCode::BAD_REQUEST,
-            "IPV6 is not unavailable".to_string(),
+            "The solution must be one or more 8 byte shares, hex encoded.",
         ));
     };
+    let require_binding = SETTINGS.require_address_binding;
+    // memory-hard hashes take a while to check, so let the runtime move other
+    // tasks off this worker in the meantime
+    let res = match SETTINGS.challenge_mode {
+        ChallengeMode::Stateful => task::block_in_place(|| {
+            Challenge::check_solution(chain, ip, address, &solution, require_binding)
+        })
+        .map_err(|e| {
+            let label = pow::rejection_label(&e);
+            (label, StatusCode::BAD_REQUEST, e.to_string())
+        }),
+        ChallengeMode::Stateless => {
+            let Some(token) = token else {
+                metrics::POW_FAILURES
+                    .with_label_values(&[chain.as_str(), "missing_token"])
+                    .inc();
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "missing challenge token",
+                ));
+            };
+            task::block_in_place(|| {
+                ChallengeToken::check_solution(
+                    chain,
+                    ip,
+                    address,
+                    &token,
+                    &solution,
+                    require_binding,
+                )
+            })
+            .map_err(|e| {
+                let label = challenge_token::rejection_label(&e);
+                let message = e.to_string();
+                let status = match e.narrow::<SpentTokensUnavailable, _>() {
+                    Ok(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    Err(_) => StatusCode::BAD_REQUEST,
+                };
+                (label, status, message)
+            })
+        }
+    };
+    match res {
+        Ok(()) => {
+            metrics::CHALLENGES_SOLVED
+                .with_label_values(&[chain.as_str()])
+                .inc();
+            Ok(())
+        }
+        Err((label, status, e)) => {
+            metrics::POW_FAILURES
+                .with_label_values(&[chain.as_str(), label])
+                .inc();
+            Err(ApiError::new(status, e))
+        }
+    }
+}
 
-    // num hashes on average to solve challenge: 2^15
-    if let Err(e) = Challenge::check_solution(Chain::L2, &ip, solution.0) {
-        return Err((StatusCode::BAD_REQUEST, e.to_string()));
+/// Rejects banned clients.
+fn check_ban(ip: &IpBucket) -> Result<(), ApiError> {
+    match bans::is_banned(ip) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "You have been banned from the faucet.",
+        )),
+        Err(e) => {
+            error!("error checking bans: {e:?}");
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "error checking bans",
+            ))
+        }
     }
+}
 
-    let tx = TransactionRequest::default()
-        .with_to(address)
-        // 1 btc == 1 "eth" => 1 sat = 1e10 "wei"
-        .with_value(U256::from(
-            SETTINGS.l2.amount_per_claim.to_sat() * SATS_TO_WEI,
+/// Rejects challenge requests and claims while the chain is paused.
+fn check_paused(state: &AppState, chain: Chain) -> Result<(), ApiError> {
+    if state.layer(chain).is_paused() {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "{} claims are paused. Please try again later.",
+                chain.as_str().to_uppercase()
+            ),
         ));
+    }
+    Ok(())
+}
+
+fn cooldown_error(chain: Chain, remaining: Duration) -> ApiError {
+    ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        format!(
+            "You have already claimed {} funds recently. Please try again in {} seconds.",
+            chain.as_str().to_uppercase(),
+            remaining.as_secs().max(1)
+        ),
+    )
+    .with_retry_after(remaining)
+}
 
-    let txid = match state.l2_wallet.send_transaction(tx).await {
-        Ok(r) => *r.tx_hash(),
+/// Rejects the claim if any of the `keys` are still cooling down from a
+/// previous claim.
+fn check_cooldown(state: &AppState, chain: Chain, keys: &[CooldownKey]) -> Result<(), ApiError> {
+    if state.layer(chain).config().cooldown.is_none() {
+        return Ok(());
+    }
+    match cooldown::remaining(chain, keys) {
+        Ok(None) => Ok(()),
+        Ok(Some(remaining)) => Err(cooldown_error(chain, remaining)),
         Err(e) => {
-            error!("error sending transaction: {e:?}");
-            return Err((
+            error!("error checking cooldown: {e:?}");
+            Err(ApiError::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "error sending tx".to_owned(),
-            ));
+                "error checking cooldown",
+            ))
         }
+    }
+}
+
+/// Starts the cooldown for all the `keys`, rejecting the claim if another
+/// claim started one first.
+fn start_cooldown(state: &AppState, chain: Chain, keys: &[CooldownKey]) -> Result<(), ApiError> {
+    let Some(duration) = state.layer(chain).config().cooldown else {
+        return Ok(());
     };
+    match cooldown::start(chain, keys, duration) {
+        Ok(None) => Ok(()),
+        Ok(Some(remaining)) => Err(cooldown_error(chain, remaining)),
+        Err(e) => {
+            error!("error starting cooldown: {e:?}");
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "error starting cooldown",
+            ))
+        }
+    }
+}
 
-    info!("l2 claim to {address} via tx {}", txid);
+/// Keys the claim history of a client is kept under: its IP bucket and, if
+/// the layer is configured to, the recipient address.
+fn history_keys(config: &LayerConfig, ip: IpBucket, address: Option<&str>) -> Vec<CooldownKey> {
+    let mut keys = vec![CooldownKey::Ip(ip)];
+    if let Some(address) = address.filter(|_| config.reputation.by_address) {
+        keys.push(CooldownKey::Address(address.to_owned()));
+    }
+    keys
+}
+
+/// Counts a successful claim towards the chain's claim rate and the client's
+/// claim history.
+fn record_claim(state: &AppState, chain: Chain, ip: IpBucket, address: &str) {
+    let layer = state.layer(chain);
+    layer.record_claim();
+    let config = layer.config();
+    state.claim_history.record(
+        chain,
+        &history_keys(&config, ip, Some(address)),
+        config.reputation.half_life,
+    );
+}
 
-    Ok(txid.to_string())
+#[derive(Debug, Serialize)]
+pub struct ClaimStatus {
+    chain: Chain,
+    #[serde(flatten)]
+    state: ClaimState,
+    /// 0-indexed position in the payout queue while the claim is queued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<usize>,
+}
+
+async fn get_claim(
+    Path(id): Path<Hex<ClaimId>>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ClaimStatus>, (StatusCode, String)> {
+    let claims = state.batcher.claims();
+    let Some(record) = claims.get(&id) else {
+        return Err((StatusCode::NOT_FOUND, "claim not found".to_string()));
+    };
+
+    let mut claim_state = record.state;
+    // confirmations are picked up by the wallet syncer, so check the wallet
+    // for any broadcast claims
+    if let ClaimState::Broadcast {
+        txid: PayoutTxid::L1(txid),
+    } = claim_state
+    {
+        // any version of a batch that was replaced can be the one that
+        // confirms
+        let confirmed = {
+            let l1w = state.l1_wallet.read();
+            state
+                .batcher
+                .sent_batches()
+                .confirmed(txid, |txid| confirmed_height(&l1w, txid))
+        };
+        if let Some((txid, height)) = confirmed {
+            claim_state = ClaimState::Confirmed {
+                txid: txid.into(),
+                height: height.into(),
+            };
+            claims.set_state(&id, claim_state.clone());
+        }
+    }
+
+    let position = match claim_state {
+        ClaimState::Queued => state.batcher.queue_position(&id),
+        _ => None,
+    };
+
+    Ok(Json(ClaimStatus {
+        chain: record.chain,
+        state: claim_state,
+        position,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    /// Number of L1 claims waiting to be paid out
+    depth: usize,
+    /// Maximum number of L1 claims that can wait to be paid out
+    capacity: usize,
+}
+
+async fn get_queue(State(state): State<Arc<AppState>>) -> Json<QueueStatus> {
+    Json(QueueStatus {
+        depth: state.batcher.queue_depth(),
+        capacity: state.batcher.queue_capacity(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeeRateStatus {
+    /// The configured fee policy
+    policy: &'static FeeConfig,
+    /// Fee rate for the confirmation target, in sat/vB
+    sat_per_vb: f64,
+    /// Fee rate for the economy confirmation target, in sat/vB
+    economy_sat_per_vb: f64,
+    /// Fee rate the next batch would be built with, in sat/vB
+    batch_sat_per_vb: f64,
+    /// Whether the rates are the configured fallback rather than estimates
+    fallback: bool,
+}
+
+async fn get_fee_rate(State(state): State<Arc<AppState>>) -> Json<FeeRateStatus> {
+    let sat_per_vb = |fr: FeeRate| fr.to_sat_per_kwu() as f64 / 250.0;
+    let urgent = state.batcher.is_urgent();
+    Json(FeeRateStatus {
+        policy: &SETTINGS.fee,
+        sat_per_vb: sat_per_vb(l1::fee_rate()),
+        economy_sat_per_vb: sat_per_vb(l1::economy_fee_rate()),
+        batch_sat_per_vb: sat_per_vb(l1::batch_fee_rate(urgent)),
+        fallback: l1::using_fallback_fee_rate(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct EndpointsStatus {
+    /// esplora endpoints, empty when using bitcoind
+    l1: Vec<EndpointStatus>,
+    l2: Vec<EndpointStatus>,
+}
+
+async fn get_endpoints(State(state): State<Arc<AppState>>) -> Json<EndpointsStatus> {
+    Json(EndpointsStatus {
+        l1: CHAIN_SOURCE.status(),
+        l2: state.l2_wallet.endpoint_status(),
+    })
+}
+
+/// The process is up and serving requests.
+async fn get_healthz() -> &'static str {
+    "ok"
+}
+
+/// Whether the faucet can pay out claims, with a breakdown of what's degraded
+/// if it can't.
+async fn get_readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let readiness = health::readiness(&state).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Metrics in the prometheus text format.
+async fn get_metrics(State(state): State<Arc<AppState>>) -> String {
+    // gauges that are cheap to read are refreshed on every scrape
+    metrics::QUEUE_DEPTH.set(state.batcher.queue_depth() as i64);
+    metrics::FEE_RATE.set(l1::fee_rate().to_sat_per_kwu() as f64 / 250.0);
+    let l1_balance = state.l1_wallet.read().balance().trusted_spendable();
+    metrics::BALANCE
+        .with_label_values(&[Chain::L1.as_str()])
+        .set(l1_balance.to_sat() as i64);
+    metrics::render()
 }
 
 async fn get_balance(
@@ -313,13 +875,13 @@ async fn get_balance(
     Ok(bal)
 }
 
-async fn get_sats_per_claim(Path(chain): Path<String>) -> Result<String, (StatusCode, String)> {
+async fn get_sats_per_claim(
+    State(state): State<Arc<AppState>>,
+    Path(chain): Path<String>,
+) -> Result<String, (StatusCode, String)> {
     let claim_level = Chain::try_from(chain.as_str())?;
 
-    let sats = match claim_level {
-        Chain::L1 => SETTINGS.l1.amount_per_claim.to_sat(),
-        Chain::L2 => SETTINGS.l2.amount_per_claim.to_sat(),
-    };
+    let sats = state.layer(claim_level).config().amount_per_claim.to_sat();
 
     Ok(sats.to_string())
 }
@@ -0,0 +1,226 @@
+//! Prometheus metrics, served on `/metrics`.
+
+use std::{
+    future::Future,
+    sync::LazyLock,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use alloy::{
+    rpc::json_rpc::{RequestPacket, ResponsePacket},
+    transports::{TransportError, TransportFut},
+};
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_histogram_vec,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Gauge,
+    Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use tower::{Layer, Service};
+
+pub static CHALLENGES_ISSUED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "faucet_challenges_issued_total",
+        "Proof of work challenges handed out",
+        &["chain"]
+    )
+    .expect("valid metric")
+});
+
+pub static CHALLENGES_SOLVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "faucet_challenges_solved_total",
+        "Proof of work solutions accepted",
+        &["chain"]
+    )
+    .expect("valid metric")
+});
+
+pub static POW_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "faucet_pow_failures_total",
+        "Proof of work solutions rejected, by reason",
+        &["chain", "error"]
+    )
+    .expect("valid metric")
+});
+
+pub static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("faucet_l1_queue_depth", "L1 payouts waiting to be batched")
+        .expect("valid metric")
+});
+
+pub static BATCH_SIZE: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "faucet_l1_batch_size",
+        "Payouts per L1 batch transaction",
+        exponential_buckets(1.0, 2.0, 10).expect("valid buckets")
+    )
+    .expect("valid metric")
+});
+
+pub static SATS_DISPENSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "faucet_sats_dispensed_total",
+        "Sats sent out in payout transactions",
+        &["chain"]
+    )
+    .expect("valid metric")
+});
+
+pub static DIFFICULTY: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "faucet_difficulty",
+        "Difficulty of the last challenge handed out",
+        &["chain"]
+    )
+    .expect("valid metric")
+});
+
+pub static BALANCE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "faucet_balance_sats",
+        "Last known spendable balance of the faucet",
+        &["chain"]
+    )
+    .expect("valid metric")
+});
+
+pub static FEE_RATE: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "faucet_l1_fee_rate_sat_per_vb",
+        "Fee rate for the L1 confirmation target"
+    )
+    .expect("valid metric")
+});
+
+pub static RPC_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "faucet_rpc_duration_seconds",
+        "Time taken by requests to the chain backends",
+        &["backend", "method"]
+    )
+    .expect("valid metric")
+});
+
+pub static RPC_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "faucet_rpc_errors_total",
+        "Failed requests to the chain backends",
+        &["backend", "method"]
+    )
+    .expect("valid metric")
+});
+
+/// Registers all the metrics, so they're reported before anything is
+/// recorded.
+pub fn init() {
+    LazyLock::force(&CHALLENGES_ISSUED);
+    LazyLock::force(&CHALLENGES_SOLVED);
+    LazyLock::force(&POW_FAILURES);
+    LazyLock::force(&QUEUE_DEPTH);
+    LazyLock::force(&BATCH_SIZE);
+    LazyLock::force(&SATS_DISPENSED);
+    LazyLock::force(&DIFFICULTY);
+    LazyLock::force(&BALANCE);
+    LazyLock::force(&FEE_RATE);
+    LazyLock::force(&RPC_LATENCY);
+    LazyLock::force(&RPC_ERRORS);
+}
+
+/// Renders the metrics in the prometheus text format.
+pub fn render() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("encoding metrics to succeed");
+    String::from_utf8(buf).expect("metrics are utf-8")
+}
+
+/// Times a request to a chain backend, counting it as an error if it fails.
+pub async fn observe_rpc<T, E>(
+    backend: &str,
+    method: &str,
+    request: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let res = request.await;
+    record_rpc(backend, method, start, res.is_err());
+    res
+}
+
+fn record_rpc(backend: &str, method: &str, start: Instant, failed: bool) {
+    RPC_LATENCY
+        .with_label_values(&[backend, method])
+        .observe(start.elapsed().as_secs_f64());
+    if failed {
+        RPC_ERRORS.with_label_values(&[backend, method]).inc();
+    }
+}
+
+/// Transport layer recording the latency and errors of L2 RPC requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetricsService(inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcMetricsService<S>(S);
+
+impl<S> Service<RequestPacket> for RpcMetricsService<S>
+where
+    S: Service<
+        RequestPacket,
+        Response = ResponsePacket,
+        Error = TransportError,
+        Future = TransportFut<'static>,
+    >,
+{
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: RequestPacket) -> Self::Future {
+        let method = match &req {
+            RequestPacket::Single(req) => req.method().to_owned(),
+            RequestPacket::Batch(_) => "batch".to_owned(),
+        };
+        let start = Instant::now();
+        let res = self.0.call(req);
+        Box::pin(async move {
+            let res = res.await;
+            let failed = res.as_ref().map_or(true, ResponsePacket::is_error);
+            record_rpc("l2", &method, start, failed);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        init();
+        CHALLENGES_ISSUED.with_label_values(&["l1"]).inc();
+        observe_rpc("esplora", "test", async { Err::<(), _>(()) })
+            .await
+            .unwrap_err();
+
+        let rendered = render();
+        assert!(rendered.contains("faucet_challenges_issued_total{chain=\"l1\"}"));
+        assert!(rendered.contains("faucet_rpc_errors_total{backend=\"esplora\",method=\"test\"} 1"));
+        assert!(rendered.contains("# TYPE faucet_l1_batch_size histogram"));
+    }
+}
@@ -0,0 +1,388 @@
+//! Durable storage for queued L1 payouts.
+//!
+//! Every accepted L1 claim is written to the wallet's sqlite database before
+//! the claim is acknowledged, and is only removed once the transaction paying
+//! it has been broadcast or it has failed too many times. This way, claims
+//! survive crashes and restarts of the faucet.
+//!
+//! Batch transactions are stored along with their payouts before they're
+//! broadcast, so that whether they went out can still be checked after a
+//! restart. Once a batch is out, its claims and the fee it first paid are
+//! kept until one of its versions confirms, so that fee bumping picks up
+//! where it left off after a restart.
+
+use bdk_wallet::{
+    bitcoin::{consensus, Address, Amount, Transaction, Txid},
+    rusqlite::{self, params, Connection},
+};
+
+use crate::{
+    batcher::L1PayoutRequest,
+    claims::ClaimId,
+    db::{self, bad_data, now_secs},
+    fee_bump::SentBatch,
+};
+
+const MARK_BROADCASTING: &str = "UPDATE faucet_l1_payouts SET txid = ?2 WHERE id = ?1";
+const REMOVE: &str = "DELETE FROM faucet_l1_payouts WHERE id = ?1";
+const RECORD_FAILED_ATTEMPT: &str =
+    "UPDATE faucet_l1_payouts SET attempts = attempts + 1, txid = NULL WHERE id = ?1";
+
+/// A payout loaded back from the store.
+#[derive(Debug)]
+pub struct StoredPayout {
+    pub request: L1PayoutRequest,
+    pub attempts: u32,
+    /// Transaction the payout was about to be broadcast in, if the faucet
+    /// stopped before it knew whether the broadcast succeeded.
+    pub tx: Option<Transaction>,
+}
+
+/// Creates the payout tables if they don't exist yet.
+pub fn init() -> rusqlite::Result<()> {
+    db::with(|db| init_in(db))
+}
+
+fn init_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS faucet_l1_payouts (
+            id BLOB PRIMARY KEY NOT NULL,
+            address TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            txid TEXT,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS faucet_l1_batches (
+            txid TEXT PRIMARY KEY NOT NULL,
+            tx BLOB NOT NULL
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS faucet_l1_sent_batches (
+            batch TEXT PRIMARY KEY NOT NULL,
+            original_fee INTEGER NOT NULL,
+            claims BLOB NOT NULL
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS faucet_l1_sent_txids (
+            txid TEXT PRIMARY KEY NOT NULL,
+            batch TEXT NOT NULL
+        );",
+    )
+}
+
+/// Adds a newly accepted payout to the store.
+pub fn insert(req: &L1PayoutRequest) -> rusqlite::Result<()> {
+    db::with(|db| insert_in(db, req))
+}
+
+fn insert_in(db: &Connection, req: &L1PayoutRequest) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO faucet_l1_payouts (id, address, amount, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            req.id.as_slice(),
+            req.address.to_string(),
+            req.amount.to_sat(),
+            now_secs()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Records that the payouts are about to be broadcast in `tx`.
+pub fn mark_broadcasting(ids: &[ClaimId], tx: &Transaction) -> rusqlite::Result<()> {
+    db::with(|db| mark_broadcasting_in(db, ids, tx))
+}
+
+fn mark_broadcasting_in(
+    db: &mut Connection,
+    ids: &[ClaimId],
+    tx: &Transaction,
+) -> rusqlite::Result<()> {
+    let txid = tx.compute_txid();
+    let db_tx = db.transaction()?;
+    db_tx.execute(
+        "INSERT OR REPLACE INTO faucet_l1_batches (txid, tx) VALUES (?1, ?2)",
+        params![txid.to_string(), consensus::serialize(tx)],
+    )?;
+    update_many_in(&db_tx, MARK_BROADCASTING, ids, Some(txid))?;
+    db_tx.commit()
+}
+
+/// Records that the payouts went out in the batch `txid`, which paid
+/// `original_fee`, and removes them from the queued payouts.
+pub fn mark_sent(ids: &[ClaimId], txid: Txid, original_fee: Amount) -> rusqlite::Result<()> {
+    db::with(|db| mark_sent_in(db, ids, txid, original_fee))
+}
+
+fn mark_sent_in(
+    db: &mut Connection,
+    ids: &[ClaimId],
+    txid: Txid,
+    original_fee: Amount,
+) -> rusqlite::Result<()> {
+    let db_tx = db.transaction()?;
+    db_tx.execute(
+        "INSERT OR REPLACE INTO faucet_l1_sent_batches (batch, original_fee, claims)
+        VALUES (?1, ?2, ?3)",
+        params![txid.to_string(), original_fee.to_sat(), ids.concat()],
+    )?;
+    insert_replacement_in(&db_tx, txid, txid)?;
+    update_many_in(&db_tx, REMOVE, ids, None)?;
+    remove_orphan_batches_in(&db_tx)?;
+    db_tx.commit()
+}
+
+/// Records `txid` as another version of the sent `batch`, about to be
+/// broadcast to replace the earlier ones.
+pub fn insert_replacement(batch: Txid, txid: Txid) -> rusqlite::Result<()> {
+    db::with(|db| insert_replacement_in(db, batch, txid))
+}
+
+fn insert_replacement_in(db: &Connection, batch: Txid, txid: Txid) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT OR IGNORE INTO faucet_l1_sent_txids (txid, batch) VALUES (?1, ?2)",
+        params![txid.to_string(), batch.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Removes sent batches that have confirmed or are gone from the wallet.
+pub fn remove_sent(batches: &[Txid]) -> rusqlite::Result<()> {
+    db::with(|db| remove_sent_in(db, batches))
+}
+
+fn remove_sent_in(db: &mut Connection, batches: &[Txid]) -> rusqlite::Result<()> {
+    let db_tx = db.transaction()?;
+    for batch in batches {
+        db_tx.execute(
+            "DELETE FROM faucet_l1_sent_batches WHERE batch = ?1",
+            [batch.to_string()],
+        )?;
+        db_tx.execute(
+            "DELETE FROM faucet_l1_sent_txids WHERE batch = ?1",
+            [batch.to_string()],
+        )?;
+    }
+    db_tx.commit()
+}
+
+/// Removes payouts that have been paid out or given up on.
+pub fn remove(ids: &[ClaimId]) -> rusqlite::Result<()> {
+    db::with(|db| update_many(db, REMOVE, ids, None))
+}
+
+/// Records a failed attempt at paying out, so the payouts can be retried.
+pub fn record_failed_attempt(ids: &[ClaimId]) -> rusqlite::Result<()> {
+    db::with(|db| update_many(db, RECORD_FAILED_ATTEMPT, ids, None))
+}
+
+/// Runs `sql` for each of the payouts, then drops batches that no payout is
+/// waiting on anymore.
+fn update_many(
+    db: &mut Connection,
+    sql: &str,
+    ids: &[ClaimId],
+    txid: Option<Txid>,
+) -> rusqlite::Result<()> {
+    let db_tx = db.transaction()?;
+    update_many_in(&db_tx, sql, ids, txid)?;
+    remove_orphan_batches_in(&db_tx)?;
+    db_tx.commit()
+}
+
+fn remove_orphan_batches_in(db: &Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "DELETE FROM faucet_l1_batches WHERE txid NOT IN
+            (SELECT txid FROM faucet_l1_payouts WHERE txid IS NOT NULL)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn update_many_in(
+    db: &Connection,
+    sql: &str,
+    ids: &[ClaimId],
+    txid: Option<Txid>,
+) -> rusqlite::Result<()> {
+    let mut stmt = db.prepare_cached(sql)?;
+    for id in ids {
+        match txid {
+            Some(txid) => stmt.execute(params![id.as_slice(), txid.to_string()])?,
+            None => stmt.execute([id.as_slice()])?,
+        };
+    }
+    Ok(())
+}
+
+/// Loads all outstanding payouts, oldest first.
+pub fn load() -> rusqlite::Result<Vec<StoredPayout>> {
+    db::with(|db| load_from(db))
+}
+
+fn load_from(db: &Connection) -> rusqlite::Result<Vec<StoredPayout>> {
+    let mut stmt = db.prepare(
+        "SELECT p.id, p.address, p.amount, p.attempts, b.tx FROM faucet_l1_payouts p
+        LEFT JOIN faucet_l1_batches b ON b.txid = p.txid
+        ORDER BY p.created_at, p.rowid",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let id: Vec<u8> = row.get(0)?;
+        let address: String = row.get(1)?;
+        let tx: Option<Vec<u8>> = row.get(4)?;
+        Ok((id, address, row.get::<_, u64>(2)?, row.get(3)?, tx))
+    })?;
+
+    let mut payouts = Vec::new();
+    for row in rows {
+        let (id, address, amount, attempts, tx) = row?;
+        let id = ClaimId::try_from(id).map_err(|_| bad_data("invalid claim id"))?;
+        let address = address
+            .parse::<Address<_>>()
+            .map_err(bad_data)?
+            .assume_checked();
+        let tx = tx
+            .map(|tx| consensus::deserialize::<Transaction>(&tx))
+            .transpose()
+            .map_err(bad_data)?;
+        payouts.push(StoredPayout {
+            request: L1PayoutRequest {
+                id,
+                address,
+                amount: Amount::from_sat(amount),
+            },
+            attempts,
+            tx,
+        });
+    }
+    Ok(payouts)
+}
+
+/// Loads the batches that went out and haven't confirmed yet.
+pub fn load_sent() -> rusqlite::Result<Vec<SentBatch>> {
+    db::with(|db| load_sent_from(db))
+}
+
+fn load_sent_from(db: &Connection) -> rusqlite::Result<Vec<SentBatch>> {
+    let mut stmt = db.prepare(
+        "SELECT b.batch, b.original_fee, b.claims, t.txid FROM faucet_l1_sent_batches b
+        JOIN faucet_l1_sent_txids t ON t.batch = b.batch
+        ORDER BY b.batch, t.rowid",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let batch: String = row.get(0)?;
+        let claims: Vec<u8> = row.get(2)?;
+        let txid: String = row.get(3)?;
+        Ok((batch, row.get::<_, u64>(1)?, claims, txid))
+    })?;
+
+    let mut batches = Vec::<(String, SentBatch)>::new();
+    for row in rows {
+        let (batch, original_fee, claims, txid) = row?;
+        let txid = txid.parse::<Txid>().map_err(bad_data)?;
+        if let Some((last, sent)) = batches.last_mut() {
+            if *last == batch {
+                sent.txids.push(txid);
+                continue;
+            }
+        }
+        let claims = claims
+            .chunks(16)
+            .map(|id| ClaimId::try_from(id).map_err(|_| bad_data("invalid claim id")))
+            .collect::<rusqlite::Result<_>>()?;
+        batches.push((
+            batch,
+            SentBatch {
+                claims,
+                original_fee: Amount::from_sat(original_fee),
+                txids: vec![txid],
+            },
+        ));
+    }
+    Ok(batches.into_iter().map(|(_, sent)| sent).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bdk_wallet::bitcoin::{absolute::LockTime, hashes::Hash, transaction::Version};
+
+    use super::*;
+
+    fn req(id: u8) -> L1PayoutRequest {
+        L1PayoutRequest {
+            id: [id; 16],
+            address: Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+                .unwrap()
+                .assume_checked(),
+            amount: Amount::from_sat(1000 * u64::from(id)),
+        }
+    }
+
+    #[test]
+    fn test_payout_store() {
+        let mut db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+
+        for id in 1..=3 {
+            insert_in(&db, &req(id)).unwrap();
+        }
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        mark_broadcasting_in(&mut db, &[[1; 16], [3; 16]], &tx).unwrap();
+        update_many(&mut db, REMOVE, &[[2; 16]], None).unwrap();
+        update_many(&mut db, RECORD_FAILED_ATTEMPT, &[[3; 16]], None).unwrap();
+
+        let payouts = load_from(&db).unwrap();
+        assert_eq!(payouts.len(), 2);
+        assert_eq!(payouts[0].request.id, [1; 16]);
+        assert_eq!(payouts[0].request.amount, Amount::from_sat(1000));
+        assert_eq!(payouts[0].tx, Some(tx));
+        assert_eq!(payouts[1].request.id, [3; 16]);
+        assert_eq!(payouts[1].request.address, req(3).address);
+        // failed attempts clear the transaction
+        assert_eq!(payouts[1].attempts, 1);
+        assert_eq!(payouts[1].tx, None);
+
+        // batches are dropped once no payout is waiting on them
+        update_many(&mut db, REMOVE, &[[1; 16]], None).unwrap();
+        let batches: u32 = db
+            .query_row("SELECT COUNT(*) FROM faucet_l1_batches", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(batches, 0);
+    }
+
+    #[test]
+    fn test_sent_batches() {
+        let mut db = Connection::open_in_memory().unwrap();
+        init_in(&db).unwrap();
+
+        for id in 1..=3 {
+            insert_in(&db, &req(id)).unwrap();
+        }
+        let [first, bumped, other] = [1, 2, 3].map(|i| Txid::from_byte_array([i; 32]));
+        mark_sent_in(&mut db, &[[1; 16], [2; 16]], first, Amount::from_sat(500)).unwrap();
+        insert_replacement_in(&db, first, bumped).unwrap();
+        mark_sent_in(&mut db, &[[3; 16]], other, Amount::from_sat(300)).unwrap();
+        remove_sent_in(&mut db, &[other]).unwrap();
+
+        // sent payouts are no longer queued
+        assert!(load_from(&db).unwrap().is_empty());
+        // the fee the batch first paid is kept for every version of it
+        let sent = load_sent_from(&db).unwrap();
+        assert_eq!(
+            sent,
+            [SentBatch {
+                claims: vec![[1; 16], [2; 16]],
+                original_fee: Amount::from_sat(500),
+                txids: vec![first, bumped],
+            }]
+        );
+    }
+}
@@ -1,7 +1,6 @@
 use std::{
     cmp,
     collections::BinaryHeap,
-    net::Ipv4Addr,
     rc::Rc,
     sync::{
         atomic::{AtomicU64, Ordering},
@@ -15,19 +14,39 @@ use concurrent_map::{CasFailure, ConcurrentMap};
 use kanal::Sender;
 use parking_lot::{Mutex, MutexGuard};
 use rand::{rng, Rng};
-use sha2::{Digest, Sha256};
-use terrors::OneOf;
+use serde::{Deserialize, Serialize};
+use terrors::{OneOf, E5};
 use tokio::{select, time::sleep};
 use tracing::debug;
 
-use crate::{display_err, err, Chain};
+use crate::{display_err, err, ip_bucket::IpBucket, pow_algorithm::PowAlgorithm, Chain};
+
+/// Where outstanding challenges are kept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMode {
+    /// Challenges are kept in memory by the faucet. Each client has at most
+    /// one outstanding challenge per chain.
+    #[default]
+    Stateful,
+    /// Challenges are handed to the client as signed tokens (see
+    /// [`ChallengeToken`](crate::challenge_token::ChallengeToken)) that the
+    /// client sends back with its solution.
+    Stateless,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Challenge {
     nonce: Nonce,
     claimed: bool,
     expires_at: Instant,
+    /// Difficulty each share has to meet
     difficulty: u8,
+    algorithm: PowAlgorithm,
+    /// Number of shares the solution has to consist of
+    shares: u8,
+    /// Recipient address the challenge is bound to, if any
+    address: Option<String>,
 }
 
 /// Tokens already claimed within the challenge duration.
@@ -38,6 +57,23 @@ display_err!(
     "You have already claimed tokens from the faucet. Please wait and try again."
 );
 
+/// The challenge is bound to a different recipient address.
+#[derive(Debug)]
+pub struct AddressMismatch;
+display_err!(
+    AddressMismatch,
+    "This challenge was issued for a different address."
+);
+
+/// The challenge isn't bound to a recipient address, but the faucet requires
+/// one.
+#[derive(Debug)]
+pub struct UnboundChallenge;
+display_err!(
+    UnboundChallenge,
+    "This challenge isn't bound to an address. Please request a new challenge for your address."
+);
+
 /// Proof of Work is invalid.
 #[derive(Debug)]
 pub struct BadProofOfWork;
@@ -54,24 +90,51 @@ display_err!(
     "Proof of Work took too long. The challenge is no longer valid."
 );
 
+/// Ways a client's proof of work solution can be rejected.
+pub type CheckSolutionError = OneOf<(
+    NonceNotFound,
+    BadProofOfWork,
+    AlreadyClaimed,
+    AddressMismatch,
+    UnboundChallenge,
+)>;
+
+/// Short name of the reason a solution was rejected, used in metrics.
+pub fn rejection_label(e: &CheckSolutionError) -> &'static str {
+    match e.as_enum() {
+        E5::A(NonceNotFound) => "nonce_not_found",
+        E5::B(BadProofOfWork) => "bad_proof_of_work",
+        E5::C(AlreadyClaimed) => "already_claimed",
+        E5::D(AddressMismatch) => "address_mismatch",
+        E5::E(UnboundChallenge) => "unbound_challenge",
+    }
+}
+
 impl Challenge {
-    /// Retrieves a proof-of-work challenge for the given Ipv4 address.
+    /// Retrieves a proof-of-work challenge for the given [`IpBucket`].
     ///
-    /// Note that this doesn't support IPv6 yet because those IPs are a lot
-    /// easier to get.
+    /// If `address` is provided, a new challenge will be bound to that
+    /// recipient address. Note that if the client already has an outstanding
+    /// challenge, that one is returned as is, with whatever binding it has.
     pub fn get(
         chain: Chain,
-        ip: &Ipv4Addr,
+        ip: &IpBucket,
         difficulty_if_not_present: u8,
+        algorithm_if_not_present: PowAlgorithm,
+        shares_if_not_present: u8,
         challenge_duration: Duration,
+        address: Option<String>,
     ) -> Self {
         let challenge = Self {
             nonce: rng().random(),
             claimed: false,
             expires_at: Instant::now() + challenge_duration,
             difficulty: difficulty_if_not_present,
+            algorithm: algorithm_if_not_present,
+            shares: shares_if_not_present,
+            address,
         };
-        match challenge_set().cas((ip.to_bits(), chain), None, Some(challenge.clone())) {
+        match challenge_set().cas((*ip, chain), None, Some(challenge.clone())) {
             Ok(None) => {
                 EVICTION_Q.add_challenge(&challenge, *ip, chain);
                 challenge
@@ -88,16 +151,21 @@ impl Challenge {
         }
     }
 
-    /// Validates the proof of work solution by the client.
+    /// Validates the proof of work solution by the client, who is claiming
+    /// funds to `address`.
+    ///
+    /// Unless `require_binding` is set, challenges that aren't bound to an
+    /// address are still accepted.
     pub fn check_solution(
         chain: Chain,
-        ip: &Ipv4Addr,
-        solution: Solution,
-    ) -> Result<(), OneOf<(NonceNotFound, BadProofOfWork, AlreadyClaimed)>> {
+        ip: &IpBucket,
+        address: &str,
+        solution: &[Share],
+        require_binding: bool,
+    ) -> Result<(), CheckSolutionError> {
         let challenge_set = challenge_set();
-        let raw_ip = ip.to_bits();
 
-        let Some(old_challenge) = challenge_set.get(&(raw_ip, chain)) else {
+        let Some(old_challenge) = challenge_set.get(&(*ip, chain)) else {
             return err!(NonceNotFound);
         };
 
@@ -105,6 +173,9 @@ impl Challenge {
             return err!(AlreadyClaimed);
         }
 
+        check_binding(old_challenge.address.as_deref(), address, require_binding)
+            .map_err(OneOf::broaden)?;
+
         let mut replacement_challenge = old_challenge.clone();
         replacement_challenge.claimed = true;
 
@@ -116,7 +187,7 @@ impl Challenge {
         // This also acts as a gate against race conditions and ensures that
         // only one client can claim a nonce at a time.
         match challenge_set.cas(
-            (ip.to_bits(), chain),
+            (*ip, chain),
             Some(&old_challenge),
             Some(replacement_challenge),
         ) {
@@ -127,18 +198,28 @@ impl Challenge {
             Err(_) => return err!(AlreadyClaimed),
         }
 
-        let mut hasher = Sha256::new();
-        hasher.update(b"alpen faucet 2024");
-        hasher.update(old_challenge.nonce);
-        hasher.update(solution);
-
-        if count_leading_zeros(&hasher.finalize()) >= old_challenge.difficulty {
+        if check_shares(
+            &old_challenge.nonce,
+            old_challenge.difficulty,
+            &old_challenge.algorithm,
+            old_challenge.shares,
+            old_challenge.address.as_deref(),
+            solution,
+        ) {
             Ok(())
         } else {
             err!(BadProofOfWork)
         }
     }
 
+    /// All challenges that haven't been evicted yet, claimed or not.
+    pub fn outstanding() -> Vec<(IpBucket, Chain, Self)> {
+        challenge_set()
+            .iter()
+            .map(|((ip, chain), challenge)| (ip, chain, challenge))
+            .collect()
+    }
+
     pub fn nonce(&self) -> [u8; 16] {
         self.nonce
     }
@@ -146,15 +227,126 @@ impl Challenge {
     pub fn difficulty(&self) -> u8 {
         self.difficulty
     }
+
+    pub fn algorithm(&self) -> PowAlgorithm {
+        self.algorithm
+    }
+
+    pub fn shares(&self) -> u8 {
+        self.shares
+    }
+
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_deref()
+    }
+
+    pub fn is_claimed(&self) -> bool {
+        self.claimed
+    }
+
+    /// Time left until the challenge expires.
+    pub fn expires_in(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Checks that a challenge bound to `bound_address` may be used to claim funds
+/// to `address`.
+pub(crate) fn check_binding(
+    bound_address: Option<&str>,
+    address: &str,
+    require_binding: bool,
+) -> Result<(), OneOf<(AddressMismatch, UnboundChallenge)>> {
+    match bound_address {
+        Some(bound_address) if bound_address != address => err!(AddressMismatch),
+        Some(_) => Ok(()),
+        None if require_binding => err!(UnboundChallenge),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether `solution` is made of exactly `shares` distinct shares that
+/// each solve the proof-of-work puzzle for `nonce`.
+pub(crate) fn check_shares(
+    nonce: &Nonce,
+    difficulty: u8,
+    algorithm: &PowAlgorithm,
+    shares: u8,
+    address: Option<&str>,
+    solution: &[Share],
+) -> bool {
+    if solution.len() != usize::from(shares) {
+        return false;
+    }
+    let mut distinct = solution.to_vec();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() != solution.len() {
+        return false;
+    }
+    solution
+        .iter()
+        .all(|share| check_pow(nonce, difficulty, algorithm, address, share))
+}
+
+/// Difficulty each of `shares` shares has to meet so that finding all of them
+/// takes as much work as a single solution at `difficulty`.
+pub fn share_difficulty(difficulty: u8, shares: u8) -> u8 {
+    difficulty.saturating_sub(shares.ilog2() as u8)
+}
+
+/// Splits the bytes of a solution into its shares. Returns `None` unless
+/// there are between 1 and 255 whole shares.
+pub fn parse_solution(bytes: &[u8]) -> Option<Solution> {
+    let shares = bytes.len() / size_of::<Share>();
+    if !bytes.len().is_multiple_of(size_of::<Share>()) || !(1..=255).contains(&shares) {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks_exact(size_of::<Share>())
+            .map(|share| share.try_into().expect("correct length"))
+            .collect(),
+    )
+}
+
+/// Checks whether `share` solves the proof-of-work puzzle for `nonce` at the
+/// given difficulty, hashed with `algorithm`.
+///
+/// Challenges bound to an address hash the address in between the nonce and
+/// the share, using a different salt.
+pub(crate) fn check_pow(
+    nonce: &Nonce,
+    difficulty: u8,
+    algorithm: &PowAlgorithm,
+    address: Option<&str>,
+    share: &Share,
+) -> bool {
+    let mut message = Vec::with_capacity(64);
+    match address {
+        Some(address) => {
+            message.extend_from_slice(b"alpen faucet 2024 v2");
+            message.extend_from_slice(nonce);
+            message.extend_from_slice(address.as_bytes());
+        }
+        None => {
+            message.extend_from_slice(b"alpen faucet 2024");
+            message.extend_from_slice(nonce);
+        }
+    }
+    message.extend_from_slice(share);
+
+    count_leading_zeros(&algorithm.hash(nonce, &message)) >= difficulty
 }
 
-pub type Solution = [u8; 8];
+/// A single 8 byte solution to a challenge's puzzle.
+pub type Share = [u8; 8];
+/// All the shares a challenge asks for.
+pub type Solution = Vec<Share>;
 pub type Nonce = [u8; 16];
-/// IP set is used to check if an IPV4 address already
-/// has a nonce present. IPs stored as u32 form for
-/// compatibility with concurrent map. IPs are big endian
-/// but these are notably using platform endianness.
-pub type ChallengeSet = ConcurrentMap<(u32, Chain), Challenge>;
+/// IP set is used to check if an [`IpBucket`] already
+/// has a nonce present.
+pub type ChallengeSet = ConcurrentMap<(IpBucket, Chain), Challenge>;
 
 static CELL: OnceLock<Mutex<ChallengeSet>> = OnceLock::new();
 
@@ -245,7 +437,7 @@ impl EvictionQueue {
     }
 
     /// Adds a challenge to the eviction queue to be removed TTL in the future
-    pub fn add_challenge(&self, challenge: &Challenge, ip: Ipv4Addr, chain: Chain) {
+    pub fn add_challenge(&self, challenge: &Challenge, ip: IpBucket, chain: Chain) {
         self.q.lock().push(EvictionEntry {
             ip,
             chain,
@@ -292,7 +484,7 @@ impl EvictionQueue {
         };
         let cs = challenge_set();
         for EvictionEntry { ip, chain, .. } in to_expire {
-            cs.remove(&(ip.to_bits(), chain));
+            cs.remove(&(ip, chain));
         }
         next_wakeup
     }
@@ -302,7 +494,7 @@ type HeapGuard<'a> = MutexGuard<'a, BinaryHeap<EvictionEntry>>;
 
 #[derive(Debug)]
 pub struct EvictionEntry {
-    ip: Ipv4Addr,
+    ip: IpBucket,
     chain: Chain,
     expires_at: Instant,
 }
@@ -344,19 +536,55 @@ fn count_leading_zeros(data: &[u8]) -> u8 {
     leading_zeros
 }
 
+/// Fractional bits of the fixed-point numbers the difficulty curves are
+/// computed with.
+const FRAC_BITS: u32 = 16;
+
+/// Shape of the curve the difficulty follows as the faucet's balance drops
+/// towards `min_balance`. Read docs/pow.md for more information.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DifficultyCurve {
+    /// Rises linearly from the minimum to the maximum difficulty as the
+    /// balance drops from `min_balance + difficulty_increase_coeff *
+    /// amount_per_claim` to `min_balance`.
+    #[default]
+    Linear,
+    /// Rises by one every time the balance above `min_balance` halves,
+    /// starting at `min_balance + difficulty_increase_coeff *
+    /// amount_per_claim`.
+    Exponential,
+    /// Interpolates linearly between `(balance, difficulty)` points, ordered
+    /// by balance. Balances outside of the table get the difficulty of the
+    /// closest point.
+    Table { points: Vec<(Amount, u8)> },
+}
+
+#[derive(Debug, Clone)]
 pub struct DifficultyConfig {
     big_m: u8,
     m: u8,
-    b: f32,
-    /// Optimization for when x >= b+Lq, which should be the majority of the time
-    min_diff_start: f32,
-    /// Optimization for the linear function. This is the gradient of the linear function.
-    precompute_big_a: f32,
-    /// Optimization for the linear function. This is the y-intercept of the linear function.
-    precompute_big_b: f32,
+    /// Minimum balance in sats
+    b: u64,
+    /// `b + Lq` in sats, rounded up. Most of the time the balance is above
+    /// this, so the difficulty is just `m`.
+    min_diff_start: u64,
+    /// `Lq` in sats, as a fixed-point number with [`FRAC_BITS`] fractional
+    /// bits
+    lq: u128,
+    curve: Curve,
+}
+
+/// [`DifficultyCurve`] with the amounts in sats.
+#[derive(Debug, Clone, PartialEq)]
+enum Curve {
+    Linear,
+    Exponential,
+    Table(Vec<(u64, u8)>),
 }
 
 impl DifficultyConfig {
+    /// Creates a config for the [`DifficultyCurve::Linear`] curve.
     pub fn new(
         max_diff: u8,
         min_diff: u8,
@@ -370,61 +598,63 @@ impl DifficultyConfig {
         if per_claim == Amount::ZERO {
             return Err(DifficultyConfigError::PerClaimMustBeGreaterThanZero);
         }
-        if difficulty_increase_coeff <= 0.0 {
+        if difficulty_increase_coeff.is_nan() || difficulty_increase_coeff <= 0.0 {
             return Err(DifficultyConfigError::DifficultyIncreaseCoefficientMustBeGreaterThanZero);
         }
 
-        let big_m = max_diff as f32;
-        let m = min_diff as f32;
-        let b = min_balance.to_sat() as f32;
-        let q = per_claim.to_sat() as f32;
-        let big_l = difficulty_increase_coeff;
-
-        // Check for potential overflow in big_l * q
-        let lq_product = big_l * q;
-        if !lq_product.is_finite() {
-            return Err(DifficultyConfigError::ArithmeticOverflow);
-        }
-
-        // Check for potential overflow in b + big_l * q
-        let min_diff_start = b + lq_product;
-        if !min_diff_start.is_finite() {
+        // f32 to f64 is exact, as is scaling by a power of two, so this only
+        // rounds off what's below the fixed-point precision
+        let big_l = (difficulty_increase_coeff as f64 * (1u64 << FRAC_BITS) as f64).round();
+        if big_l >= u64::MAX as f64 {
             return Err(DifficultyConfigError::ArithmeticOverflow);
         }
-
-        // Check for division by zero or very small values that could cause issues
-        if lq_product.abs() < f32::EPSILON {
+        // can't overflow, both factors are below 2^64
+        let lq = big_l as u128 * per_claim.to_sat() as u128;
+        if lq == 0 {
             return Err(DifficultyConfigError::InvalidCalculation);
         }
 
-        // Check for potential overflow in (m - big_m) / (big_l * q)
-        let numerator = m - big_m;
-        let precompute_big_a = numerator / lq_product;
-        if !precompute_big_a.is_finite() {
-            return Err(DifficultyConfigError::ArithmeticOverflow);
-        }
-
-        // Check for potential overflow in precompute_big_a * b
-        let ab_product = precompute_big_a * b;
-        if !ab_product.is_finite() {
-            return Err(DifficultyConfigError::ArithmeticOverflow);
-        }
-
-        // Check for potential overflow in big_m - precompute_big_a * b
-        let precompute_big_b = big_m - ab_product;
-        if !precompute_big_b.is_finite() {
-            return Err(DifficultyConfigError::ArithmeticOverflow);
-        }
+        let lq_sats = u64::try_from(lq.div_ceil(1 << FRAC_BITS))
+            .map_err(|_| DifficultyConfigError::ArithmeticOverflow)?;
+        let min_diff_start = min_balance
+            .to_sat()
+            .checked_add(lq_sats)
+            .ok_or(DifficultyConfigError::ArithmeticOverflow)?;
 
         Ok(DifficultyConfig {
             big_m: max_diff,
             m: min_diff,
-            b,
+            b: min_balance.to_sat(),
             min_diff_start,
-            precompute_big_a,
-            precompute_big_b,
+            lq,
+            curve: Curve::Linear,
         })
     }
+
+    /// Switches to the given curve shape.
+    pub fn with_curve(mut self, curve: &DifficultyCurve) -> Result<Self, DifficultyConfigError> {
+        self.curve = match curve {
+            DifficultyCurve::Linear => Curve::Linear,
+            DifficultyCurve::Exponential => Curve::Exponential,
+            DifficultyCurve::Table { points } => {
+                if points.is_empty() || !points.is_sorted_by(|(a, _), (b, _)| a < b) {
+                    return Err(DifficultyConfigError::InvalidTable);
+                }
+                let points = points
+                    .iter()
+                    .map(|(balance, difficulty)| (balance.to_sat(), *difficulty))
+                    .collect();
+                Curve::Table(points)
+            }
+        };
+        Ok(self)
+    }
+
+    /// Balance above `b` as a fixed-point number, if it's below `b + Lq`.
+    fn ramp_position(&self, x: u64) -> Option<u128> {
+        let t = ((x - self.b) as u128) << FRAC_BITS;
+        (t < self.lq).then_some(t)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -434,6 +664,7 @@ pub enum DifficultyConfigError {
     DifficultyIncreaseCoefficientMustBeGreaterThanZero,
     ArithmeticOverflow,
     InvalidCalculation,
+    InvalidTable,
 }
 
 impl std::fmt::Display for DifficultyConfigError {
@@ -463,6 +694,12 @@ impl std::fmt::Display for DifficultyConfigError {
             DifficultyConfigError::InvalidCalculation => {
                 write!(f, "Invalid calculation parameters resulted in division by zero or near-zero values")
             }
+            DifficultyConfigError::InvalidTable => {
+                write!(
+                    f,
+                    "Difficulty table must have at least one point, with strictly increasing balances"
+                )
+            }
         }
     }
 }
@@ -471,15 +708,50 @@ impl std::error::Error for DifficultyConfigError {}
 
 /// Calculates dynamic difficulty for a given challenge. Read docs/pow.md for more information.
 pub fn calculate_difficulty(config: &DifficultyConfig, x: Amount) -> u8 {
-    match x.to_sat() as f32 {
+    let x = x.to_sat();
+    if x <= config.b {
+        return config.big_m;
+    }
+    let difficulty = match &config.curve {
         // Most expected path optimization, return min difficulty
-        x if x >= config.min_diff_start => config.m,
-        // Least expected path optimization, return max difficulty
-        x if x <= config.b => config.big_m,
-        // Optimised calculation for the gradient
-        // Safety: guaranteed within 0..=255 due to the nature of the linear function and the bounds of x
-        // the cast performs a truncation of the decimal part, so we round prior
-        x => (config.precompute_big_a * x + config.precompute_big_b).round() as u8,
+        Curve::Linear | Curve::Exponential if x >= config.min_diff_start => config.m,
+        Curve::Linear => match config.ramp_position(x) {
+            // M - (M - m) * t / Lq, rounded half up. t < Lq, so it's positive
+            Some(t) => {
+                let lq = config.lq;
+                let num = config.big_m as u128 * lq - (config.big_m - config.m) as u128 * t;
+                ((2 * num + lq) / (2 * lq)) as u8
+            }
+            None => config.m,
+        },
+        Curve::Exponential => match config.ramp_position(x) {
+            // m + floor(log2(Lq / t)), t is at least one sat so it's not zero
+            Some(t) => config
+                .m
+                .saturating_add((config.lq / t).ilog2().min(u8::MAX as u32) as u8),
+            None => config.m,
+        },
+        Curve::Table(points) => interpolate(points, x),
+    };
+    difficulty.clamp(config.m, config.big_m)
+}
+
+/// Difficulty at balance `x` on the line through the table's points.
+fn interpolate(points: &[(u64, u8)], x: u64) -> u8 {
+    let next = points.partition_point(|(balance, _)| *balance <= x);
+    match (
+        next.checked_sub(1).map(|i| points[i]),
+        points.get(next).copied(),
+    ) {
+        (Some((_, y0)), None) => y0,
+        (None, Some((_, y1))) => y1,
+        // (y0 * (x1 - x) + y1 * (x - x0)) / (x1 - x0), rounded half up
+        (Some((x0, y0)), Some((x1, y1))) => {
+            let d = (x1 - x0) as u128;
+            let num = y0 as u128 * (x1 - x) as u128 + y1 as u128 * (x - x0) as u128;
+            ((2 * num + d) / (2 * d)) as u8
+        }
+        (None, None) => unreachable!("tables aren't empty"),
     }
 }
 
@@ -487,6 +759,115 @@ pub fn calculate_difficulty(config: &DifficultyConfig, x: Amount) -> u8 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_pow_bound_to_address() {
+        let nonce = [7; 16];
+        let address = "tb1qexample";
+        // find a solution for the bound challenge
+        let solution = (0u64..)
+            .map(u64::to_be_bytes)
+            .find(|solution| check_pow(&nonce, 8, &PowAlgorithm::Sha256, Some(address), solution))
+            .unwrap();
+
+        assert!(!check_pow(
+            &nonce,
+            8,
+            &PowAlgorithm::Sha256,
+            Some("tb1qother"),
+            &solution
+        ));
+        assert!(!check_pow(
+            &nonce,
+            8,
+            &PowAlgorithm::Sha256,
+            None,
+            &solution
+        ));
+    }
+
+    #[test]
+    fn test_pow_with_scrypt() {
+        let nonce = [7; 16];
+        let scrypt = PowAlgorithm::Scrypt {
+            log_n: 4,
+            r: 1,
+            p: 1,
+        };
+        let solution = (0u64..)
+            .map(u64::to_be_bytes)
+            .find(|solution| check_pow(&nonce, 8, &scrypt, None, solution))
+            .unwrap();
+
+        // solutions only count for the algorithm they were found with
+        let sha256_solution = (0u64..)
+            .map(u64::to_be_bytes)
+            .find(|solution| check_pow(&nonce, 8, &PowAlgorithm::Sha256, None, solution))
+            .unwrap();
+        assert_ne!(solution, sha256_solution);
+        let other_params = PowAlgorithm::Scrypt {
+            log_n: 5,
+            r: 1,
+            p: 1,
+        };
+        assert!(!check_pow(&nonce, 8, &other_params, None, &solution));
+    }
+
+    #[test]
+    fn test_multiple_shares() {
+        let nonce = [7; 16];
+        let sha256 = PowAlgorithm::Sha256;
+        let shares: Vec<Share> = (0u64..)
+            .map(u64::to_be_bytes)
+            .filter(|share| check_pow(&nonce, 6, &sha256, None, share))
+            .take(4)
+            .collect();
+
+        assert!(check_shares(&nonce, 6, &sha256, 4, None, &shares));
+        // the share count has to match
+        assert!(!check_shares(&nonce, 6, &sha256, 4, None, &shares[..3]));
+        assert!(!check_shares(&nonce, 6, &sha256, 2, None, &shares));
+        // no share can be used twice
+        let repeated = [shares[0], shares[1], shares[2], shares[0]];
+        assert!(!check_shares(&nonce, 6, &sha256, 4, None, &repeated));
+        // and they all have to be valid
+        let invalid = (0u64..)
+            .map(u64::to_be_bytes)
+            .find(|share| !check_pow(&nonce, 6, &sha256, None, share))
+            .unwrap();
+        let with_invalid = [shares[0], shares[1], shares[2], invalid];
+        assert!(!check_shares(&nonce, 6, &sha256, 4, None, &with_invalid));
+    }
+
+    #[test]
+    fn test_parse_solution() {
+        assert_eq!(parse_solution(&[1; 8]), Some(vec![[1; 8]]));
+        assert_eq!(
+            parse_solution(&[[1; 8], [2; 8]].concat()),
+            Some(vec![[1; 8], [2; 8]])
+        );
+        assert_eq!(parse_solution(&[]), None);
+        assert_eq!(parse_solution(&[1; 12]), None);
+        assert_eq!(parse_solution(&[1; 8 * 256]), None);
+    }
+
+    #[test]
+    fn test_share_difficulty() {
+        assert_eq!(share_difficulty(20, 1), 20);
+        assert_eq!(share_difficulty(20, 4), 18);
+        assert_eq!(share_difficulty(20, 64), 14);
+        assert_eq!(share_difficulty(3, 16), 0);
+    }
+
+    #[test]
+    fn test_check_binding() {
+        assert!(check_binding(None, "a", false).is_ok());
+        assert!(check_binding(Some("a"), "a", true).is_ok());
+        let unbound = check_binding(None, "a", true);
+        assert!(unbound.unwrap_err().narrow::<UnboundChallenge, _>().is_ok());
+        let mismatch = check_binding(Some("b"), "a", false);
+        assert!(mismatch.unwrap_err().narrow::<AddressMismatch, _>().is_ok());
+    }
+
     #[test]
     fn test_new_config_valid() {
         let config =
@@ -494,14 +875,12 @@ mod tests {
 
         assert_eq!(config.big_m, 255);
         assert_eq!(config.m, 20);
-        assert_eq!(config.b, 0.0);
-        assert_eq!(config.min_diff_start, 100_000.0); // b + L*q = 0 + 10*10000
+        assert_eq!(config.b, 0);
+        assert_eq!(config.min_diff_start, 100_000); // b + L*q = 0 + 10*10000
 
         // Verify precomputed values
-        let expected_a = (20.0 - 255.0) / (10.0 * 10_000.0);
-        let expected_b = 255.0 - expected_a * 0.0;
-        assert_eq!(config.precompute_big_a, expected_a);
-        assert_eq!(config.precompute_big_b, expected_b);
+        assert_eq!(config.lq, 100_000 << FRAC_BITS);
+        assert_eq!(config.curve, Curve::Linear);
     }
 
     #[test]
@@ -639,7 +1018,7 @@ mod tests {
         // Test with different L value
         let config =
             DifficultyConfig::new(255, 17, Amount::ZERO, Amount::from_sat(5000), 25.).unwrap();
-        assert_eq!(config.min_diff_start, 125000.0); // 0 + 25*5000
+        assert_eq!(config.min_diff_start, 125000); // 0 + 25*5000
 
         // High balance should give min difficulty
         assert_eq!(calculate_difficulty(&config, Amount::from_sat(200_000)), 17);
@@ -655,10 +1034,12 @@ mod tests {
 
         // Manually calculate expected difficulty for x = 50000
         let x = 50000.0;
-        let expected = config.precompute_big_a * x + config.precompute_big_b;
+        let expected: f64 = (20.0 - 255.0) / (10.0 * 10_000.0) * x + 255.0;
         let calculated = calculate_difficulty(&config, Amount::from_sat(50_000));
 
+        // 137.5 exactly, rounded half up
         assert_eq!(calculated, expected.round() as u8);
+        assert_eq!(calculated, 138);
     }
 
     #[test]
@@ -702,4 +1083,105 @@ mod tests {
         let diff = calculate_difficulty(&config, Amount::from_sat(mid_balance));
         assert!(diff > 20 && diff < 255);
     }
+
+    #[test]
+    fn test_exact_large_balances() {
+        // f32 can't tell these balances apart, they're 1 sat off a multiple
+        // of 2^24 sats
+        let config = DifficultyConfig::new(
+            64,
+            18,
+            Amount::from_sat(50 * (1 << 24)),
+            Amount::from_sat(1 << 24),
+            1.,
+        )
+        .unwrap();
+        assert_eq!(
+            calculate_difficulty(&config, Amount::from_sat(50 * (1 << 24))),
+            64
+        );
+        assert_eq!(
+            calculate_difficulty(&config, Amount::from_sat(50 * (1 << 24) + 1)),
+            64
+        );
+        assert_eq!(
+            calculate_difficulty(&config, Amount::from_sat(51 * (1 << 24) - 1)),
+            18
+        );
+        assert_eq!(
+            calculate_difficulty(&config, Amount::from_sat(51 * (1 << 24))),
+            18
+        );
+        // halfway is 41 exactly
+        assert_eq!(
+            calculate_difficulty(&config, Amount::from_sat(50 * (1 << 24) + (1 << 23))),
+            41
+        );
+    }
+
+    #[test]
+    fn test_exponential_curve() {
+        let config = DifficultyConfig::new(
+            64,
+            20,
+            Amount::from_sat(1000),
+            Amount::from_sat(10_000),
+            10.,
+        )
+        .unwrap()
+        .with_curve(&DifficultyCurve::Exponential)
+        .unwrap();
+
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(101_000)), 20);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(100_999)), 20);
+        // one more for every halving of the balance above 1000
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(51_000)), 21);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(26_000)), 22);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(1001)), 36);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(1000)), 64);
+    }
+
+    #[test]
+    fn test_table_curve() {
+        let points = vec![
+            (Amount::from_sat(1000), 60),
+            (Amount::from_sat(2000), 40),
+            (Amount::from_sat(10_000), 20),
+        ];
+        let config =
+            DifficultyConfig::new(64, 18, Amount::from_sat(500), Amount::from_sat(100), 1.)
+                .unwrap()
+                .with_curve(&DifficultyCurve::Table { points })
+                .unwrap();
+
+        // below min_balance
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(500)), 64);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(501)), 60);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(1000)), 60);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(1500)), 50);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(2000)), 40);
+        // 37.5 rounded half up
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(3000)), 38);
+        assert_eq!(calculate_difficulty(&config, Amount::from_sat(10_000)), 20);
+        assert_eq!(
+            calculate_difficulty(&config, Amount::from_btc(1.).unwrap()),
+            20
+        );
+
+        let config =
+            DifficultyConfig::new(64, 18, Amount::ZERO, Amount::from_sat(100), 1.).unwrap();
+        for points in [
+            vec![],
+            vec![(Amount::from_sat(2), 20), (Amount::from_sat(1), 30)],
+            vec![(Amount::from_sat(1), 20), (Amount::from_sat(1), 30)],
+        ] {
+            assert_eq!(
+                config
+                    .clone()
+                    .with_curve(&DifficultyCurve::Table { points })
+                    .unwrap_err(),
+                DifficultyConfigError::InvalidTable
+            );
+        }
+    }
 }
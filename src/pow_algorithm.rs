@@ -0,0 +1,137 @@
+//! Hash functions proof-of-work challenges can be solved with.
+//!
+//! Plain SHA-256 is cheap to compute on GPUs and ASICs, which solve it orders
+//! of magnitude faster than a browser. scrypt needs a configurable amount of
+//! memory per hash, which narrows that gap.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::pow::Nonce;
+
+/// Most memory a single scrypt hash may need, so that checking solutions
+/// can't exhaust the faucet's memory.
+const MAX_SCRYPT_MEMORY: u64 = 256 * 1024 * 1024;
+/// Most scrypt lanes, each of which takes as long as the whole hash with
+/// `p = 1`.
+const MAX_SCRYPT_P: u32 = 16;
+
+/// The hash function a challenge is solved with, along with its parameters.
+/// Handed to clients with each challenge.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PowAlgorithm {
+    /// A single SHA-256 of the challenge message.
+    #[default]
+    Sha256,
+    /// scrypt of the challenge message, salted with the challenge nonce. Each
+    /// hash needs `128 * r * 2^log_n` bytes of memory.
+    Scrypt {
+        /// Base 2 logarithm of the CPU/memory cost
+        #[serde(default = "default_log_n")]
+        log_n: u8,
+        /// Block size
+        #[serde(default = "default_r")]
+        r: u32,
+        /// Parallelism
+        #[serde(default = "default_p")]
+        p: u32,
+    },
+}
+
+fn default_log_n() -> u8 {
+    14
+}
+
+fn default_r() -> u32 {
+    8
+}
+
+fn default_p() -> u32 {
+    1
+}
+
+impl PowAlgorithm {
+    /// Whether the parameters are accepted by the hash function, and don't
+    /// need more than 256 MiB of memory or 16 lanes per hash.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            PowAlgorithm::Sha256 => true,
+            PowAlgorithm::Scrypt { log_n, r, p } => {
+                scrypt::Params::new(log_n, r, p, 32).is_ok()
+                    && p <= MAX_SCRYPT_P
+                    && 128u64
+                        .checked_mul(r.into())
+                        .and_then(|bytes| bytes.checked_shl(log_n.into()))
+                        .is_some_and(|bytes| bytes <= MAX_SCRYPT_MEMORY)
+            }
+        }
+    }
+
+    /// Hashes the challenge `message`, which starts with the salt and `nonce`
+    /// and ends with the solution.
+    pub fn hash(&self, nonce: &Nonce, message: &[u8]) -> [u8; 32] {
+        match *self {
+            PowAlgorithm::Sha256 => Sha256::digest(message).into(),
+            PowAlgorithm::Scrypt { log_n, r, p } => {
+                let params = scrypt::Params::new(log_n, r, p, 32).expect("validated params");
+                let mut output = [0; 32];
+                scrypt::scrypt(message, nonce, &params, &mut output).expect("valid output length");
+                output
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        // same as the solvers in `utils` compute
+        let nonce = [7; 16];
+        let mut message = b"alpen faucet 2024".to_vec();
+        message.extend_from_slice(&nonce);
+        message.extend_from_slice(&[0; 8]);
+
+        let hex = |hash: [u8; 32]| hash.map(|b| format!("{b:02x}")).concat();
+        assert_eq!(
+            hex(PowAlgorithm::Sha256.hash(&nonce, &message)),
+            "8ac606f380549b6f1176d3df5b66becbd239d3bcbfcc338040306a3c30261a11"
+        );
+        let scrypt = PowAlgorithm::Scrypt {
+            log_n: 4,
+            r: 1,
+            p: 1,
+        };
+        assert_eq!(
+            hex(scrypt.hash(&nonce, &message)),
+            "63ff8034706c6131973485e952fa3014efb36873854bc019c8032683a0f21a13"
+        );
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(PowAlgorithm::Sha256.is_valid());
+        assert!(PowAlgorithm::Scrypt {
+            log_n: 14,
+            r: 8,
+            p: 1
+        }
+        .is_valid());
+        // 512 MiB
+        assert!(!PowAlgorithm::Scrypt {
+            log_n: 19,
+            r: 8,
+            p: 1
+        }
+        .is_valid());
+        assert!(!PowAlgorithm::Scrypt {
+            log_n: 14,
+            r: 0,
+            p: 1
+        }
+        .is_valid());
+    }
+}
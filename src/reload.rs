@@ -0,0 +1,255 @@
+//! Reloading the config file while the faucet is running.
+//!
+//! The config is re-read on SIGHUP and whenever the file changes. Only the
+//! `[l1]` and `[l2]` sections and the `[batcher]` section, except for the L2
+//! batching settings, can be changed without a restart. A reload that changes
+//! anything else is rejected as a whole, leaving the running settings as they
+//! were.
+//!
+//! Only the settings that changed in the file are applied, so changes made
+//! through the [admin API](crate::admin) to other settings are kept.
+
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    time::interval,
+};
+use tracing::{error, info};
+
+use crate::{
+    settings::{config_path, LayerConfig, Settings, SETTINGS},
+    AppState, Chain,
+};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spawns a tokio task that reloads the config on SIGHUP or when the file
+/// changes.
+pub fn spawn(state: Arc<AppState>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP handler to install");
+    tokio::spawn(async move {
+        let mut poll_interval = interval(POLL_INTERVAL);
+        let mut modified = modified_at();
+        // settings as last read from the file, so only the settings that
+        // change in the file override changes made through the admin API
+        let mut loaded = SETTINGS.clone();
+        loop {
+            select! {
+                _ = hangup.recv() => info!("received SIGHUP, reloading config"),
+                _ = poll_interval.tick() => {
+                    if modified_at() == modified {
+                        continue;
+                    }
+                    info!("{} changed, reloading config", config_path().display());
+                }
+            }
+            modified = modified_at();
+            reload(&state, &mut loaded);
+        }
+    });
+}
+
+fn modified_at() -> Option<SystemTime> {
+    fs::metadata(config_path())
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Re-reads the config and applies whatever changed since it was last
+/// `loaded`.
+fn reload(state: &AppState, loaded: &mut Settings) {
+    let new = match Settings::load(config_path()) {
+        Ok(new) => new,
+        Err(e) => {
+            error!("not reloading config, it's invalid: {e:?}");
+            return;
+        }
+    };
+    let needs_restart = restart_required(loaded, &new);
+    if !needs_restart.is_empty() {
+        error!(
+            "not reloading config, changing {} needs a restart",
+            needs_restart.join(", ")
+        );
+        return;
+    }
+
+    for chain in [Chain::L1, Chain::L2] {
+        let (old_config, new_config) = match chain {
+            Chain::L1 => (&loaded.l1, &new.l1),
+            Chain::L2 => (&loaded.l2, &new.l2),
+        };
+        if old_config == new_config {
+            continue;
+        }
+        // the new settings were validated on their own, but combined with
+        // changes made through the admin API they may not make a valid
+        // difficulty curve, in which case the chain's config is left as is
+        match state
+            .layer(chain)
+            .update(|config| apply_changed(config, old_config, new_config))
+        {
+            Ok(_) => info!("reloaded {} config", chain.as_str()),
+            Err(e) => error!("failed reloading {} config: {e}", chain.as_str()),
+        }
+    }
+    if loaded.batcher != new.batcher {
+        state.batcher.set_config(new.batcher.clone());
+        info!("reloaded batcher config");
+    }
+    *loaded = new;
+}
+
+/// Sets the fields of `config` that differ between the `old` and `new`
+/// versions of the file to their new values.
+fn apply_changed(config: &mut LayerConfig, old: &LayerConfig, new: &LayerConfig) {
+    macro_rules! apply_changed {
+        ($($field:ident),* $(,)?) => {
+            // doesn't compile if a field is missing
+            let LayerConfig { $($field: _),* } = new;
+            $(
+                if old.$field != new.$field {
+                    config.$field = new.$field.clone();
+                }
+            )*
+        };
+    }
+    apply_changed!(
+        min_difficulty,
+        max_difficulty,
+        min_balance,
+        amount_per_claim,
+        difficulty_increase_coeff,
+        difficulty_curve,
+        claim_rate,
+        reputation,
+        pow_algorithm,
+        pow_shares,
+        challenge_duration,
+        cooldown,
+    );
+}
+
+/// Names of the settings that differ between `old` and `new` and can't be
+/// changed without a restart.
+fn restart_required(old: &Settings, new: &Settings) -> Vec<&'static str> {
+    [
+        ("host", old.host != new.host),
+        ("port", old.port != new.port),
+        // ClientIpSource isn't PartialEq
+        (
+            "ip_src",
+            format!("{:?}", old.ip_src) != format!("{:?}", new.ip_src),
+        ),
+        (
+            "ipv6_prefix_len",
+            old.ipv6_prefix_len != new.ipv6_prefix_len,
+        ),
+        ("challenge_mode", old.challenge_mode != new.challenge_mode),
+        (
+            "require_address_binding",
+            old.require_address_binding != new.require_address_binding,
+        ),
+        ("seed_file", old.seed_file != new.seed_file),
+        ("seed", old.seed != new.seed),
+        ("derivation", old.derivation != new.derivation),
+        ("sqlite_file", old.sqlite_file != new.sqlite_file),
+        ("network", old.network != new.network),
+        ("chain source", old.chain_source != new.chain_source),
+        (
+            "l2_http_endpoint",
+            old.l2_http_endpoints != new.l2_http_endpoints,
+        ),
+        ("endpoints", old.endpoints != new.endpoints),
+        ("health", old.health != new.health),
+        ("fee", old.fee != new.fee),
+        ("admin", old.admin != new.admin),
+        ("claim_history", old.claim_history != new.claim_history),
+        (
+            "batcher.l2_batching",
+            old.batcher.l2_batching != new.batcher.l2_batching,
+        ),
+        (
+            "batcher.l2_disperse_contract",
+            old.batcher.l2_disperse_contract != new.batcher.l2_disperse_contract,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use bdk_wallet::bitcoin::{Amount, Network};
+    use config::{Config, File, FileFormat};
+
+    use super::*;
+    use crate::settings::ReadableSettings;
+
+    fn settings() -> Settings {
+        Config::builder()
+            .add_source(File::from_str(
+                r#"
+                ip_src = "ConnectInfo"
+                esplora = "https://esplora.example.com"
+                l2_http_endpoint = "https://rpc.example.com"
+                l1 = { amount_per_claim = 10000 }
+                l2 = { amount_per_claim = 10000 }
+                "#,
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize::<ReadableSettings>()
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply_changed() {
+        let old = settings().l1;
+        let mut live = old.clone();
+        // changed through the admin API
+        live.amount_per_claim = Amount::from_sat(50_000);
+        live.min_difficulty = 20;
+
+        let mut new = old.clone();
+        new.min_difficulty = 22;
+        new.pow_shares = 4;
+        apply_changed(&mut live, &old, &new);
+        assert_eq!(live.amount_per_claim, Amount::from_sat(50_000));
+        assert_eq!(live.min_difficulty, 22);
+        assert_eq!(live.pow_shares, 4);
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let old = settings();
+        let mut new = old.clone();
+        new.l1.amount_per_claim = Amount::from_sat(20_000);
+        new.l2.challenge_duration = Duration::from_secs(10);
+        new.batcher.period = Duration::from_secs(10);
+        new.batcher.max_per_tx = 10;
+        new.batcher.rbf_after = Duration::from_secs(10);
+        assert!(restart_required(&old, &new).is_empty());
+
+        new.network = Network::Bitcoin;
+        new.host = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        new.batcher.l2_batching = true;
+        assert_eq!(
+            restart_required(&old, &new),
+            ["host", "network", "batcher.l2_batching"]
+        );
+    }
+}
@@ -1,45 +1,474 @@
+//! The seed all of the faucet's keys are derived from.
+//!
+//! The seed file holds either the 32 seed bytes as they are, or the seed
+//! encrypted with a key derived from a passphrase. Which of the two it is gets
+//! detected when loading, so an encrypted seed file is never turned back into
+//! a plaintext one. Seed files must only be accessible by their owner.
+//!
+//! Only serving the faucet creates the seed file or encrypts a plaintext one.
+//! The other subcommands just read it.
+
 use std::{
-    fs::{read, write},
-    io,
-    path::Path,
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
 };
 
+use argon2::{Algorithm, Argon2, Params, Version};
+use bip39::Mnemonic;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
 use rand::{rng, Rng};
+use serde::{Deserialize, Serialize};
 use tracing::info;
 
 use crate::settings::SETTINGS;
 
 pub type Seed = [u8; 32];
-pub struct SavableSeed(Seed);
 
-impl SavableSeed {
-    fn save(&self) -> io::Result<()> {
-        write(&SETTINGS.seed_file, self.0)?;
-        info!("seed saved");
-        Ok(())
+/// Start of an encrypted seed file. It's followed by the KDF parameters, the
+/// salt, the nonce and the encrypted seed with its authentication tag.
+const MAGIC: &[u8; 8] = b"AFSEED01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 3 * 4 + SALT_LEN + NONCE_LEN;
+const ENCRYPTED_LEN: usize = HEADER_LEN + 32 + 16;
+
+/// Argon2id parameters for newly encrypted seeds, the second recommended
+/// option of RFC 9106.
+const KDF_PARAMS: KdfParams = KdfParams {
+    m_cost: 64 * 1024,
+    t_cost: 3,
+    p_cost: 4,
+};
+
+/// Most memory a seed file may ask the KDF to use, in KiB, so a corrupt file
+/// fails instead of exhausting memory.
+const MAX_M_COST: u32 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeedConfig {
+    /// Whether the seed file is encrypted with a passphrase. An existing
+    /// plaintext seed file gets encrypted the next time the faucet is served.
+    ///
+    /// Defaults to `false`.
+    pub encrypt: bool,
+
+    /// Environment variable the passphrase is read from.
+    ///
+    /// Defaults to `ALPEN_FAUCET_SEED_PASSPHRASE`.
+    pub passphrase_env: String,
+
+    /// File the passphrase is read from if the environment variable isn't
+    /// set, ignoring trailing newlines. Without either, the passphrase is
+    /// prompted for when running in a terminal.
+    ///
+    /// Defaults to `None`.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        Self {
+            encrypt: false,
+            passphrase_env: "ALPEN_FAUCET_SEED_PASSPHRASE".to_owned(),
+            passphrase_file: None,
+        }
     }
+}
 
-    fn read() -> io::Result<Option<Self>> {
-        if Path::new(&SETTINGS.seed_file).exists() {
-            let bytes = read(&SETTINGS.seed_file)?;
-            Ok(bytes.try_into().map(Self).ok())
+impl SeedConfig {
+    /// Reads the passphrase from the environment, the passphrase file or a
+    /// prompt, in that order. `confirm` has it entered twice when prompting.
+    fn passphrase(&self, confirm: bool) -> Result<String, SeedError> {
+        let passphrase = if let Ok(passphrase) = env::var(&self.passphrase_env) {
+            passphrase
+        } else if let Some(path) = &self.passphrase_file {
+            check_permissions(path)?;
+            let passphrase = fs::read_to_string(path)?;
+            passphrase.trim_end_matches(['\r', '\n']).to_owned()
+        } else if io::stdin().is_terminal() {
+            let passphrase = rpassword::prompt_password("Seed passphrase: ")?;
+            if confirm && rpassword::prompt_password("Repeat seed passphrase: ")? != passphrase {
+                return Err(SeedError::PassphraseMismatch);
+            }
+            passphrase
         } else {
-            Ok(None)
+            return Err(SeedError::NoPassphrase);
+        };
+        if passphrase.is_empty() {
+            return Err(SeedError::EmptyPassphrase);
         }
+        Ok(passphrase)
     }
+}
 
-    pub fn load_or_create() -> io::Result<Seed> {
-        match Self::read() {
-            Ok(Some(me)) => {
-                info!("successfully loaded seed");
-                Ok(me.0)
-            }
-            _ => {
-                info!("couldn't load seed, generating new one");
-                let me = Self(rng().random());
-                me.save()?;
-                Ok(me.0)
-            }
+#[derive(Debug)]
+pub enum SeedError {
+    Io(io::Error),
+    /// The file can be accessed by users other than its owner.
+    InsecurePermissions(PathBuf, u32),
+    /// The seed file is neither a plaintext nor an encrypted seed.
+    Corrupt,
+    /// The passphrase is wrong, or the encrypted seed was tampered with.
+    Decrypt,
+    /// The seed is encrypted, but there's no passphrase to decrypt it with.
+    NoPassphrase,
+    EmptyPassphrase,
+    /// The passphrase was entered differently the second time.
+    PassphraseMismatch,
+    /// A seed is being imported, but there already is a seed file.
+    AlreadyExists(PathBuf),
+    /// The seed is being loaded without creating it, but there's no seed
+    /// file.
+    NotFound(PathBuf),
+}
+
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::InsecurePermissions(path, mode) => write!(
+                f,
+                "{} has mode {mode:o}, restrict it with `chmod 600`",
+                path.display()
+            ),
+            Self::Corrupt => write!(f, "seed file is corrupt"),
+            Self::Decrypt => write!(f, "wrong passphrase or corrupt seed file"),
+            Self::NoPassphrase => write!(f, "seed is encrypted but no passphrase was given"),
+            Self::EmptyPassphrase => write!(f, "seed passphrase is empty"),
+            Self::PassphraseMismatch => write!(f, "passphrases don't match"),
+            Self::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+            Self::NotFound(path) => write!(
+                f,
+                "{} doesn't exist, create it with `serve` or `import-mnemonic`",
+                path.display()
+            ),
         }
     }
 }
+
+impl From<io::Error> for SeedError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KdfParams {
+    /// Memory in KiB
+    m_cost: u32,
+    /// Number of passes
+    t_cost: u32,
+    /// Degree of parallelism
+    p_cost: u32,
+}
+
+/// Loads the seed from `SETTINGS.seed_file`, generating and saving a new one
+/// if the file doesn't exist. Never replaces an existing seed file with a
+/// new seed.
+pub fn load_or_create() -> Result<Seed, SeedError> {
+    load_or_create_at(&SETTINGS.seed_file, &SETTINGS.seed, KDF_PARAMS)
+}
+
+/// Loads the seed from `SETTINGS.seed_file`, failing if the file doesn't
+/// exist. The file is left as it is, even if it should be encrypted.
+pub fn load() -> Result<Seed, SeedError> {
+    load_at(&SETTINGS.seed_file, &SETTINGS.seed).map(|(seed, _)| seed)
+}
+
+/// Saves `seed` to `SETTINGS.seed_file`, which must not exist yet.
+pub fn import(seed: &Seed) -> Result<(), SeedError> {
+    if SETTINGS.seed_file.try_exists()? {
+        return Err(SeedError::AlreadyExists(SETTINGS.seed_file.clone()));
+    }
+    save_new(&SETTINGS.seed_file, &SETTINGS.seed, KDF_PARAMS, seed)
+}
+
+/// The BIP39 mnemonic encoding `seed`.
+pub fn mnemonic(seed: &Seed) -> Mnemonic {
+    Mnemonic::from_entropy(seed).expect("valid entropy")
+}
+
+/// The BIP39 seed of `seed`'s mnemonic. We do not use a passphrase.
+pub fn bip39_seed(seed: &Seed) -> [u8; 64] {
+    mnemonic(seed).to_seed("")
+}
+
+fn load_or_create_at(path: &Path, cfg: &SeedConfig, params: KdfParams) -> Result<Seed, SeedError> {
+    if !path.try_exists()? {
+        info!("no seed file, generating new seed");
+        let seed = rng().random();
+        save_new(path, cfg, params, &seed)?;
+        return Ok(seed);
+    }
+
+    let (seed, encrypted) = load_at(path, cfg)?;
+    if cfg.encrypt && !encrypted {
+        info!("encrypting plaintext seed file");
+        replace(path, &encrypt(&seed, &cfg.passphrase(true)?, params)?)?;
+    }
+    Ok(seed)
+}
+
+/// Loads the seed from an existing seed file, along with whether the file is
+/// encrypted.
+fn load_at(path: &Path, cfg: &SeedConfig) -> Result<(Seed, bool), SeedError> {
+    if !path.try_exists()? {
+        return Err(SeedError::NotFound(path.to_owned()));
+    }
+    check_permissions(path)?;
+    let contents = fs::read(path)?;
+    let encrypted = contents.starts_with(MAGIC);
+    let seed = if encrypted {
+        decrypt(&contents, &cfg.passphrase(false)?)?
+    } else {
+        Seed::try_from(contents.as_slice()).map_err(|_| SeedError::Corrupt)?
+    };
+    info!("successfully loaded seed");
+    Ok((seed, encrypted))
+}
+
+fn save_new(
+    path: &Path,
+    cfg: &SeedConfig,
+    params: KdfParams,
+    seed: &Seed,
+) -> Result<(), SeedError> {
+    if cfg.encrypt {
+        write_new(path, &encrypt(seed, &cfg.passphrase(true)?, params)?)?;
+    } else {
+        write_new(path, seed)?;
+    }
+    info!("seed saved");
+    Ok(())
+}
+
+fn check_permissions(path: &Path) -> Result<(), SeedError> {
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(SeedError::InsecurePermissions(path.to_owned(), mode));
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a new file only its owner can access.
+fn write_new(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Atomically replaces the file at `path` with one holding `contents`.
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    write_new(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+fn encrypt(seed: &Seed, passphrase: &str, params: KdfParams) -> Result<Vec<u8>, SeedError> {
+    let salt: [u8; SALT_LEN] = rng().random();
+    let nonce: [u8; NONCE_LEN] = rng().random();
+    let mut contents = Vec::with_capacity(ENCRYPTED_LEN);
+    contents.extend_from_slice(MAGIC);
+    for param in [params.m_cost, params.t_cost, params.p_cost] {
+        contents.extend_from_slice(&param.to_le_bytes());
+    }
+    contents.extend_from_slice(&salt);
+    contents.extend_from_slice(&nonce);
+
+    // the header is authenticated too, so the KDF parameters can't be changed
+    let ciphertext = cipher(passphrase, &salt, params)?
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: seed,
+                aad: &contents,
+            },
+        )
+        .expect("encrypting a seed to succeed");
+    contents.extend_from_slice(&ciphertext);
+    Ok(contents)
+}
+
+fn decrypt(contents: &[u8], passphrase: &str) -> Result<Seed, SeedError> {
+    if contents.len() != ENCRYPTED_LEN {
+        return Err(SeedError::Corrupt);
+    }
+    let (header, ciphertext) = contents.split_at(HEADER_LEN);
+    let param_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let params = KdfParams {
+        m_cost: param_at(MAGIC.len()),
+        t_cost: param_at(MAGIC.len() + 4),
+        p_cost: param_at(MAGIC.len() + 8),
+    };
+    let (salt, nonce) = header[MAGIC.len() + 12..].split_at(SALT_LEN);
+
+    let seed = cipher(passphrase, salt, params)?
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| SeedError::Decrypt)?;
+    Ok(seed.try_into().expect("32 byte seed"))
+}
+
+/// Derives the seed encryption key from `passphrase` with Argon2id.
+fn cipher(
+    passphrase: &str,
+    salt: &[u8],
+    params: KdfParams,
+) -> Result<XChaCha20Poly1305, SeedError> {
+    if params.m_cost > MAX_M_COST {
+        return Err(SeedError::Corrupt);
+    }
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|_| SeedError::Corrupt)?;
+    let mut key = [0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| SeedError::Corrupt)?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters so the tests don't take long
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("faucet-seed-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn encrypted_config(dir: &Path, passphrase: &str) -> SeedConfig {
+        let passphrase_file = dir.join("passphrase");
+        let _ = fs::remove_file(&passphrase_file);
+        write_new(&passphrase_file, format!("{passphrase}\n").as_bytes()).unwrap();
+        SeedConfig {
+            encrypt: true,
+            passphrase_env: "FAUCET_TEST_UNSET_PASSPHRASE".to_owned(),
+            passphrase_file: Some(passphrase_file),
+        }
+    }
+
+    #[test]
+    fn test_encryption_roundtrip() {
+        let seed = [7; 32];
+        let encrypted = encrypt(&seed, "hunter2", TEST_PARAMS).unwrap();
+        assert_eq!(encrypted.len(), ENCRYPTED_LEN);
+        assert_eq!(decrypt(&encrypted, "hunter2").unwrap(), seed);
+        assert!(matches!(
+            decrypt(&encrypted, "hunter3"),
+            Err(SeedError::Decrypt)
+        ));
+
+        // the KDF parameters are authenticated
+        let mut tampered = encrypted.clone();
+        tampered[MAGIC.len() + 4] += 1;
+        assert!(matches!(
+            decrypt(&tampered, "hunter2"),
+            Err(SeedError::Decrypt)
+        ));
+        assert!(matches!(
+            decrypt(&encrypted[..ENCRYPTED_LEN - 1], "hunter2"),
+            Err(SeedError::Corrupt)
+        ));
+    }
+
+    #[test]
+    fn test_load_or_create() {
+        let dir = temp_dir("load");
+        let path = dir.join("faucet.seed");
+        let plain = SeedConfig::default();
+
+        let seed = load_or_create_at(&path, &plain, TEST_PARAMS).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(fs::read(&path).unwrap(), seed);
+        assert_eq!(load_or_create_at(&path, &plain, TEST_PARAMS).unwrap(), seed);
+
+        // turning on encryption encrypts the existing seed
+        let encrypted = encrypted_config(&dir, "hunter2");
+        assert_eq!(
+            load_or_create_at(&path, &encrypted, TEST_PARAMS).unwrap(),
+            seed
+        );
+        assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+        assert_eq!(
+            load_or_create_at(&path, &encrypted, TEST_PARAMS).unwrap(),
+            seed
+        );
+        let wrong = encrypted_config(&dir, "hunter3");
+        assert!(matches!(
+            load_or_create_at(&path, &wrong, TEST_PARAMS),
+            Err(SeedError::Decrypt)
+        ));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            load_or_create_at(&path, &encrypted, TEST_PARAMS),
+            Err(SeedError::InsecurePermissions(_, 0o644))
+        ));
+
+        // corrupt seeds aren't replaced
+        fs::remove_file(&path).unwrap();
+        write_new(&path, b"not a seed").unwrap();
+        assert!(matches!(
+            load_or_create_at(&path, &plain, TEST_PARAMS),
+            Err(SeedError::Corrupt)
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"not a seed");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = temp_dir("load-only");
+        let path = dir.join("faucet.seed");
+        let encrypted = encrypted_config(&dir, "hunter2");
+
+        // missing seed files aren't created
+        assert!(matches!(
+            load_at(&path, &encrypted),
+            Err(SeedError::NotFound(_))
+        ));
+        assert!(!path.exists());
+
+        // plaintext seed files aren't encrypted
+        write_new(&path, &[7; 32]).unwrap();
+        assert_eq!(load_at(&path, &encrypted).unwrap(), ([7; 32], false));
+        assert_eq!(fs::read(&path).unwrap(), [7; 32]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,8 @@
 use std::{
     net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::LazyLock,
+    sync::{LazyLock, OnceLock},
     time::Duration,
 };
 
@@ -10,33 +10,47 @@ use axum_client_ip::ClientIpSource;
 use bdk_wallet::bitcoin::{Amount, Network};
 use config::Config;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::{batcher::BatcherConfig, CRATE_NAME};
+use crate::{
+    admin::AdminConfig,
+    batcher::BatcherConfig,
+    chain_source::{BitcoindConfig, ChainSourceKind},
+    claim_history::{ClaimHistoryConfig, ReputationConfig},
+    claim_rate::ClaimRateConfig,
+    endpoints::EndpointsConfig,
+    health::HealthConfig,
+    l1::{Derivation, FeeConfig},
+    layer::difficulty_config,
+    pow::{ChallengeMode, DifficultyConfigError, DifficultyCurve},
+    pow_algorithm::PowAlgorithm,
+    seed::SeedConfig,
+    Chain, CRATE_NAME,
+};
+
+/// Config file used when none is given with `--config`/`-c`
+pub const DEFAULT_CONFIG_PATH: &str = "faucet.toml";
 
-pub static SETTINGS: LazyLock<Settings> = LazyLock::new(|| {
-    let args = std::env::args().collect::<Vec<_>>();
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 
-    let settings_path = match (args.get(1), args.get(2)) {
-        (Some(a1), Some(a2)) if a1 == "--config" || a1 == "-c" => Some(PathBuf::from(a2)),
-        _ => None,
-    };
+/// Settings the faucet was started with. Some of them can be changed while
+/// it's running, see [`reload`](crate::reload).
+pub static SETTINGS: LazyLock<Settings> =
+    LazyLock::new(|| Settings::load(config_path()).expect("a valid config"));
 
-    let mut builder = Config::builder();
-    if let Some(path) = settings_path {
-        builder = builder.add_source(config::File::from(path));
-    } else {
-        builder = builder.add_source(config::File::with_name("faucet.toml"))
+/// Sets the path of the config file. Must be called before [`SETTINGS`] is
+/// first used to have any effect.
+pub fn set_config_path(path: PathBuf) {
+    if CONFIG_PATH.set(path).is_err() {
+        warn!("config path was already set");
     }
-    builder
-        // Add in settings from the environment (with a prefix of CRATE_NAME)
-        .add_source(config::Environment::with_prefix(&CRATE_NAME.to_uppercase()))
-        .build()
-        .expect("a valid config")
-        .try_deserialize::<ReadableSettings>()
-        .expect("a valid config")
-        .try_into()
-        .expect("invalid config")
-});
+}
+
+/// Path of the config file, [`DEFAULT_CONFIG_PATH`] unless another one was set
+/// with [`set_config_path`].
+pub fn config_path() -> &'static Path {
+    CONFIG_PATH.get_or_init(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct ReadableSettings {
@@ -46,55 +60,161 @@ pub struct ReadableSettings {
     pub port: Option<u16>,
     /// How the server should determine the client's IP address
     pub ip_src: ClientIpSource,
+    /// Length of the prefix IPv6 clients are grouped by. Defaults to `56`
+    pub ipv6_prefix_len: Option<u8>,
+    /// Where outstanding POW challenges are kept. Defaults to [`ChallengeMode::Stateful`]
+    pub challenge_mode: Option<ChallengeMode>,
+    /// Whether POW challenges must be bound to a recipient address. Defaults to `false`
+    pub require_address_binding: Option<bool>,
     /// Path to the seed file which stores the wallet's seed/master bytes
     pub seed_file: Option<String>,
+    /// Whether and how the seed file is encrypted
+    pub seed: Option<SeedConfig>,
+    /// How the L1 wallet's keys are derived from the seed. Defaults to
+    /// [`Derivation::Legacy`]
+    pub derivation: Option<Derivation>,
     /// Path to the SQLite database file which stores the wallet's data
     pub sqlite_file: Option<String>,
     /// Network to use for the wallet. Defaults to [`Network::Signet`]
     pub network: Option<Network>,
-    /// URL of the esplora API to use for the wallet. Should not have a trailing slash
-    pub esplora: String,
-    /// URL of the EVM L2 HTTP endpoint to use for the wallet. Should not have a trailing slash
-    pub l2_http_endpoint: String,
+    /// Where the L1 wallet gets its view of the chain from. Defaults to
+    /// [`ChainSourceKind::Esplora`]
+    pub chain_source: Option<ChainSourceKind>,
+    /// URLs of the esplora APIs to use for the wallet, most preferred first. Should not
+    /// have a trailing slash. Required when `chain_source` is `esplora`
+    pub esplora: Option<OneOrMany<String>>,
+    /// bitcoind node to use for the wallet. Required when `chain_source` is `bitcoind`
+    pub bitcoind: Option<BitcoindConfig>,
+    /// URLs of the EVM L2 HTTP endpoints to use for the wallet, most preferred first. Should
+    /// not have a trailing slash
+    pub l2_http_endpoint: OneOrMany<String>,
+    /// Endpoint health check configuration
+    pub endpoints: Option<EndpointsConfig>,
+    /// Readiness check configuration
+    pub health: Option<HealthConfig>,
     /// Transaction batching configuration
     pub batcher: Option<BatcherConfig>,
+    /// L1 fee estimation configuration
+    pub fee: Option<FeeConfig>,
+    /// Admin API configuration
+    pub admin: Option<AdminConfig>,
+    /// Where the per-client claim history is kept
+    pub claim_history: Option<ClaimHistoryConfig>,
     pub l1: ReadableLayerConfig,
     pub l2: ReadableLayerConfig,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Settings struct filled with either config values or
 /// opinionated defaults
 pub struct Settings {
     pub host: IpAddr,
     pub port: u16,
     pub ip_src: ClientIpSource,
+    pub ipv6_prefix_len: u8,
+    pub challenge_mode: ChallengeMode,
+    pub require_address_binding: bool,
     pub seed_file: PathBuf,
+    pub seed: SeedConfig,
+    pub derivation: Derivation,
     pub sqlite_file: PathBuf,
     pub network: Network,
-    pub esplora: String,
-    pub l2_http_endpoint: String,
+    pub chain_source: ChainSourceConfig,
+    pub l2_http_endpoints: Vec<String>,
+    pub endpoints: EndpointsConfig,
+    pub health: HealthConfig,
     pub batcher: BatcherConfig,
+    pub fee: FeeConfig,
+    pub admin: AdminConfig,
+    pub claim_history: ClaimHistoryConfig,
     pub l1: LayerConfig,
     pub l2: LayerConfig,
 }
 
+/// Either a single value or a list of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> From<OneOrMany<T>> for Vec<T> {
+    fn from(value: OneOrMany<T>) -> Self {
+        match value {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+/// The configured chain source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainSourceConfig {
+    /// URLs of esplora APIs, most preferred first
+    Esplora(Vec<String>),
+    Bitcoind(BitcoindConfig),
+}
+
 // on L2, we represent 1 btc as 1 "eth" on the rollup
 // that means 1 sat = 1e10 "wei"
 // we have to store the amount we send in wei as a u64,
 // so this is a safety check.
-const MAX_SATS_PER_CLAIM: Amount = Amount::from_sat(u64::MAX / 10u64.pow(10));
+pub(crate) const MAX_SATS_PER_CLAIM: Amount = Amount::from_sat(u64::MAX / 10u64.pow(10));
 
 #[derive(Debug)]
 pub enum SettingsError {
+    /// The config couldn't be read or parsed.
+    Config(config::ConfigError),
     /// `sats_per_claim` is too high.
-    TooHighSatsPerClaim,
+    TooHighSatsPerClaim(Chain),
     /// `sats_per_claim` is too low.
     TooLowSatsPerClaim,
     /// Invalid seed path.
     InvalidSeedPath(String),
     /// Invalid database path.
     InvalidDatabasePath(String),
+    /// `ipv6_prefix_len` is longer than an IPv6 address.
+    InvalidIpv6PrefixLen(u8),
+    /// The fee rate floor, ceiling or fallback are negative, or the floor is
+    /// above the ceiling.
+    InvalidFeeRateBounds,
+    /// `chain_source` is set to a source that isn't configured.
+    MissingChainSourceConfig(ChainSourceKind),
+    /// An endpoint list is empty.
+    NoEndpoints(&'static str),
+    /// The admin token is set but empty.
+    EmptyAdminToken,
+    /// A layer's difficulty settings don't make a valid difficulty curve.
+    InvalidDifficultyConfig(Chain, DifficultyConfigError),
+    /// A layer's claim rate target isn't positive or its half life is zero.
+    InvalidClaimRateConfig(Chain),
+    /// A layer's reputation half life is zero.
+    InvalidReputationConfig(Chain),
+    /// A layer's proof-of-work algorithm parameters are invalid or need too
+    /// much memory.
+    InvalidPowAlgorithm(Chain),
+    /// A layer's `pow_shares` isn't a power of two up to 64.
+    InvalidPowShares(Chain),
+}
+
+impl From<config::ConfigError> for SettingsError {
+    fn from(e: config::ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+impl Settings {
+    /// Reads the settings from the config file at `path` and the environment.
+    pub fn load(path: &Path) -> Result<Self, SettingsError> {
+        Config::builder()
+            .add_source(config::File::from(path))
+            // Add in settings from the environment (with a prefix of CRATE_NAME)
+            .add_source(config::Environment::with_prefix(&CRATE_NAME.to_uppercase()))
+            .build()?
+            .try_deserialize::<ReadableSettings>()?
+            .try_into()
+    }
 }
 
 impl TryFrom<ReadableSettings> for Settings {
@@ -102,10 +222,60 @@ impl TryFrom<ReadableSettings> for Settings {
 
     fn try_from(read_settings: ReadableSettings) -> Result<Self, Self::Error> {
         if read_settings.l1.amount_per_claim > MAX_SATS_PER_CLAIM {
-            panic!("L1 sats per claim is too high, max is {MAX_SATS_PER_CLAIM}");
+            return Err(SettingsError::TooHighSatsPerClaim(Chain::L1));
         }
         if read_settings.l2.amount_per_claim > MAX_SATS_PER_CLAIM {
-            panic!("L2 sats per claim is too high, max is {MAX_SATS_PER_CLAIM}");
+            return Err(SettingsError::TooHighSatsPerClaim(Chain::L2));
+        }
+        let l1 = LayerConfig::from(read_settings.l1);
+        difficulty_config(&l1).map_err(|e| SettingsError::InvalidDifficultyConfig(Chain::L1, e))?;
+        let l2 = LayerConfig::from(read_settings.l2);
+        difficulty_config(&l2).map_err(|e| SettingsError::InvalidDifficultyConfig(Chain::L2, e))?;
+        for (chain, layer) in [(Chain::L1, &l1), (Chain::L2, &l2)] {
+            if !layer.claim_rate.is_valid() {
+                return Err(SettingsError::InvalidClaimRateConfig(chain));
+            }
+            if !layer.reputation.is_valid() {
+                return Err(SettingsError::InvalidReputationConfig(chain));
+            }
+            if !layer.pow_algorithm.is_valid() {
+                return Err(SettingsError::InvalidPowAlgorithm(chain));
+            }
+            if !layer.pow_shares.is_power_of_two() || layer.pow_shares > 64 {
+                return Err(SettingsError::InvalidPowShares(chain));
+            }
+        }
+        let ipv6_prefix_len = read_settings.ipv6_prefix_len.unwrap_or(56);
+        if ipv6_prefix_len > 128 {
+            return Err(SettingsError::InvalidIpv6PrefixLen(ipv6_prefix_len));
+        }
+        let fee = read_settings.fee.unwrap_or_default();
+        let valid_rate = |rate: f64| rate.is_finite() && rate >= 0.0;
+        if !valid_rate(fee.min_sat_per_vb)
+            || !valid_rate(fee.max_sat_per_vb)
+            || !valid_rate(fee.fallback_sat_per_vb)
+            || fee.min_sat_per_vb > fee.max_sat_per_vb
+        {
+            return Err(SettingsError::InvalidFeeRateBounds);
+        }
+        let chain_source_kind = read_settings.chain_source.unwrap_or_default();
+        let chain_source = match chain_source_kind {
+            ChainSourceKind::Esplora => read_settings
+                .esplora
+                .map(|urls| ChainSourceConfig::Esplora(urls.into())),
+            ChainSourceKind::Bitcoind => read_settings.bitcoind.map(ChainSourceConfig::Bitcoind),
+        }
+        .ok_or(SettingsError::MissingChainSourceConfig(chain_source_kind))?;
+        if matches!(&chain_source, ChainSourceConfig::Esplora(urls) if urls.is_empty()) {
+            return Err(SettingsError::NoEndpoints("esplora"));
+        }
+        let l2_http_endpoints = Vec::from(read_settings.l2_http_endpoint);
+        if l2_http_endpoints.is_empty() {
+            return Err(SettingsError::NoEndpoints("l2_http_endpoint"));
+        }
+        let admin = read_settings.admin.unwrap_or_default();
+        if admin.token.as_ref().is_some_and(|token| token.is_empty()) {
+            return Err(SettingsError::EmptyAdminToken);
         }
 
         Ok(Self {
@@ -114,10 +284,15 @@ impl TryFrom<ReadableSettings> for Settings {
                 .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
             port: read_settings.port.unwrap_or(3000),
             ip_src: read_settings.ip_src,
+            ipv6_prefix_len,
+            challenge_mode: read_settings.challenge_mode.unwrap_or_default(),
+            require_address_binding: read_settings.require_address_binding.unwrap_or(false),
             seed_file: PathBuf::from_str(
                 &read_settings.seed_file.unwrap_or("faucet.seed".to_owned()),
             )
             .map_err(|e| SettingsError::InvalidSeedPath(e.to_string()))?,
+            seed: read_settings.seed.unwrap_or_default(),
+            derivation: read_settings.derivation.unwrap_or_default(),
             sqlite_file: PathBuf::from_str(
                 &read_settings
                     .sqlite_file
@@ -125,11 +300,16 @@ impl TryFrom<ReadableSettings> for Settings {
             )
             .map_err(|e| SettingsError::InvalidDatabasePath(e.to_string()))?,
             network: read_settings.network.unwrap_or(Network::Signet),
-            esplora: read_settings.esplora,
-            l2_http_endpoint: read_settings.l2_http_endpoint,
+            chain_source,
+            l2_http_endpoints,
+            endpoints: read_settings.endpoints.unwrap_or_default(),
+            health: read_settings.health.unwrap_or_default(),
             batcher: read_settings.batcher.unwrap_or_default(),
-            l1: read_settings.l1.into(),
-            l2: read_settings.l2.into(),
+            fee,
+            admin,
+            claim_history: read_settings.claim_history.unwrap_or_default(),
+            l1,
+            l2,
         })
     }
 }
@@ -167,6 +347,33 @@ pub struct ReadableLayerConfig {
     /// Defaults to `20`.
     pub difficulty_increase_coeff: Option<f32>,
 
+    /// Shape of the difficulty curve. See docs/pow.md to see how this works.
+    ///
+    /// Defaults to [`DifficultyCurve::Linear`].
+    pub difficulty_curve: Option<DifficultyCurve>,
+
+    /// Raises the difficulty while claims come in faster than expected.
+    ///
+    /// Disabled by default.
+    pub claim_rate: Option<ClaimRateConfig>,
+
+    /// Raises the difficulty for clients that claimed recently.
+    ///
+    /// Disabled by default.
+    pub reputation: Option<ReputationConfig>,
+
+    /// Hash function challenges are solved with.
+    ///
+    /// Defaults to [`PowAlgorithm::Sha256`].
+    pub pow_algorithm: Option<PowAlgorithm>,
+
+    /// Number of solutions a challenge asks for, each at `log2(pow_shares)`
+    /// less difficulty, which takes as long on average but varies less. Must
+    /// be a power of two up to 64.
+    ///
+    /// Defaults to `1`.
+    pub pow_shares: Option<u8>,
+
     /// How long a challenge is valid for.
     ///
     /// Defaults to `120` seconds.
@@ -178,9 +385,17 @@ pub struct ReadableLayerConfig {
     /// challenge_duration = { secs = 120, nanos = 0 }
     /// ```
     pub challenge_duration: Option<Duration>,
+
+    /// How long an IP address or recipient address has to wait between
+    /// successful claims.
+    ///
+    /// Disabled by default.
+    ///
+    /// Uses the same format as `challenge_duration`.
+    pub cooldown: Option<Duration>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct LayerConfig {
     /// Minimum difficulty required for a user to claim funds.
     ///
@@ -205,8 +420,27 @@ pub struct LayerConfig {
     /// to the minimum balance. See docs/pow.md to see how this works.
     pub difficulty_increase_coeff: f32,
 
+    /// Shape of the difficulty curve. See docs/pow.md to see how this works.
+    pub difficulty_curve: DifficultyCurve,
+
+    /// Raises the difficulty while claims come in faster than expected.
+    pub claim_rate: ClaimRateConfig,
+
+    /// Raises the difficulty for clients that claimed recently.
+    pub reputation: ReputationConfig,
+
+    /// Hash function challenges are solved with.
+    pub pow_algorithm: PowAlgorithm,
+
+    /// Number of solutions a challenge asks for.
+    pub pow_shares: u8,
+
     /// How long a challenge is valid for.
     pub challenge_duration: Duration,
+
+    /// How long an IP address or recipient address has to wait between
+    /// successful claims, if at all.
+    pub cooldown: Option<Duration>,
 }
 
 impl From<ReadableLayerConfig> for LayerConfig {
@@ -217,7 +451,13 @@ impl From<ReadableLayerConfig> for LayerConfig {
             min_balance: value.min_balance.unwrap_or(Amount::ZERO),
             amount_per_claim: value.amount_per_claim,
             difficulty_increase_coeff: value.difficulty_increase_coeff.unwrap_or(20.),
+            difficulty_curve: value.difficulty_curve.unwrap_or_default(),
+            claim_rate: value.claim_rate.unwrap_or_default(),
+            reputation: value.reputation.unwrap_or_default(),
+            pow_algorithm: value.pow_algorithm.unwrap_or_default(),
+            pow_shares: value.pow_shares.unwrap_or(1),
             challenge_duration: value.challenge_duration.unwrap_or(Duration::from_secs(120)),
+            cooldown: value.cooldown,
         }
     }
 }
@@ -0,0 +1,101 @@
+# Assembles the disperse contract used to batch L2 payouts (see src/disperse.rs)
+# and prints its creation bytecode as hex.
+#
+# The contract has a single function, `disperseEther(uint256[] payouts)`. Each
+# payout packs the recipient address into the top 160 bits and the amount of
+# wei into the low 96 bits. Every payout is sent with a 50k gas stipend and
+# emits `Payout(address indexed recipient, uint256 value, bool success)`, in
+# the same order as the payouts. Failed transfers don't revert the batch, and
+# whatever's left over is refunded to the caller at the end.
+
+# bytes4(keccak256("disperseEther(uint256[])"))
+SELECTOR = 0x0E82DC38
+# keccak256("Payout(address,uint256,bool)")
+PAYOUT_TOPIC = 0x85D302949C5F5BA2BC82AE37E5483C096D2454C26C5526BD2252232CFAC0FA91
+GAS_PER_TRANSFER = 50_000
+
+OPCODES = {
+    "STOP": 0x00, "ADD": 0x01, "LT": 0x10, "EQ": 0x14, "ISZERO": 0x15,
+    "AND": 0x16, "SHL": 0x1B, "SHR": 0x1C, "CALLER": 0x33,
+    "CALLDATALOAD": 0x35, "CODECOPY": 0x39, "SELFBALANCE": 0x47, "POP": 0x50,
+    "MSTORE": 0x52, "JUMP": 0x56, "JUMPI": 0x57, "GAS": 0x5A,
+    "JUMPDEST": 0x5B, "DUP1": 0x80, "DUP2": 0x81, "DUP3": 0x82, "DUP5": 0x84,
+    "DUP7": 0x86, "SWAP1": 0x90, "LOG2": 0xA2, "CALL": 0xF1, "RETURN": 0xF3,
+    "REVERT": 0xFD,
+}
+
+def push(size, value):
+    return ("PUSH", size, value)
+
+def label(name):
+    return ("LABEL", name)
+
+def jump_to(name):
+    return ("PUSH_LABEL", name)
+
+RUNTIME = [
+    # revert unless called with the disperseEther selector
+    push(1, 0), "CALLDATALOAD", push(1, 0xE0), "SHR", push(4, SELECTOR), "EQ",
+    jump_to("main"), "JUMPI",
+    push(1, 0), "DUP1", "REVERT",
+    label("main"),
+    # [ptr, end] = bounds of the payouts array in calldata
+    push(1, 4), "CALLDATALOAD", push(1, 4), "ADD",
+    "DUP1", "CALLDATALOAD",
+    "SWAP1", push(1, 32), "ADD",
+    "SWAP1",
+    push(1, 5), "SHL", "DUP2", "ADD",
+    label("loop"),
+    # while ptr < end
+    "DUP1", "DUP3", "LT", "ISZERO", jump_to("done"), "JUMPI",
+    # [ptr, end, recipient, value]
+    "DUP2", "CALLDATALOAD",
+    "DUP1", push(1, 0x60), "SHR",
+    "SWAP1", push(12, (1 << 96) - 1), "AND",
+    # success = call(GAS_PER_TRANSFER, recipient, value, 0, 0, 0, 0)
+    push(1, 0), "DUP1", "DUP1", "DUP1", "DUP5", "DUP7", push(2, GAS_PER_TRANSFER),
+    "CALL",
+    # emit Payout(recipient, value, success)
+    push(1, 0x20), "MSTORE",
+    push(1, 0), "MSTORE",
+    push(32, PAYOUT_TOPIC),
+    push(1, 0x40), push(1, 0), "LOG2",
+    # ptr += 32
+    "SWAP1", push(1, 32), "ADD", "SWAP1",
+    jump_to("loop"), "JUMP",
+    label("done"),
+    # refund whatever is left to the caller
+    push(1, 0), "DUP1", "DUP1", "DUP1", "SELFBALANCE", "CALLER", "GAS", "CALL",
+    "POP", "STOP",
+]
+
+def assemble(program):
+    labels = {}
+    # the first pass finds the label offsets, the second fills them in
+    for _ in range(2):
+        code = bytearray()
+        for ins in program:
+            if isinstance(ins, str):
+                code.append(OPCODES[ins])
+            elif ins[0] == "PUSH":
+                code.append(0x5F + ins[1])
+                code += ins[2].to_bytes(ins[1], "big")
+            elif ins[0] == "PUSH_LABEL":
+                code.append(0x61)
+                code += labels.get(ins[1], 0).to_bytes(2, "big")
+            elif ins[0] == "LABEL":
+                labels[ins[1]] = len(code)
+                code.append(OPCODES["JUMPDEST"])
+    return bytes(code)
+
+def creation_code(runtime):
+    # codecopy(0, INIT_LEN, len(runtime)); return(0, len(runtime))
+    init_len = 13
+    init = bytearray()
+    init += bytes([0x61]) + len(runtime).to_bytes(2, "big") + bytes([OPCODES["DUP1"]])
+    init += bytes([0x61]) + init_len.to_bytes(2, "big")
+    init += bytes([0x60, 0x00, OPCODES["CODECOPY"], 0x60, 0x00, OPCODES["RETURN"]])
+    assert len(init) == init_len
+    return bytes(init) + runtime
+
+print(creation_code(assemble(RUNTIME)).hex())
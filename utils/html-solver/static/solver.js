@@ -3,6 +3,126 @@ async function sha256(data) {
   return new Uint8Array(buffer);
 }
 
+async function pbkdf2Sha256(password, salt, length) {
+  const key = await crypto.subtle.importKey('raw', password, 'PBKDF2', false, ['deriveBits']);
+  const bits = await crypto.subtle.deriveBits(
+    { name: 'PBKDF2', salt, iterations: 1, hash: 'SHA-256' },
+    key,
+    length * 8
+  );
+  return new Uint8Array(bits);
+}
+
+function rotl(a, n) {
+  return (a << n) | (a >>> (32 - n));
+}
+
+// Salsa20/8 core, applied to 16 words in place
+function salsa208(b) {
+  const x = b.slice();
+  for (let i = 0; i < 8; i += 2) {
+    x[4] ^= rotl(x[0] + x[12], 7); x[8] ^= rotl(x[4] + x[0], 9);
+    x[12] ^= rotl(x[8] + x[4], 13); x[0] ^= rotl(x[12] + x[8], 18);
+    x[9] ^= rotl(x[5] + x[1], 7); x[13] ^= rotl(x[9] + x[5], 9);
+    x[1] ^= rotl(x[13] + x[9], 13); x[5] ^= rotl(x[1] + x[13], 18);
+    x[14] ^= rotl(x[10] + x[6], 7); x[2] ^= rotl(x[14] + x[10], 9);
+    x[6] ^= rotl(x[2] + x[14], 13); x[10] ^= rotl(x[6] + x[2], 18);
+    x[3] ^= rotl(x[15] + x[11], 7); x[7] ^= rotl(x[3] + x[15], 9);
+    x[11] ^= rotl(x[7] + x[3], 13); x[15] ^= rotl(x[11] + x[7], 18);
+
+    x[1] ^= rotl(x[0] + x[3], 7); x[2] ^= rotl(x[1] + x[0], 9);
+    x[3] ^= rotl(x[2] + x[1], 13); x[0] ^= rotl(x[3] + x[2], 18);
+    x[6] ^= rotl(x[5] + x[4], 7); x[7] ^= rotl(x[6] + x[5], 9);
+    x[4] ^= rotl(x[7] + x[6], 13); x[5] ^= rotl(x[4] + x[7], 18);
+    x[11] ^= rotl(x[10] + x[9], 7); x[8] ^= rotl(x[11] + x[10], 9);
+    x[9] ^= rotl(x[8] + x[11], 13); x[10] ^= rotl(x[9] + x[8], 18);
+    x[12] ^= rotl(x[15] + x[14], 7); x[13] ^= rotl(x[12] + x[15], 9);
+    x[14] ^= rotl(x[13] + x[12], 13); x[15] ^= rotl(x[14] + x[13], 18);
+  }
+  for (let i = 0; i < 16; i++) {
+    b[i] += x[i];
+  }
+}
+
+// scrypt BlockMix of the 2 * r blocks in `b`, using `y` as scratch space
+function blockMix(b, y, r) {
+  const x = b.slice((2 * r - 1) * 16);
+  for (let i = 0; i < 2 * r; i++) {
+    for (let j = 0; j < 16; j++) {
+      x[j] ^= b[i * 16 + j];
+    }
+    salsa208(x);
+    // even blocks go to the first half, odd blocks to the second
+    y.set(x, (i >> 1) * 16 + (i & 1) * r * 16);
+  }
+  b.set(y);
+}
+
+// scrypt ROMix of `b`, using `v` and `y` as scratch space
+function roMix(b, v, y, n, r) {
+  const len = 32 * r;
+  for (let i = 0; i < n; i++) {
+    v.set(b, i * len);
+    blockMix(b, y, r);
+  }
+  for (let i = 0; i < n; i++) {
+    const j = b[len - 16] & (n - 1);
+    for (let k = 0; k < len; k++) {
+      b[k] ^= v[j * len + k];
+    }
+    blockMix(b, y, r);
+  }
+}
+
+function toWords(bytes) {
+  const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+  const words = new Uint32Array(bytes.length / 4);
+  for (let i = 0; i < words.length; i++) {
+    words[i] = view.getUint32(i * 4, true);
+  }
+  return words;
+}
+
+function fromWords(words, bytes) {
+  const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+  for (let i = 0; i < words.length; i++) {
+    view.setUint32(i * 4, words[i], true);
+  }
+}
+
+// Returns a function computing scrypt with the given parameters and a 32
+// byte output, reusing its memory between calls
+function scryptHasher(logN, r, p) {
+  const n = 2 ** logN;
+  const v = new Uint32Array(32 * r * n);
+  const y = new Uint32Array(32 * r);
+  return async function (password, salt) {
+    const b = await pbkdf2Sha256(password, salt, p * 128 * r);
+    for (let i = 0; i < p; i++) {
+      const block = b.subarray(i * 128 * r, (i + 1) * 128 * r);
+      const words = toWords(block);
+      roMix(words, v, y, n, r);
+      fromWords(words, block);
+    }
+    return pbkdf2Sha256(password, b, 32);
+  };
+}
+
+// Returns the hash function of the challenge's `algorithm`, as returned by
+// the faucet
+function hasher(algorithm) {
+  switch (algorithm?.type ?? 'sha256') {
+    case 'sha256':
+      return (data) => sha256(data);
+    case 'scrypt': {
+      const scrypt = scryptHasher(algorithm.log_n, algorithm.r, algorithm.p);
+      return (data, nonce) => scrypt(data, nonce);
+    }
+    default:
+      throw new Error(`unsupported algorithm ${algorithm.type}`);
+  }
+}
+
 function countLeadingZeros(data) {
   let leadingZeros = 0;
   for (let byte of data) {
@@ -16,21 +136,28 @@ function countLeadingZeros(data) {
   return leadingZeros;
 }
 
-async function findSolution(nonce, difficulty) {
-  const salt = new Uint8Array([
-    0x73, 0x74, 0x72, 0x61, 0x74, 0x61, 0x20, 0x66,
-    0x61, 0x75, 0x63, 0x65, 0x74, 0x20, 0x32, 0x30,
-    0x32, 0x34
-  ]);
+// `address` is the address the challenge is bound to, exactly as returned by
+// the faucet, or empty if the challenge isn't bound to an address.
+// `algorithm` is the `algorithm` field returned with the challenge, SHA-256 if
+// not given. `shares` is the number of distinct solutions to find, which are
+// returned concatenated
+async function findSolution(nonce, difficulty, address, algorithm, shares = 1) {
+  const hash = hasher(algorithm);
+  const encoder = new TextEncoder();
+  const salt = encoder.encode(address ? 'alpen faucet 2024 v2' : 'alpen faucet 2024');
+  const boundAddress = encoder.encode(address || '');
 
   nonce = new Uint8Array(nonce.match(/.{1,2}/g).map(byte => parseInt(byte, 16)));
   let solution = new Uint8Array(8);
+  let found = [];
 
   while (true) {
-    const hashInput = new Uint8Array([...salt, ...nonce, ...solution]);
-    const hash = await sha256(hashInput);
-    if (countLeadingZeros(hash) >= difficulty) {
-      return Array.from(solution).map(byte => byte.toString(16).padStart(2, '0')).join('');
+    const hashInput = new Uint8Array([...salt, ...nonce, ...boundAddress, ...solution]);
+    if (countLeadingZeros(await hash(hashInput, nonce)) >= difficulty) {
+      found.push(Array.from(solution).map(byte => byte.toString(16).padStart(2, '0')).join(''));
+      if (found.length >= shares) {
+        return found.join('');
+      }
     }
     // Increment solution
     for (let i = 7; i >= 0; i--) {
@@ -45,7 +172,7 @@ async function findSolution(nonce, difficulty) {
 }
 
 onmessage = async function (event) {
-  const { nonce, difficulty } = event.data;
-  const solution = await findSolution(nonce, difficulty);
+  const { nonce, difficulty, address, algorithm, shares } = event.data;
+  const solution = await findSolution(nonce, difficulty, address, algorithm, shares);
   postMessage({ solution });
 };
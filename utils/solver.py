@@ -4,6 +4,22 @@ import hashlib
 def sha256(data):
     return hashlib.sha256(data).digest()
 
+# scrypt hashing function, salted with the challenge nonce
+def scrypt(data, nonce, log_n, r, p):
+    n = 2 ** log_n
+    return hashlib.scrypt(
+        data, salt=nonce, n=n, r=r, p=p, maxmem=128 * r * (n + p + 2) + 1024, dklen=32
+    )
+
+# Hash function of the challenge's `algorithm`, as returned by the faucet
+def hasher(algorithm):
+    if algorithm is None or algorithm["type"] == "sha256":
+        return lambda data, nonce: sha256(data)
+    if algorithm["type"] == "scrypt":
+        log_n, r, p = algorithm["log_n"], algorithm["r"], algorithm["p"]
+        return lambda data, nonce: scrypt(data, nonce, log_n, r, p)
+    raise ValueError("unsupported algorithm " + algorithm["type"])
+
 # Count leading zeros in a byte array
 def count_leading_zeros(data):
     leading_zeros = 0
@@ -11,23 +27,34 @@ def count_leading_zeros(data):
         if byte == 0:
             leading_zeros += 8
         else:
-            leading_zeros += bin(byte).find('1')
+            leading_zeros += format(byte, '08b').find('1')
             break
     return leading_zeros
 
 # Find solution
-def find_solution(nonce, difficulty):
-    salt = bytes.fromhex("737472617461206661756365742032303234")
+# `address` is the address the challenge is bound to, exactly as returned by
+# the faucet, or None if the challenge isn't bound to an address
+# `algorithm` is the `algorithm` field returned with the challenge, SHA-256 if
+# None
+# `shares` is the number of distinct solutions to find, which are returned
+# concatenated
+def find_solution(nonce, difficulty, address=None, algorithm=None, shares=1):
+    hash_fn = hasher(algorithm)
+    salt = b"alpen faucet 2024 v2" if address else b"alpen faucet 2024"
+    bound_address = address.encode() if address else b""
     nonce = bytes.fromhex(nonce)
     solution = bytearray(8)
+    found = []
 
     while True:
-        hash_input = salt + nonce + solution
-        hash = sha256(hash_input)
+        hash_input = salt + nonce + bound_address + solution
+        hash = hash_fn(hash_input, nonce)
         print(hash.hex())
         print(count_leading_zeros(hash))
         if count_leading_zeros(hash) >= difficulty:
-            return solution.hex()
+            found.append(solution.hex())
+            if len(found) >= shares:
+                return "".join(found)
         # Increment solution
         for i in range(7, -1, -1):
             if solution[i] < 0xFF:
@@ -39,5 +66,8 @@ def find_solution(nonce, difficulty):
 # Example usage
 nonce = "4bbbefa849c59704f7f13745ca47161a"  # Replace with actual nonce
 difficulty = 17  # Replace with actual difficulty
-solution = find_solution(nonce, difficulty)
+address = None  # Replace with the bound address, if any
+algorithm = None  # Replace with the algorithm, e.g. {"type": "scrypt", "log_n": 14, "r": 8, "p": 1}
+shares = 1  # Replace with the number of shares
+solution = find_solution(nonce, difficulty, address, algorithm, shares)
 print("Solution:", solution)